@@ -4,6 +4,8 @@ fn main() -> Result<()> {
         "src/protos/gogoproto/gogo.proto",
         "src/protos/types.proto",
         "src/protos/remote.proto",
+        "src/protos/wal.proto",
+        "src/protos/spool.proto",
     ];
     let proto_includes = &["src/protos"];
     prost_build::compile_protos(proto_files, proto_includes)?;
@@ -1,12 +1,47 @@
-use crate::labels::map;
+use crate::filter::Filters;
+use crate::labels::LabelMapping;
 use crate::metrics_buffer::{Key as MetricsKey, Metrics, MetricsBuffer};
-use crate::owner_buffer::OwnerBuffer;
+use crate::owner_buffer::{
+    AttributeKey, AttributeValue, FlushedOwners, OwnerBuffer, OwnerKey, OwnerValue, Workload,
+};
 use crate::prometheus::WriteRequest;
+use crate::relabel::Relabeler;
+use crate::wal::{Checkpoint, Wal, pb};
 use log::debug;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Owner kind with which KSM reports objects that have no controller.
+const NO_OWNER: &str = "<none>";
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// What one flush took from the buffers.
+pub struct Flushed {
+    pub metrics: Vec<(MetricsKey, Metrics)>,
+    pub owners: Vec<(OwnerKey, OwnerValue)>,
+    pub attributes: Vec<(AttributeKey, AttributeValue)>,
+    pub workloads: Vec<(OwnerKey, Workload)>,
+    /// To be passed to `commit` once the rows above are in the database.
+    pub checkpoint: Option<Checkpoint>,
+}
 
 pub struct BufferManager {
     metrics_buffer: MetricsBuffer,
     owner_buffer: OwnerBuffer,
+    wal: Option<Wal>,
+    relabeler: Relabeler,
+    label_mapping: LabelMapping,
+    filters: Filters,
+    /// Held shared while a request is logged and applied, and exclusively while
+    /// flushing, so that every sealed log segment has reached the buffers by
+    /// the time they are flushed.
+    ingest_lock: RwLock<()>,
 }
 
 impl BufferManager {
@@ -14,18 +49,59 @@ impl BufferManager {
         Self {
             metrics_buffer,
             owner_buffer,
+            wal: None,
+            relabeler: Relabeler::default(),
+            label_mapping: LabelMapping::default(),
+            filters: Filters::default(),
+            ingest_lock: RwLock::new(()),
+        }
+    }
+
+    /// Logs every accepted request to `wal` before applying it, so that the
+    /// buffers can be restored with `replay_wal` after a restart.
+    pub fn with_wal(metrics_buffer: MetricsBuffer, owner_buffer: OwnerBuffer, wal: Wal) -> Self {
+        // Samples for buckets that an earlier run has written are late.
+        let metrics_buffer = metrics_buffer.with_flushed_before(wal.flushed_before());
+        Self {
+            wal: Some(wal),
+            ..Self::new(metrics_buffer, owner_buffer)
+        }
+    }
+
+    /// Relabels incoming series with `relabeler` before they are mapped.
+    pub fn with_relabeler(self, relabeler: Relabeler) -> Self {
+        Self { relabeler, ..self }
+    }
+
+    /// Maps incoming series with `label_mapping` instead of the defaults.
+    pub fn with_label_mapping(self, label_mapping: LabelMapping) -> Self {
+        Self {
+            label_mapping,
+            ..self
         }
     }
 
-    pub fn process_write_request(
-        &self,
-        write_request: WriteRequest,
-    ) -> (
-        usize,
-        Vec<(MetricsKey, Metrics)>,
-        Vec<(String, String, String)>,
-    ) {
+    /// Stores only the series that pass `filters` instead of the defaults.
+    pub fn with_filters(self, filters: Filters) -> Self {
+        Self { filters, ..self }
+    }
+
+    /// Restores the buffers from the write-ahead log. Returns the number of
+    /// replayed batches.
+    pub fn replay_wal(&self) -> std::io::Result<usize> {
+        match &self.wal {
+            Some(wal) => wal.replay(|batch| self.apply(&batch)),
+            None => Ok(0),
+        }
+    }
+
+    /// Adds the samples of a remote write request to the buffers and returns
+    /// the number of samples received. Writing to the database is left to
+    /// `flush`, so the request never waits on it. Fails only if the request
+    /// could not be written to the write-ahead log.
+    pub fn process_write_request(&self, write_request: WriteRequest) -> std::io::Result<usize> {
         let mut total_samples = 0;
+        let mut batch = pb::Batch::default();
 
         debug!(
             "Starting to process write request with {} timeseries",
@@ -53,27 +129,106 @@ impl BufferManager {
             }
 
             total_samples += ts.samples.len();
-            if let Some(labels) = map(&ts.labels) {
+            let Some(relabeled) = self.relabeler.relabel(ts.labels) else {
+                continue;
+            };
+            if let Some(labels) = self.label_mapping.map(&relabeled) {
+                if !self.filters.accept(&labels) {
+                    continue;
+                }
                 let environment = match labels.environment.as_deref() {
                     Some(env) => env,
                     None => continue,
                 };
-                let pod = match labels.pod.as_deref() {
-                    Some(p) => p,
-                    None => continue,
-                };
+                // Series without a namespace, e.g. from outside Kubernetes,
+                // share the empty one.
+                let namespace = labels.namespace.as_deref().unwrap_or_default();
                 let name = match labels.name.as_deref() {
                     Some(n) => n,
                     None => continue,
                 };
 
-                if name == "owner" {
-                    if let Some(owner) = labels.owner.as_deref() {
-                        self.owner_buffer.insert(environment, pod, owner);
+                // Labels are in effect from the newest sample on, or from
+                // now for series that come without samples.
+                let timestamp = || {
+                    ts.samples
+                        .iter()
+                        .map(|sample| sample.timestamp as u64)
+                        .max()
+                        .unwrap_or_else(now_millis)
+                };
+                // Attributes whose label is missing are recorded as empty,
+                // which ends their current value.
+                let attributes = |pod: &str, batch: &mut pb::Batch| {
+                    for name in self.label_mapping.attributes.values() {
+                        batch.attributes.push(pb::Attribute {
+                            environment: environment.to_string(),
+                            namespace: namespace.to_string(),
+                            pod: pod.to_string(),
+                            name: name.clone(),
+                            value: labels.attributes.get(name).cloned().unwrap_or_default(),
+                            timestamp: timestamp(),
+                        });
+                    }
+                };
+
+                // Pods without an owner of their own may inherit the one of
+                // their namespace, so the absence of an owner is recorded as
+                // an empty one.
+                let owner = |pod: &str, batch: &mut pb::Batch| {
+                    batch.owners.push(pb::Owner {
+                        environment: environment.to_string(),
+                        namespace: namespace.to_string(),
+                        pod: pod.to_string(),
+                        owner: labels.owner.clone().unwrap_or_default(),
+                        timestamp: timestamp(),
+                    });
+                };
+
+                if name == "namespace_labels" {
+                    if !namespace.is_empty() {
+                        owner("", &mut batch);
+                        attributes("", &mut batch);
+                    }
+                    continue;
+                }
+
+                let owned = match name {
+                    "pod_owner" => Some(("Pod", labels.pod.as_deref())),
+                    "replicaset_owner" => Some(("ReplicaSet", labels.replicaset.as_deref())),
+                    "job_owner" => Some(("Job", labels.job.as_deref())),
+                    _ => None,
+                };
+                if let Some((kind, object)) = owned {
+                    if let (Some(object), Some(owner_kind), Some(owner_name)) = (
+                        object,
+                        labels.owner_kind.as_deref(),
+                        labels.owner_name.as_deref(),
+                    ) && owner_kind != NO_OWNER
+                    {
+                        batch.owner_references.push(pb::OwnerReference {
+                            environment: environment.to_string(),
+                            namespace: namespace.to_string(),
+                            kind: kind.to_string(),
+                            name: object.to_string(),
+                            owner_kind: owner_kind.to_string(),
+                            owner_name: owner_name.to_string(),
+                        });
                     }
                     continue;
                 }
 
+                let pod = match labels.pod.as_deref() {
+                    Some(p) => p,
+                    None => continue,
+                };
+
+                if name == "owner" {
+                    owner(pod, &mut batch);
+                    attributes(pod, &mut batch);
+                    continue;
+                }
+
                 let container = match labels.container.as_deref() {
                     Some(c) => c,
                     None => continue,
@@ -90,21 +245,125 @@ impl BufferManager {
                         continue;
                     }
 
-                    self.metrics_buffer.insert(
-                        name,
-                        environment,
-                        pod,
-                        container,
-                        sample.timestamp as u64,
-                        sample.value,
-                    );
+                    batch.samples.push(pb::Sample {
+                        name: name.to_string(),
+                        environment: environment.to_string(),
+                        namespace: namespace.to_string(),
+                        pod: pod.to_string(),
+                        container: container.to_string(),
+                        timestamp: sample.timestamp as u64,
+                        value: sample.value,
+                    });
                 }
             }
         }
 
-        let flushed_metrics = self.metrics_buffer.flush();
-        let flushed_owners = self.owner_buffer.flush();
-        (total_samples, flushed_metrics, flushed_owners)
+        if batch.samples.is_empty()
+            && batch.owners.is_empty()
+            && batch.attributes.is_empty()
+            && batch.owner_references.is_empty()
+        {
+            return Ok(total_samples);
+        }
+
+        let _shared = self.ingest_lock.read().unwrap();
+        if let Some(wal) = &self.wal {
+            wal.append(&batch)?;
+        }
+        self.apply(&batch);
+
+        Ok(total_samples)
+    }
+
+    fn apply(&self, batch: &pb::Batch) {
+        for owner in &batch.owners {
+            self.owner_buffer.insert(
+                &owner.environment,
+                &owner.namespace,
+                &owner.pod,
+                &owner.owner,
+                owner.timestamp,
+            );
+        }
+        for attribute in &batch.attributes {
+            self.owner_buffer.insert_attribute(
+                &attribute.environment,
+                &attribute.namespace,
+                &attribute.pod,
+                &attribute.name,
+                &attribute.value,
+                attribute.timestamp,
+            );
+        }
+        for reference in &batch.owner_references {
+            self.owner_buffer.insert_reference(
+                &reference.environment,
+                &reference.namespace,
+                &reference.kind,
+                &reference.name,
+                &reference.owner_kind,
+                &reference.owner_name,
+            );
+        }
+        for sample in &batch.samples {
+            self.metrics_buffer.insert(
+                &sample.name,
+                &sample.environment,
+                &sample.namespace,
+                &sample.pod,
+                &sample.container,
+                sample.timestamp,
+                sample.value,
+            );
+        }
+    }
+
+    /// Takes the metric buckets, owners and workloads that are due from the
+    /// buffers.
+    pub fn flush(&self) -> Flushed {
+        let _exclusive = self.ingest_lock.write().unwrap();
+        let segment = self.wal.as_ref().and_then(|wal| wal.seal());
+        let thresholds = self.metrics_buffer.thresholds();
+        let metrics = self.metrics_buffer.flush_before(&thresholds);
+        // Buckets that wait for the next CPU counter of their series stay in
+        // the buffer, so their samples are not committed yet.
+        let threshold = thresholds.min();
+        let committed_before = self
+            .metrics_buffer
+            .oldest()
+            .map_or(threshold, |oldest| oldest.min(threshold));
+        let owners = self.owner_buffer.flush();
+
+        // The owner buffer is flushed as a whole, so if anything came out of
+        // it, every owner, attribute and owner reference in the sealed
+        // segments is part of this flush.
+        let checkpoint = segment.map(|segment| Checkpoint {
+            segment,
+            committed_before,
+            owners_committed: !owners.is_empty(),
+            flushed_before: self.metrics_buffer.flushed_before(),
+        });
+
+        let FlushedOwners {
+            owners,
+            attributes,
+            workloads,
+        } = owners;
+        Flushed {
+            metrics,
+            owners,
+            attributes,
+            workloads,
+            checkpoint,
+        }
+    }
+
+    /// Releases the write-ahead log segments covered by a flush that has been
+    /// written to the database.
+    pub fn commit(&self, checkpoint: &Checkpoint) {
+        if let Some(wal) = &self.wal {
+            wal.truncate(checkpoint);
+        }
     }
 }
 
@@ -114,11 +373,12 @@ mod tests {
     use crate::metrics_buffer::MetricsBuffer;
     use crate::owner_buffer::OwnerBuffer;
     use crate::prometheus::{Label, Sample, TimeSeries, WriteRequest};
-    use std::time::SystemTime;
+    use std::time::{Duration, SystemTime};
 
     #[test]
     fn test_process_write_request_with_valid_metrics() {
-        let metrics_buffer = MetricsBuffer::new(60000, 5);
+        // Idle right away, so that the bucket is flushed without newer ones.
+        let metrics_buffer = MetricsBuffer::new(60000, 5).with_idle_timeout(Duration::ZERO);
         let owner_buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
         let buffer_manager = BufferManager::new(metrics_buffer, owner_buffer);
 
@@ -152,8 +412,12 @@ mod tests {
             metadata: vec![],
         };
 
-        let (total_samples, flushed_metrics, flushed_owners) =
-            buffer_manager.process_write_request(write_request);
+        let total_samples = buffer_manager.process_write_request(write_request).unwrap();
+        let Flushed {
+            metrics: flushed_metrics,
+            owners: flushed_owners,
+            ..
+        } = buffer_manager.flush();
 
         assert_eq!(total_samples, 1);
         assert_eq!(flushed_metrics.len(), 1);
@@ -194,19 +458,238 @@ mod tests {
         };
 
         // Process the write request.
-        let (total_samples, flushed_metrics, flushed_owners) =
-            buffer_manager.process_write_request(write_request);
+        let before = now_millis();
+        let total_samples = buffer_manager.process_write_request(write_request).unwrap();
+        let Flushed {
+            metrics: flushed_metrics,
+            owners: flushed_owners,
+            ..
+        } = buffer_manager.flush();
 
         // Verify the results.
         assert_eq!(total_samples, 0);
         assert!(flushed_metrics.is_empty());
+        let (key, value) = &flushed_owners[0];
         assert_eq!(
-            flushed_owners[0],
+            *key,
+            OwnerKey {
+                environment: "prod".to_string(),
+                namespace: String::new(),
+                pod: "pod-1".to_string(),
+            }
+        );
+        assert_eq!(value.owner, "team-a");
+        // Without samples, the owner is in effect from the time it arrived.
+        assert!(value.valid_from >= before);
+    }
+
+    #[test]
+    fn test_process_write_request_with_attributes() {
+        let label_mapping: LabelMapping = serde_yaml::from_str(
+            "attributes:\n  label_owner: owner\n  label_cost_center: cost_center\n",
+        )
+        .unwrap();
+        let buffer_manager = BufferManager::new(
+            MetricsBuffer::new(60000, 5),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+        )
+        .with_label_mapping(label_mapping);
+
+        let series = |name: &str, pod: Option<&str>| TimeSeries {
+            labels: [
+                ("__name__", Some(name)),
+                ("cluster", Some("prod")),
+                ("namespace", Some("shop")),
+                ("pod", pod),
+                ("label_cost_center", Some("cc-42")),
+            ]
+            .iter()
+            .filter_map(|(name, value)| {
+                value.map(|value| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+            })
+            .collect(),
+            samples: vec![Sample {
+                value: 1.0,
+                timestamp: 60_000,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        };
+        let write_request = WriteRequest {
+            timeseries: vec![
+                series("kube_pod_labels", Some("pod-1")),
+                series("kube_namespace_labels", None),
+            ],
+            metadata: vec![],
+        };
+
+        buffer_manager.process_write_request(write_request).unwrap();
+        let Flushed {
+            owners,
+            mut attributes,
+            ..
+        } = buffer_manager.flush();
+
+        // Without an owner label, there is no owner, and the owner attribute
+        // is recorded as empty, but the other attributes are kept.
+        assert!(owners.is_empty());
+        attributes.sort_by(|a, b| (&a.0.pod, &a.0.name).cmp(&(&b.0.pod, &b.0.name)));
+        let attribute = |pod: &str, name: &str, value: &str| {
             (
-                "prod".to_string(),
-                "pod-1".to_string(),
-                "team-a".to_string()
+                AttributeKey {
+                    environment: "prod".to_string(),
+                    namespace: "shop".to_string(),
+                    pod: pod.to_string(),
+                    name: name.to_string(),
+                },
+                AttributeValue {
+                    value: value.to_string(),
+                    valid_from: 60_000,
+                },
             )
+        };
+        assert_eq!(
+            attributes,
+            vec![
+                attribute("", "cost_center", "cc-42"),
+                attribute("", "owner", ""),
+                attribute("pod-1", "cost_center", "cc-42"),
+                attribute("pod-1", "owner", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_write_request_with_namespace_owner() {
+        let buffer_manager = BufferManager::new(
+            MetricsBuffer::new(60000, 5),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+        );
+
+        let series = |name: &str, pod: Option<&str>, owner: Option<&str>| TimeSeries {
+            labels: [
+                ("__name__", Some(name)),
+                ("cluster", Some("prod")),
+                ("namespace", Some("shop")),
+                ("pod", pod),
+                ("label_owner", owner),
+            ]
+            .iter()
+            .filter_map(|(name, value)| {
+                value.map(|value| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+            })
+            .collect(),
+            samples: vec![Sample {
+                value: 1.0,
+                timestamp: 60_000,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        };
+        let write_request = WriteRequest {
+            timeseries: vec![
+                series("kube_namespace_labels", None, Some("team-shop")),
+                series("kube_pod_labels", Some("pod-1"), None),
+                series("kube_pod_labels", Some("pod-2"), Some("team-a")),
+            ],
+            metadata: vec![],
+        };
+
+        buffer_manager.process_write_request(write_request).unwrap();
+        let mut owners: Vec<_> = buffer_manager
+            .flush()
+            .owners
+            .into_iter()
+            .map(|(key, value)| (key.pod, value.owner))
+            .collect();
+        owners.sort();
+
+        assert_eq!(
+            owners,
+            vec![
+                ("pod-1".to_string(), "team-shop".to_string()),
+                ("pod-2".to_string(), "team-a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_write_request_with_owner_references() {
+        let buffer_manager = BufferManager::new(
+            MetricsBuffer::new(60000, 5),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+        );
+
+        let series = |labels: &[(&str, &str)]| TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            samples: vec![Sample {
+                value: 1.0,
+                timestamp: 1234567890,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        };
+        let write_request = WriteRequest {
+            timeseries: vec![
+                series(&[
+                    ("__name__", "kube_pod_owner"),
+                    ("cluster", "prod"),
+                    ("namespace", "shop"),
+                    ("pod", "web-5d4f-abcde"),
+                    ("owner_kind", "ReplicaSet"),
+                    ("owner_name", "web-5d4f"),
+                ]),
+                series(&[
+                    ("__name__", "kube_replicaset_owner"),
+                    ("cluster", "prod"),
+                    ("namespace", "shop"),
+                    ("replicaset", "web-5d4f"),
+                    ("owner_kind", "Deployment"),
+                    ("owner_name", "web"),
+                ]),
+                series(&[
+                    ("__name__", "kube_pod_owner"),
+                    ("cluster", "prod"),
+                    ("namespace", "shop"),
+                    ("pod", "debug"),
+                    ("owner_kind", "<none>"),
+                    ("owner_name", "<none>"),
+                ]),
+            ],
+            metadata: vec![],
+        };
+
+        buffer_manager.process_write_request(write_request).unwrap();
+        let Flushed {
+            metrics, workloads, ..
+        } = buffer_manager.flush();
+
+        assert!(metrics.is_empty());
+        assert_eq!(
+            workloads,
+            vec![(
+                OwnerKey {
+                    environment: "prod".to_string(),
+                    namespace: "shop".to_string(),
+                    pod: "web-5d4f-abcde".to_string(),
+                },
+                Workload {
+                    kind: "Deployment".to_string(),
+                    name: "web".to_string(),
+                }
+            )]
         );
     }
 
@@ -238,8 +721,12 @@ mod tests {
             metadata: vec![],
         };
 
-        let (total_samples, flushed_metrics, flushed_owners) =
-            buffer_manager.process_write_request(write_request);
+        let total_samples = buffer_manager.process_write_request(write_request).unwrap();
+        let Flushed {
+            metrics: flushed_metrics,
+            owners: flushed_owners,
+            ..
+        } = buffer_manager.flush();
 
         assert_eq!(total_samples, 1);
         assert!(flushed_metrics.is_empty());
@@ -282,11 +769,190 @@ mod tests {
             metadata: vec![],
         };
 
-        let (total_samples, flushed_metrics, flushed_owners) =
-            buffer_manager.process_write_request(write_request);
+        let total_samples = buffer_manager.process_write_request(write_request).unwrap();
+        let Flushed {
+            metrics: flushed_metrics,
+            owners: flushed_owners,
+            ..
+        } = buffer_manager.flush();
 
         assert_eq!(total_samples, 1);
         assert!(flushed_metrics.is_empty());
         assert!(flushed_owners.is_empty());
     }
+
+    #[test]
+    fn test_process_write_request_relabels_before_mapping() {
+        let relabeler: Relabeler = serde_yaml::from_str(
+            "- source_labels: [k8s_cluster]\n  regex: 'prod-(.+)'\n  target_label: cluster\n\
+             - source_labels: [pod]\n  regex: 'batch-.*'\n  action: drop\n",
+        )
+        .unwrap();
+        let buffer_manager = BufferManager::new(
+            MetricsBuffer::new(60000, 5).with_idle_timeout(Duration::ZERO),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+        )
+        .with_relabeler(relabeler);
+
+        let series = |pod: &str| TimeSeries {
+            labels: vec![
+                Label {
+                    name: "k8s_cluster".to_string(),
+                    value: "prod-eu".to_string(),
+                },
+                Label {
+                    name: "pod".to_string(),
+                    value: pod.to_string(),
+                },
+                Label {
+                    name: "container".to_string(),
+                    value: "container-1".to_string(),
+                },
+                Label {
+                    name: "__name__".to_string(),
+                    value: "container_memory_working_set_bytes".to_string(),
+                },
+            ],
+            samples: vec![Sample {
+                value: 0.5,
+                timestamp: 1234567890,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        };
+        let write_request = WriteRequest {
+            timeseries: vec![series("pod-1"), series("batch-1")],
+            metadata: vec![],
+        };
+
+        let total_samples = buffer_manager.process_write_request(write_request).unwrap();
+        let Flushed { metrics, .. } = buffer_manager.flush();
+
+        assert_eq!(total_samples, 2);
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].0.environment, "eu");
+        assert_eq!(metrics[0].0.pod, "pod-1");
+    }
+
+    #[test]
+    fn test_replay_wal_restores_buffers() {
+        let dir = tempfile::tempdir().unwrap();
+        let write_request = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![
+                    Label {
+                        name: "cluster".to_string(),
+                        value: "prod".to_string(),
+                    },
+                    Label {
+                        name: "pod".to_string(),
+                        value: "pod-1".to_string(),
+                    },
+                    Label {
+                        name: "container".to_string(),
+                        value: "container-1".to_string(),
+                    },
+                    Label {
+                        name: "__name__".to_string(),
+                        value: "container_memory_working_set_bytes".to_string(),
+                    },
+                ],
+                samples: vec![Sample {
+                    value: 0.5,
+                    timestamp: 1234567890,
+                }],
+                exemplars: vec![],
+                histograms: vec![],
+            }],
+            metadata: vec![],
+        };
+
+        let buffer_manager = BufferManager::with_wal(
+            MetricsBuffer::new(60000, 5),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+            Wal::open(dir.path(), crate::wal::DEFAULT_SEGMENT_SIZE).unwrap(),
+        );
+        buffer_manager.process_write_request(write_request).unwrap();
+        drop(buffer_manager);
+
+        let restarted = BufferManager::with_wal(
+            MetricsBuffer::new(60000, 5).with_idle_timeout(Duration::ZERO),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+            Wal::open(dir.path(), crate::wal::DEFAULT_SEGMENT_SIZE).unwrap(),
+        );
+        assert_eq!(restarted.replay_wal().unwrap(), 1);
+
+        let flushed = restarted.flush();
+        assert_eq!(flushed.metrics.len(), 1);
+        assert_eq!(flushed.metrics[0].1.memory_usage, Some(0.5));
+
+        // Once committed, the log no longer brings the samples back.
+        restarted.commit(&flushed.checkpoint.unwrap());
+        drop(restarted);
+        let wal = Wal::open(dir.path(), crate::wal::DEFAULT_SEGMENT_SIZE).unwrap();
+        assert_eq!(wal.replay(|_| {}).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_written_buckets_stay_written_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory = |timestamps: &[i64], value: f64| WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: [
+                    ("cluster", "prod"),
+                    ("pod", "pod-1"),
+                    ("container", "container-1"),
+                    ("__name__", "container_memory_working_set_bytes"),
+                ]
+                .iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+                samples: timestamps
+                    .iter()
+                    .map(|&timestamp| Sample { value, timestamp })
+                    .collect(),
+                exemplars: vec![],
+                histograms: vec![],
+            }],
+            metadata: vec![],
+        };
+
+        let buffer_manager = BufferManager::with_wal(
+            MetricsBuffer::new(60000, 5),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+            Wal::open(dir.path(), crate::wal::DEFAULT_SEGMENT_SIZE).unwrap(),
+        );
+        buffer_manager
+            .process_write_request(memory(&[60_000, 600_000], 1.0))
+            .unwrap();
+        let flushed = buffer_manager.flush();
+        assert_eq!(flushed.metrics.len(), 1);
+        buffer_manager.commit(&flushed.checkpoint.unwrap());
+        drop(buffer_manager);
+
+        let restarted = BufferManager::with_wal(
+            MetricsBuffer::new(60000, 5).with_idle_timeout(Duration::ZERO),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+            Wal::open(dir.path(), crate::wal::DEFAULT_SEGMENT_SIZE).unwrap(),
+        );
+        restarted.replay_wal().unwrap();
+        restarted
+            .process_write_request(memory(&[60_000], 2.0))
+            .unwrap();
+
+        // The written bucket is neither replayed nor replaced, but merged.
+        let mut flushed = restarted.flush().metrics;
+        flushed.sort_by_key(|(key, _)| key.timestamp);
+        let buckets: Vec<_> = flushed
+            .iter()
+            .map(|(key, metrics)| (key.timestamp, metrics.late, metrics.memory_usage))
+            .collect();
+        assert_eq!(
+            buckets,
+            vec![(60_000, true, Some(2.0)), (600_000, false, Some(1.0))]
+        );
+    }
 }
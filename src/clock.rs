@@ -0,0 +1,42 @@
+use std::time::SystemTime;
+
+/// Source of the wall-clock time, so that tests can move it at will.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The clock of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// A clock that only moves when told to.
+    pub struct ManualClock(Mutex<SystemTime>);
+
+    impl ManualClock {
+        pub fn new(now: SystemTime) -> Self {
+            ManualClock(Mutex::new(now))
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+}
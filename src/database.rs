@@ -1,9 +1,20 @@
+use crate::history::{ATTRIBUTES, History, OWNERS, Version};
 use crate::metrics_buffer::{Key, Metrics};
-use log::{debug, info, warn};
+use crate::migrations::{Migration, VERSION_TABLE, migrate, pending};
+use crate::owner_buffer::{AttributeKey, AttributeValue, OwnerKey, OwnerValue, Workload};
+use crate::rollup::{Expiry, Rollups, Series};
+use crate::sink::Sink;
+use crate::spool::{
+    DEFAULT_QUEUE_SIZE, Spool, attribute_chunks, metric_chunks, owner_chunks, pb, workload_chunks,
+};
+use chrono::{Datelike, Months, NaiveDate, Utc};
+use log::{debug, error, info, warn};
 use mysql::prelude::*;
 use mysql::*;
-use std::sync::Mutex;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::time::MissedTickBehavior;
 
 /// Number of times the initial connection is attempted before giving up.
 pub const DEFAULT_CONNECT_ATTEMPTS: u32 = 10;
@@ -11,11 +22,252 @@ pub const DEFAULT_CONNECT_ATTEMPTS: u32 = 10;
 pub const DEFAULT_CONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
 /// Upper bound for the wait between two attempts.
 const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Number of times a chunk is sent before it is left to the spool.
+pub const DEFAULT_WRITE_ATTEMPTS: u32 = 3;
+
+/// Fills in what a row of the same bucket left open, so that retried chunks
+/// do no harm.
+const INSERT_METRICS: &str = r"INSERT INTO micrometrics
+    (time, environment, namespace, pod, container, cpu_usage, cpu_limit, memory_usage,
+    memory_limit, cpu_request, memory_request, cpu_restarts, memory_usage_count,
+    memory_usage_min, memory_usage_max, memory_usage_avg, memory_usage_p95, merged_chunk)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+    ON DUPLICATE KEY UPDATE
+    cpu_usage = IFNULL(VALUES(cpu_usage), cpu_usage),
+    cpu_restarts = IFNULL(VALUES(cpu_restarts), cpu_restarts),
+    memory_usage_count = IFNULL(VALUES(memory_usage_count), memory_usage_count),
+    memory_usage_min = IFNULL(VALUES(memory_usage_min), memory_usage_min),
+    memory_usage_max = IFNULL(VALUES(memory_usage_max), memory_usage_max),
+    memory_usage_avg = IFNULL(VALUES(memory_usage_avg), memory_usage_avg),
+    memory_usage_p95 = IFNULL(VALUES(memory_usage_p95), memory_usage_p95),
+    cpu_limit = IFNULL(VALUES(cpu_limit), cpu_limit),
+    memory_usage = IFNULL(VALUES(memory_usage), memory_usage),
+    memory_limit = IFNULL(VALUES(memory_limit), memory_limit),
+    cpu_request = IFNULL(VALUES(cpu_request), cpu_request),
+    memory_request = IFNULL(VALUES(memory_request), memory_request)";
+
+/// Adds late samples to the statistics of the stored row. The sketch of the
+/// stored row is gone, so its 95th percentile stays that of the samples that
+/// arrived in time. The row remembers the chunk it was merged from last, so
+/// that the same chunk written again right away, e.g., after its commit was
+/// lost, does not count its samples twice. Merges are not exactly once,
+/// though: a retry after another chunk merged into the row, or late samples
+/// that the write-ahead log replays after a crash between the commit and the
+/// checkpoint, which come in chunks with new ids, are merged again. MySQL
+/// assigns from left to right, hence the average comes before the count, and
+/// `merged_chunk` comes last.
+const MERGE_METRICS: &str = r"INSERT INTO micrometrics
+    (time, environment, namespace, pod, container, cpu_usage, cpu_limit, memory_usage,
+    memory_limit, cpu_request, memory_request, cpu_restarts, memory_usage_count,
+    memory_usage_min, memory_usage_max, memory_usage_avg, memory_usage_p95, merged_chunk)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+    ON DUPLICATE KEY UPDATE
+    memory_usage_avg = IF(merged_chunk <=> VALUES(merged_chunk), memory_usage_avg,
+        COALESCE(
+            (memory_usage_avg * memory_usage_count
+                + VALUES(memory_usage_avg) * VALUES(memory_usage_count))
+                / (memory_usage_count + VALUES(memory_usage_count)),
+            VALUES(memory_usage_avg), memory_usage_avg)),
+    memory_usage_count = IF(merged_chunk <=> VALUES(merged_chunk), memory_usage_count,
+        COALESCE(memory_usage_count + VALUES(memory_usage_count),
+            VALUES(memory_usage_count), memory_usage_count)),
+    memory_usage_min = COALESCE(LEAST(memory_usage_min, VALUES(memory_usage_min)),
+        VALUES(memory_usage_min), memory_usage_min),
+    memory_usage_max = COALESCE(GREATEST(memory_usage_max, VALUES(memory_usage_max)),
+        VALUES(memory_usage_max), memory_usage_max),
+    cpu_limit = IFNULL(VALUES(cpu_limit), cpu_limit),
+    memory_usage = IFNULL(VALUES(memory_usage), memory_usage),
+    memory_limit = IFNULL(VALUES(memory_limit), memory_limit),
+    cpu_request = IFNULL(VALUES(cpu_request), cpu_request),
+    memory_request = IFNULL(VALUES(memory_request), memory_request),
+    merged_chunk = VALUES(merged_chunk)";
+
+/// Named lock that serializes migrations across replicas.
+const MIGRATION_LOCK: &str = "microinsight_schema_migration";
+/// How long a replica waits for another one to finish migrating.
+const MIGRATION_LOCK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The schema, from the oldest to the newest version. Version 1 is the schema
+/// from before migrations and uses `IF NOT EXISTS`, so that existing
+/// installations adopt it as is.
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create micrometrics and microowner",
+        statements: &[
+            r"CREATE TABLE IF NOT EXISTS micrometrics (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage FLOAT,
+                cpu_limit FLOAT,
+                memory_usage FLOAT,
+                memory_limit FLOAT,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+            r"CREATE TABLE IF NOT EXISTS microowner (
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                owner VARCHAR(255),
+                PRIMARY KEY (environment, pod)
+            )",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Store CPU as DOUBLE/DECIMAL and memory as integer bytes",
+        statements: &[r"ALTER TABLE micrometrics
+                MODIFY cpu_usage DOUBLE,
+                MODIFY cpu_limit DECIMAL(12, 3),
+                MODIFY memory_usage BIGINT,
+                MODIFY memory_limit BIGINT"],
+    },
+    Migration {
+        version: 3,
+        description: "Create hourly and daily rollups",
+        statements: &[
+            r"CREATE TABLE micrometrics_hourly (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage DOUBLE,
+                cpu_limit_seconds DOUBLE,
+                memory_usage_avg DOUBLE,
+                memory_usage_max BIGINT,
+                memory_limit_max BIGINT,
+                samples INT,
+                memory_samples INT,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+            r"CREATE TABLE micrometrics_daily (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage DOUBLE,
+                cpu_limit_seconds DOUBLE,
+                memory_usage_avg DOUBLE,
+                memory_usage_max BIGINT,
+                memory_limit_max BIGINT,
+                samples INT,
+                memory_samples INT,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Add resource requests",
+        statements: &[
+            "ALTER TABLE micrometrics ADD cpu_request DECIMAL(12, 3), ADD memory_request BIGINT",
+            r"ALTER TABLE micrometrics_hourly
+                ADD cpu_request_seconds DOUBLE, ADD memory_request_max BIGINT",
+            r"ALTER TABLE micrometrics_daily
+                ADD cpu_request_seconds DOUBLE, ADD memory_request_max BIGINT",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Add namespace to the primary keys",
+        statements: &[
+            r"ALTER TABLE micrometrics
+                ADD namespace VARCHAR(255) NOT NULL DEFAULT '' AFTER environment,
+                DROP PRIMARY KEY, ADD PRIMARY KEY (time, environment, namespace, pod, container)",
+            r"ALTER TABLE micrometrics_hourly
+                ADD namespace VARCHAR(255) NOT NULL DEFAULT '' AFTER environment,
+                DROP PRIMARY KEY, ADD PRIMARY KEY (time, environment, namespace, pod, container)",
+            r"ALTER TABLE micrometrics_daily
+                ADD namespace VARCHAR(255) NOT NULL DEFAULT '' AFTER environment,
+                DROP PRIMARY KEY, ADD PRIMARY KEY (time, environment, namespace, pod, container)",
+            r"ALTER TABLE microowner
+                ADD namespace VARCHAR(255) NOT NULL DEFAULT '' AFTER environment,
+                DROP PRIMARY KEY, ADD PRIMARY KEY (environment, namespace, pod)",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Create microworkload",
+        statements: &[r"CREATE TABLE microworkload (
+                environment VARCHAR(255),
+                namespace VARCHAR(255),
+                pod VARCHAR(255),
+                kind VARCHAR(255),
+                workload VARCHAR(255),
+                PRIMARY KEY (environment, namespace, pod)
+            )"],
+    },
+    Migration {
+        version: 7,
+        description: "Track the validity of owners",
+        statements: &[r"ALTER TABLE microowner
+                ADD valid_from DATETIME NOT NULL DEFAULT '1970-01-01 00:00:00',
+                ADD valid_to DATETIME NULL,
+                DROP PRIMARY KEY, ADD PRIMARY KEY (environment, namespace, pod, valid_from)"],
+    },
+    Migration {
+        version: 8,
+        description: "Create microattribute",
+        statements: &[r"CREATE TABLE microattribute (
+                environment VARCHAR(255),
+                namespace VARCHAR(255),
+                pod VARCHAR(255),
+                attribute VARCHAR(255),
+                value VARCHAR(255),
+                valid_from DATETIME NOT NULL,
+                valid_to DATETIME NULL,
+                PRIMARY KEY (environment, namespace, pod, attribute, valid_from)
+            )"],
+    },
+    Migration {
+        version: 9,
+        description: "Count CPU restarts",
+        statements: &[
+            "ALTER TABLE micrometrics ADD cpu_restarts INT",
+            "ALTER TABLE micrometrics_hourly ADD cpu_restarts INT",
+            "ALTER TABLE micrometrics_daily ADD cpu_restarts INT",
+        ],
+    },
+    Migration {
+        version: 10,
+        description: "Aggregate memory samples",
+        statements: &[r"ALTER TABLE micrometrics
+                ADD memory_usage_count INT, ADD memory_usage_min BIGINT, ADD memory_usage_max BIGINT,
+                ADD memory_usage_avg BIGINT, ADD memory_usage_p95 BIGINT"],
+    },
+    Migration {
+        version: 11,
+        description: "Remember the chunk that late samples were merged from",
+        statements: &[r"ALTER TABLE micrometrics ADD merged_chunk BIGINT"],
+    },
+];
+
+/// How timestamps are passed to the database, in UTC.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Wait between two rounds of partition maintenance.
+pub const DEFAULT_MAINTENANCE_PERIOD: Duration = Duration::from_secs(60 * 60);
+/// Number of partitions kept ready after the current one, so that inserts
+/// never land in the catch-all partition.
+const PARTITIONS_AHEAD: u32 = 3;
+/// Catch-all partition for rows beyond the pre-created ones.
+const FUTURE_PARTITION: &str = "p_future";
+/// Holds everything before the first partition of a newly partitioned table.
+const PAST_PARTITION: &str = "p_past";
+
+// MySQL server error codes that no retry can fix.
+const ER_COLUMN_NULL: u16 = 1048;
+const ER_NET_PACKET_TOO_LARGE: u16 = 1153;
+const ER_OUT_OF_RANGE: u16 = 1264;
+const ER_TRUNCATED_WRONG_VALUE: u16 = 1292;
+const ER_TRUNCATED_WRONG_VALUE_FOR_FIELD: u16 = 1366;
+const ER_DATA_TOO_LONG: u16 = 1406;
 
 /// Runs `attempt` until it succeeds, waiting `base_delay` after the first
 /// failure and twice as long after each further one, capped at
 /// MAX_CONNECT_DELAY. `wait` is injected so that tests do not have to sleep.
-fn retry_with_backoff<T, E, F, W>(
+pub(crate) fn retry_with_backoff<T, E, F, W>(
     mut attempt: F,
     attempts: u32,
     base_delay: Duration,
@@ -50,6 +302,10 @@ pub struct Database {
     chunk_size: usize,
     connect_attempts: u32,
     connect_base_delay: Duration,
+    write_attempts: u32,
+    spool: Spool,
+    rollups: Option<Rollups>,
+    expiry: Expiry,
 }
 
 impl Database {
@@ -75,6 +331,42 @@ impl Database {
             chunk_size,
             connect_attempts: attempts,
             connect_base_delay: base_delay,
+            write_attempts: DEFAULT_WRITE_ATTEMPTS,
+            spool: Spool::in_memory(DEFAULT_QUEUE_SIZE),
+            rollups: None,
+            expiry: Expiry::default(),
+        }
+    }
+
+    /// Keeps chunks that could not be written in `spool` instead of the
+    /// default in-memory queue, and tries each chunk `write_attempts` times
+    /// before handing it over.
+    pub fn with_spool(mut self, spool: Spool, write_attempts: u32) -> Self {
+        self.spool = spool;
+        self.write_attempts = write_attempts;
+        self
+    }
+
+    /// Keeps `micrometrics_hourly` and `micrometrics_daily` up to date with
+    /// every write, and deletes expired rollups once per `EXPIRY_PERIOD`.
+    pub fn with_rollups(mut self, rollups: Rollups) -> Self {
+        self.rollups = Some(rollups);
+        self
+    }
+
+    /// Partition maintenance on the same pool, to be run in the background.
+    /// Without a partition period, `micrometrics` stays a single table.
+    pub fn maintenance(
+        &self,
+        period: PartitionPeriod,
+        retention_days: Option<u32>,
+        interval: Duration,
+    ) -> Maintenance {
+        Maintenance {
+            pool: self.pool.lock().unwrap().clone(),
+            period,
+            retention_days,
+            interval,
         }
     }
 
@@ -91,97 +383,549 @@ impl Database {
         .expect("Failed to get connection")
     }
 
-    pub fn create_tables(&self) {
-        let mut conn = self.conn_with_retry();
-        conn.query_drop(
-            r"CREATE TABLE IF NOT EXISTS micrometrics (
-                time TIMESTAMP,
-                environment VARCHAR(255),
-                pod VARCHAR(255),
-                container VARCHAR(255),
-                cpu_usage FLOAT,
-                cpu_limit FLOAT,
-                memory_usage FLOAT,
-                memory_limit FLOAT,
-                PRIMARY KEY (time, environment, pod, container)
-            )",
-        )
-        .expect("Failed to create micrometrics table");
-
-        conn.query_drop(
-            r"CREATE TABLE IF NOT EXISTS microowner (
-                environment VARCHAR(255),
-                pod VARCHAR(255),
-                owner VARCHAR(255),
-                PRIMARY KEY (environment, pod)
-            )",
-        )
-        .expect("Failed to create microowner table");
+    fn write_with_retry(&self, chunk: pb::Chunk) {
+        self.spool.write(
+            chunk,
+            self.write_attempts,
+            self.connect_base_delay,
+            |chunk| self.write_chunk(chunk),
+            is_permanent,
+        );
     }
 
-    pub fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>) {
-        info!("Inserting {} metrics into the database", metrics.len());
+    /// Writes all rows of the chunk in one transaction, so that a chunk is
+    /// either written with its rollups or not at all.
+    fn write_chunk(&self, chunk: &pb::Chunk) -> Result<()> {
+        let mut conn = self.pool.lock().unwrap().get_conn()?;
+        let mut tx = conn.start_transaction(TxOpts::default())?;
 
-        let mut conn = self
-            .pool
-            .lock()
-            .unwrap()
-            .get_conn()
-            .expect("Failed to get connection");
-        let query = r"INSERT INTO micrometrics
-            (time, environment, pod, container, cpu_usage, cpu_limit, memory_usage, memory_limit)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-            ON DUPLICATE KEY UPDATE
-            cpu_usage = IFNULL(VALUES(cpu_usage), cpu_usage),
-            cpu_limit = IFNULL(VALUES(cpu_limit), cpu_limit),
-            memory_usage = IFNULL(VALUES(memory_usage), memory_usage),
-            memory_limit = IFNULL(VALUES(memory_limit), memory_limit)";
-
-        let insert_values: Vec<_> = metrics
-            .into_iter()
-            .filter_map(|(key, metrics)| {
-                if metrics.cpu_limit.is_none() && metrics.memory_limit.is_none() {
-                    return None;
+        if !chunk.metrics.is_empty() {
+            let (late, in_time): (Vec<_>, Vec<_>) = chunk.metrics.iter().partition(|row| row.late);
+            let merges = [
+                (INSERT_METRICS, in_time, None),
+                (MERGE_METRICS, late, Some(chunk.id as i64)),
+            ];
+            for (query, rows, merged_chunk) in merges {
+                if rows.is_empty() {
+                    continue;
                 }
-                chrono::DateTime::from_timestamp_millis(key.timestamp as i64).map(|timestamp| {
+                let insert_values = rows.into_iter().filter_map(|row| {
+                    chrono::DateTime::from_timestamp_millis(row.timestamp as i64).map(|timestamp| {
+                        // More parameters than a tuple can hold.
+                        Params::Positional(vec![
+                            timestamp.format(TIME_FORMAT).to_string().into(),
+                            row.environment.as_str().into(),
+                            row.namespace.as_str().into(),
+                            row.pod.as_str().into(),
+                            row.container.as_str().into(),
+                            row.cpu_usage.into(),
+                            row.cpu_limit.into(),
+                            row.memory_usage_bytes().into(),
+                            row.memory_limit_bytes().into(),
+                            row.cpu_request.into(),
+                            row.memory_request_bytes().into(),
+                            row.cpu_restarts.into(),
+                            row.memory_usage_count.into(),
+                            row.memory_usage_min_bytes().into(),
+                            row.memory_usage_max_bytes().into(),
+                            row.memory_usage_avg_bytes().into(),
+                            row.memory_usage_p95_bytes().into(),
+                            merged_chunk.into(),
+                        ])
+                    })
+                });
+                tx.exec_batch(query, insert_values)?;
+            }
+
+            if let Some(rollups) = &self.rollups {
+                refresh_rollups(&mut tx, rollups, &chunk.metrics)?;
+            }
+        }
+
+        write_history(&mut tx, &OWNERS, &chunk.owners)?;
+        write_history(&mut tx, &ATTRIBUTES, &chunk.attributes)?;
+
+        if !chunk.workloads.is_empty() {
+            // A pod whose ReplicaSet was not resolved before may be
+            // attributed to its Deployment later.
+            let query = r"INSERT INTO microworkload (environment, namespace, pod, kind, workload)
+                          VALUES (?, ?, ?, ?, ?)
+                          ON DUPLICATE KEY UPDATE kind = VALUES(kind), workload = VALUES(workload)";
+            tx.exec_batch(
+                query,
+                chunk.workloads.iter().map(|row| {
                     (
-                        timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
-                        key.environment,
-                        key.pod,
-                        key.container,
-                        metrics.cpu_usage,
-                        metrics.cpu_limit,
-                        metrics.memory_usage,
-                        metrics.memory_limit,
+                        &row.environment,
+                        &row.namespace,
+                        &row.pod,
+                        &row.kind,
+                        &row.workload,
                     )
-                })
-            })
+                }),
+            )?;
+        }
+
+        tx.commit()
+    }
+}
+
+/// Size of a `micrometrics` partition. Boundaries are midnight UTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionPeriod {
+    Day,
+    Month,
+}
+
+impl FromStr for PartitionPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "day" => Ok(PartitionPeriod::Day),
+            "month" => Ok(PartitionPeriod::Month),
+            _ => Err(format!("Unknown partition period {:?}", s)),
+        }
+    }
+}
+
+impl PartitionPeriod {
+    /// The first day of the period that contains `date`.
+    fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            PartitionPeriod::Day => date,
+            PartitionPeriod::Month => date.with_day(1).unwrap(),
+        }
+    }
+
+    fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            PartitionPeriod::Day => start.succ_opt().unwrap(),
+            PartitionPeriod::Month => start + Months::new(1),
+        }
+    }
+
+    /// Partitions are named after the first day they hold, e.g., `p20240708`
+    /// or `p202407`.
+    fn name(self, start: NaiveDate) -> String {
+        match self {
+            PartitionPeriod::Day => start.format("p%Y%m%d").to_string(),
+            PartitionPeriod::Month => start.format("p%Y%m").to_string(),
+        }
+    }
+}
+
+fn epoch(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp()
+}
+
+/// An existing partition and its upper bound in seconds, `None` for
+/// `MAXVALUE`.
+type ExistingPartition = (String, Option<i64>);
+
+/// What a round of maintenance changes.
+#[derive(Debug, Default, PartialEq)]
+struct PartitionPlan {
+    /// New partitions and their upper bounds, in ascending order.
+    create: Vec<(String, i64)>,
+    drop: Vec<String>,
+}
+
+/// Plans partitions up to PARTITIONS_AHEAD periods after `today`, and the
+/// removal of those that only hold rows from before `retention_days`.
+fn plan_partitions(
+    period: PartitionPeriod,
+    existing: &[ExistingPartition],
+    today: NaiveDate,
+    retention_days: Option<u32>,
+) -> PartitionPlan {
+    let last_bound = existing.iter().filter_map(|(_, bound)| *bound).max();
+    let mut plan = PartitionPlan::default();
+
+    let mut start = period.start(today);
+    for _ in 0..=PARTITIONS_AHEAD {
+        let end = period.next(start);
+        if last_bound.is_none_or(|last| epoch(end) > last) {
+            plan.create.push((period.name(start), epoch(end)));
+        }
+        start = end;
+    }
+
+    if let Some(days) = retention_days {
+        let cutoff = epoch(today - chrono::Days::new(days as u64));
+        plan.drop = existing
+            .iter()
+            .filter(|(_, bound)| bound.is_some_and(|bound| bound <= cutoff))
+            .map(|(name, _)| name.clone())
             .collect();
+    }
+    plan
+}
+
+/// The DDL for `plan`. New partitions are split off the catch-all partition,
+/// and a table that is not partitioned yet is partitioned as a whole.
+fn partition_statements(plan: &PartitionPlan, partitioned: bool, first_bound: i64) -> Vec<String> {
+    let mut definitions: Vec<String> = plan
+        .create
+        .iter()
+        .map(|(name, bound)| format!("PARTITION {} VALUES LESS THAN ({})", name, bound))
+        .collect();
+    definitions.push(format!(
+        "PARTITION {} VALUES LESS THAN MAXVALUE",
+        FUTURE_PARTITION
+    ));
+
+    let mut statements = Vec::new();
+    if !partitioned {
+        definitions.insert(
+            0,
+            format!(
+                "PARTITION {} VALUES LESS THAN ({})",
+                PAST_PARTITION, first_bound
+            ),
+        );
+        statements.push(format!(
+            "ALTER TABLE micrometrics PARTITION BY RANGE (UNIX_TIMESTAMP(time)) ({})",
+            definitions.join(", ")
+        ));
+    } else if !plan.create.is_empty() {
+        statements.push(format!(
+            "ALTER TABLE micrometrics REORGANIZE PARTITION {} INTO ({})",
+            FUTURE_PARTITION,
+            definitions.join(", ")
+        ));
+    }
+    if !plan.drop.is_empty() {
+        statements.push(format!(
+            "ALTER TABLE micrometrics DROP PARTITION {}",
+            plan.drop.join(", ")
+        ));
+    }
+    statements
+}
+
+/// Keeps `micrometrics` partitioned by time: creates upcoming partitions
+/// ahead of time and drops the ones past retention. Dropping a partition is
+/// instant and, unlike a `DELETE`, does not lock the rest of the table.
+pub struct Maintenance {
+    pool: Pool,
+    period: PartitionPeriod,
+    retention_days: Option<u32>,
+    interval: Duration,
+}
+
+impl Maintenance {
+    pub fn run_once(&self) -> Result<()> {
+        let mut conn = self.pool.get_conn()?;
+        let rows: Vec<(Option<String>, Option<String>)> = conn.query(
+            r"SELECT PARTITION_NAME, PARTITION_DESCRIPTION FROM information_schema.PARTITIONS
+            WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'micrometrics'
+            ORDER BY PARTITION_ORDINAL_POSITION",
+        )?;
+        // An unpartitioned table has a single row without a name.
+        let existing: Vec<ExistingPartition> = rows
+            .into_iter()
+            .filter_map(|(name, bound)| name.map(|name| (name, bound.and_then(|b| b.parse().ok()))))
+            .collect();
+
+        let today = Utc::now().date_naive();
+        let plan = plan_partitions(self.period, &existing, today, self.retention_days);
+        if !plan.create.is_empty() || !plan.drop.is_empty() {
+            info!(
+                "Creating partitions {:?} and dropping partitions {:?}",
+                plan.create.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+                plan.drop
+            );
+        }
+        let first_bound = epoch(self.period.start(today));
+        for statement in partition_statements(&plan, !existing.is_empty(), first_bound) {
+            debug!("{}", statement);
+            conn.query_drop(statement)?;
+        }
+        Ok(())
+    }
+
+    pub async fn run(self) {
+        let maintenance = Arc::new(self);
+        let mut ticker = tokio::time::interval(maintenance.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            let worker = maintenance.clone();
+            match tokio::task::spawn_blocking(move || worker.run_once()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Partition maintenance failed: {}", e),
+                Err(e) => error!("Partition maintenance failed: {}", e),
+            }
+        }
+    }
+}
+
+impl Sink for Database {
+    fn create_tables(&self) {
+        let mut conn = self.conn_with_retry();
+        conn.query_drop(format!(
+            r"CREATE TABLE IF NOT EXISTS {} (
+                version INT PRIMARY KEY,
+                description VARCHAR(255),
+                applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            VERSION_TABLE
+        ))
+        .expect("Failed to create schema version table");
+
+        // Replicas starting at the same time must not apply a migration twice.
+        // The lock belongs to the session, so everything below runs on `conn`.
+        let locked: Option<Option<i64>> = conn
+            .exec_first(
+                "SELECT GET_LOCK(?, ?)",
+                (MIGRATION_LOCK, MIGRATION_LOCK_TIMEOUT.as_secs()),
+            )
+            .expect("Failed to acquire migration lock");
+        assert_eq!(
+            locked.flatten(),
+            Some(1),
+            "Timed out waiting for the migration lock"
+        );
+
+        let current = schema_version(&mut conn).expect("Failed to read schema version");
+        migrate(MIGRATIONS, current, |migration| {
+            for statement in migration.statements {
+                conn.query_drop(statement)?;
+            }
+            conn.exec_drop(
+                format!(
+                    "INSERT INTO {} (version, description) VALUES (?, ?)",
+                    VERSION_TABLE
+                ),
+                (migration.version, migration.description),
+            )
+        })
+        .expect("Failed to migrate schema");
+
+        conn.exec_drop("DO RELEASE_LOCK(?)", (MIGRATION_LOCK,))
+            .expect("Failed to release migration lock");
+    }
+
+    fn pending_migrations(&self) -> Vec<&'static Migration> {
+        let mut conn = self.conn_with_retry();
+        let current = schema_version(&mut conn).expect("Failed to read schema version");
+        pending(MIGRATIONS, current)
+    }
+
+    fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>) {
+        info!("Inserting {} metrics into the database", metrics.len());
+        for chunk in metric_chunks(metrics, self.chunk_size) {
+            debug!("Inserting a chunk of {} metrics", chunk.metrics.len());
+            self.write_with_retry(chunk);
+        }
 
-        for chunk in insert_values.chunks(self.chunk_size) {
-            debug!("Inserting a chunk of {} metrics", chunk.len());
-            if let Err(e) = conn.exec_batch(query, chunk) {
-                eprintln!("Error inserting metrics: {}", e);
+        if let Some(rollups) = &self.rollups
+            && self.expiry.due()
+        {
+            let result = self
+                .pool
+                .lock()
+                .unwrap()
+                .get_conn()
+                .and_then(|mut conn| expire_rollups(&mut conn, rollups));
+            if let Err(e) = result {
+                warn!("Failed to delete expired rollups: {}", e);
             }
         }
     }
 
-    pub fn insert_owners(&self, owners: Vec<(String, String, String)>) {
+    fn insert_owners(&self, owners: Vec<(OwnerKey, OwnerValue)>) {
         info!("Inserting {} owners into the database", owners.len());
+        for chunk in owner_chunks(owners, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn insert_attributes(&self, attributes: Vec<(AttributeKey, AttributeValue)>) {
+        info!(
+            "Inserting {} attributes into the database",
+            attributes.len()
+        );
+        for chunk in attribute_chunks(attributes, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn insert_workloads(&self, workloads: Vec<(OwnerKey, Workload)>) {
+        info!("Inserting {} workloads into the database", workloads.len());
+        for chunk in workload_chunks(workloads, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn retry_pending(&self) {
+        self.spool
+            .retry(|chunk| self.write_chunk(chunk), is_permanent);
+    }
+
+    fn sync(&self) -> Result<bool, String> {
+        Ok(self.spool.is_durable())
+    }
+}
+
+/// Writes the observations `rows` to `history`, each in two statements.
+fn write_history<'a, T>(conn: &mut impl Queryable, history: &History, rows: &'a [T]) -> Result<()>
+where
+    Version<'a>: From<&'a T>,
+{
+    let rows: Vec<_> = rows
+        .iter()
+        .map(Version::from)
+        .filter_map(|version| {
+            chrono::DateTime::from_timestamp_millis(version.valid_from as i64)
+                .map(|valid_from| (valid_from.format(TIME_FORMAT).to_string(), version))
+        })
+        .collect();
+    if rows.is_empty() {
+        return Ok(());
+    }
+    conn.exec_batch(
+        history.close(),
+        rows.iter()
+            .map(|(valid_from, version)| version.close_params(valid_from)),
+    )?;
+    conn.exec_batch(
+        history.open("INSERT IGNORE", "FROM DUAL"),
+        rows.iter()
+            .map(|(valid_from, version)| version.open_params(valid_from)),
+    )?;
+    Ok(())
+}
+
+/// Recomputes the rollups of the hours and days that `rows` fall into.
+fn refresh_rollups(
+    conn: &mut impl Queryable,
+    rollups: &Rollups,
+    rows: &[pb::MetricRow],
+) -> Result<()> {
+    let refresh = rollups.refresh(rows, Utc::now());
+    let params = |time: &chrono::NaiveDateTime, series: &Series, length: chrono::Duration| {
+        params! {
+            "start" => time.format(TIME_FORMAT).to_string(),
+            "end" => (*time + length).format(TIME_FORMAT).to_string(),
+            "interval" => rollups.interval_secs,
+            "environment" => series.environment,
+            "namespace" => series.namespace,
+            "pod" => series.pod,
+            "container" => series.container,
+        }
+    };
+    conn.exec_batch(
+        r"INSERT INTO micrometrics_hourly
+        (time, environment, namespace, pod, container, cpu_usage, cpu_limit_seconds,
+        memory_usage_avg, memory_usage_max, memory_limit_max, samples, memory_samples,
+        cpu_request_seconds, memory_request_max, cpu_restarts)
+        SELECT :start, environment, namespace, pod, container, SUM(cpu_usage), SUM(cpu_limit) * :interval,
+        AVG(IFNULL(memory_usage_avg, memory_usage)), MAX(IFNULL(memory_usage_max, memory_usage)),
+        MAX(memory_limit), COUNT(*), COUNT(memory_usage),
+        SUM(cpu_request) * :interval, MAX(memory_request), SUM(cpu_restarts)
+        FROM micrometrics WHERE time >= :start AND time < :end
+        AND environment = :environment AND namespace = :namespace AND pod = :pod
+        AND container = :container
+        GROUP BY environment, namespace, pod, container
+        ON DUPLICATE KEY UPDATE
+        cpu_usage = VALUES(cpu_usage),
+        cpu_limit_seconds = VALUES(cpu_limit_seconds),
+        memory_usage_avg = VALUES(memory_usage_avg),
+        memory_usage_max = VALUES(memory_usage_max),
+        memory_limit_max = VALUES(memory_limit_max),
+        samples = VALUES(samples),
+        memory_samples = VALUES(memory_samples),
+        cpu_request_seconds = VALUES(cpu_request_seconds),
+        memory_request_max = VALUES(memory_request_max),
+        cpu_restarts = VALUES(cpu_restarts)",
+        refresh
+            .hours
+            .iter()
+            .map(|(hour, series)| params(hour, series, chrono::Duration::hours(1))),
+    )?;
+    conn.exec_batch(
+        r"INSERT INTO micrometrics_daily
+        (time, environment, namespace, pod, container, cpu_usage, cpu_limit_seconds,
+        memory_usage_avg, memory_usage_max, memory_limit_max, samples, memory_samples,
+        cpu_request_seconds, memory_request_max, cpu_restarts)
+        SELECT :start, environment, namespace, pod, container, SUM(cpu_usage), SUM(cpu_limit_seconds),
+        SUM(memory_usage_avg * memory_samples) / NULLIF(SUM(memory_samples), 0),
+        MAX(memory_usage_max), MAX(memory_limit_max), SUM(samples), SUM(memory_samples),
+        SUM(cpu_request_seconds), MAX(memory_request_max), SUM(cpu_restarts)
+        FROM micrometrics_hourly WHERE time >= :start AND time < :end
+        AND environment = :environment AND namespace = :namespace AND pod = :pod
+        AND container = :container
+        GROUP BY environment, namespace, pod, container
+        ON DUPLICATE KEY UPDATE
+        cpu_usage = VALUES(cpu_usage),
+        cpu_limit_seconds = VALUES(cpu_limit_seconds),
+        memory_usage_avg = VALUES(memory_usage_avg),
+        memory_usage_max = VALUES(memory_usage_max),
+        memory_limit_max = VALUES(memory_limit_max),
+        samples = VALUES(samples),
+        memory_samples = VALUES(memory_samples),
+        cpu_request_seconds = VALUES(cpu_request_seconds),
+        memory_request_max = VALUES(memory_request_max),
+        cpu_restarts = VALUES(cpu_restarts)",
+        refresh
+            .days
+            .iter()
+            .map(|(day, series)| params(day, series, chrono::Duration::days(1))),
+    )?;
+    Ok(())
+}
 
-        let mut conn = self
-            .pool
-            .lock()
-            .unwrap()
-            .get_conn()
-            .expect("Failed to get connection");
-        let query = r"INSERT IGNORE INTO microowner (environment, pod, owner)
-                      VALUES (?, ?, ?)";
-
-        if let Err(e) = conn.exec_batch(query, owners) {
-            eprintln!("Error inserting owners: {}", e);
+/// Deletes the rollups past their retention.
+fn expire_rollups(conn: &mut PooledConn, rollups: &Rollups) -> Result<()> {
+    let (hourly, daily) = rollups.cutoffs(Utc::now());
+    for (table, cutoff) in [
+        ("micrometrics_hourly", hourly),
+        ("micrometrics_daily", daily),
+    ] {
+        if let Some(cutoff) = cutoff {
+            conn.exec_drop(
+                format!("DELETE FROM {} WHERE time < ?", table),
+                (cutoff.format(TIME_FORMAT).to_string(),),
+            )?;
         }
     }
+    Ok(())
+}
+
+/// The latest applied migration, or 0 for a database that has never been
+/// migrated.
+fn schema_version(conn: &mut PooledConn) -> Result<u32> {
+    let exists: Option<u32> = conn.exec_first(
+        r"SELECT COUNT(*) FROM information_schema.tables
+        WHERE table_schema = DATABASE() AND table_name = ?",
+        (VERSION_TABLE,),
+    )?;
+    if exists.unwrap_or(0) == 0 {
+        return Ok(0);
+    }
+    let version: Option<Option<u32>> =
+        conn.query_first(format!("SELECT MAX(version) FROM {}", VERSION_TABLE))?;
+    Ok(version.flatten().unwrap_or(0))
+}
+
+/// Errors for which the same rows will fail no matter how often they are sent,
+/// e.g., because the chunk exceeds `max_allowed_packet`.
+fn is_permanent(e: &Error) -> bool {
+    match e {
+        Error::DriverError(DriverError::PacketTooLarge) => true,
+        Error::MySqlError(e) => matches!(
+            e.code,
+            ER_COLUMN_NULL
+                | ER_NET_PACKET_TOO_LARGE
+                | ER_OUT_OF_RANGE
+                | ER_TRUNCATED_WRONG_VALUE
+                | ER_TRUNCATED_WRONG_VALUE_FOR_FIELD
+                | ER_DATA_TOO_LONG
+        ),
+        _ => false,
+    }
 }
 
 #[cfg(test)]
@@ -300,4 +1044,119 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(calls.get(), 1);
     }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn test_partition_period_boundaries() {
+        let month = PartitionPeriod::Month;
+        assert_eq!(month.start(date("2024-01-31")), date("2024-01-01"));
+        assert_eq!(month.next(date("2024-12-01")), date("2025-01-01"));
+        assert_eq!(month.name(date("2024-07-01")), "p202407");
+        assert_eq!(PartitionPeriod::Day.name(date("2024-07-08")), "p20240708");
+        assert_eq!("Month".parse(), Ok(PartitionPeriod::Month));
+        assert!("week".parse::<PartitionPeriod>().is_err());
+    }
+
+    #[test]
+    fn test_plan_partitions_for_unpartitioned_table() {
+        let plan = plan_partitions(PartitionPeriod::Day, &[], date("2024-07-08"), Some(30));
+        assert_eq!(
+            plan.create,
+            vec![
+                ("p20240708".to_string(), epoch(date("2024-07-09"))),
+                ("p20240709".to_string(), epoch(date("2024-07-10"))),
+                ("p20240710".to_string(), epoch(date("2024-07-11"))),
+                ("p20240711".to_string(), epoch(date("2024-07-12"))),
+            ]
+        );
+        assert!(plan.drop.is_empty());
+
+        let statements = partition_statements(&plan, false, epoch(date("2024-07-08")));
+        assert_eq!(statements.len(), 1);
+        assert!(statements[0].starts_with(
+            "ALTER TABLE micrometrics PARTITION BY RANGE (UNIX_TIMESTAMP(time)) (PARTITION p_past VALUES LESS THAN (1720396800), PARTITION p20240708"
+        ));
+        assert!(statements[0].ends_with("PARTITION p_future VALUES LESS THAN MAXVALUE)"));
+    }
+
+    #[test]
+    fn test_plan_partitions_adds_ahead_and_drops_expired() {
+        let existing = vec![
+            ("p_past".to_string(), Some(epoch(date("2024-05-01")))),
+            ("p202405".to_string(), Some(epoch(date("2024-06-01")))),
+            ("p202406".to_string(), Some(epoch(date("2024-07-01")))),
+            ("p202407".to_string(), Some(epoch(date("2024-08-01")))),
+            ("p202408".to_string(), Some(epoch(date("2024-09-01")))),
+            ("p_future".to_string(), None),
+        ];
+        let plan = plan_partitions(
+            PartitionPeriod::Month,
+            &existing,
+            date("2024-07-15"),
+            Some(30),
+        );
+        assert_eq!(
+            plan,
+            PartitionPlan {
+                create: vec![
+                    ("p202409".to_string(), epoch(date("2024-10-01"))),
+                    ("p202410".to_string(), epoch(date("2024-11-01"))),
+                ],
+                drop: vec!["p_past".to_string(), "p202405".to_string()],
+            }
+        );
+
+        let statements = partition_statements(&plan, true, 0);
+        assert_eq!(
+            statements,
+            vec![
+                "ALTER TABLE micrometrics REORGANIZE PARTITION p_future INTO (PARTITION p202409 VALUES LESS THAN (1727740800), PARTITION p202410 VALUES LESS THAN (1730419200), PARTITION p_future VALUES LESS THAN MAXVALUE)".to_string(),
+                "ALTER TABLE micrometrics DROP PARTITION p_past, p202405".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_partitions_is_idempotent() {
+        let plan = plan_partitions(PartitionPeriod::Day, &[], date("2024-07-08"), None);
+        let mut existing: Vec<ExistingPartition> = plan
+            .create
+            .into_iter()
+            .map(|(name, bound)| (name, Some(bound)))
+            .collect();
+        existing.push(("p_future".to_string(), None));
+
+        let plan = plan_partitions(PartitionPeriod::Day, &existing, date("2024-07-08"), None);
+        assert_eq!(plan, PartitionPlan::default());
+        assert!(partition_statements(&plan, true, 0).is_empty());
+    }
+
+    fn server_error(code: u16) -> Error {
+        Error::MySqlError(MySqlError {
+            state: "HY000".to_string(),
+            message: "test".to_string(),
+            code,
+        })
+    }
+
+    #[test]
+    fn test_packet_too_large_is_permanent() {
+        assert!(is_permanent(&server_error(ER_NET_PACKET_TOO_LARGE)));
+        assert!(is_permanent(&Error::DriverError(
+            DriverError::PacketTooLarge
+        )));
+    }
+
+    #[test]
+    fn test_outage_is_not_permanent() {
+        // 1205 is a lock wait timeout, 2006 "server has gone away".
+        assert!(!is_permanent(&server_error(1205)));
+        assert!(!is_permanent(&server_error(2006)));
+        assert!(!is_permanent(&Error::DriverError(
+            DriverError::ConnectTimeout
+        )));
+    }
 }
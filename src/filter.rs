@@ -0,0 +1,287 @@
+use crate::labels::MappedLabels;
+use dashmap::DashSet;
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Distinct series dropped by the filters, by the name of the rule that
+/// dropped them.
+pub static FILTERED_SERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "microinsight_filtered_series_total",
+            "Series dropped by include and exclude rules",
+        ),
+        &["rule"],
+    )
+    .unwrap()
+});
+
+/// Name under which series that match none of the include rules are counted.
+const NOT_INCLUDED: &str = "not_included";
+
+/// A rule as written in the configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: Option<String>,
+    pod: Option<String>,
+    container: Option<String>,
+    namespace: Option<String>,
+    environment: Option<String>,
+}
+
+/// Matches a series if all of its regular expressions match, each anchored at
+/// both ends. Labels that a series lacks are matched as empty strings by
+/// exclude rules and ignored by include rules, so that, e.g., an include rule
+/// for containers keeps the owner series, which have none.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RuleConfig")]
+pub struct Rule {
+    name: Option<String>,
+    pod: Option<Regex>,
+    container: Option<Regex>,
+    namespace: Option<Regex>,
+    environment: Option<Regex>,
+}
+
+fn anchored(regex: Option<String>) -> Result<Option<Regex>, String> {
+    regex
+        .map(|regex| {
+            Regex::new(&format!("^(?:{})$", regex))
+                .map_err(|e| format!("Invalid regex {:?}: {}", regex, e))
+        })
+        .transpose()
+}
+
+impl TryFrom<RuleConfig> for Rule {
+    type Error = String;
+
+    fn try_from(config: RuleConfig) -> Result<Self, Self::Error> {
+        let rule = Rule {
+            name: config.name,
+            pod: anchored(config.pod)?,
+            container: anchored(config.container)?,
+            namespace: anchored(config.namespace)?,
+            environment: anchored(config.environment)?,
+        };
+        if rule.is_empty() {
+            return Err(
+                "A rule needs at least one of pod, container, namespace or environment".to_string(),
+            );
+        }
+        Ok(rule)
+    }
+}
+
+fn matches(regex: &Option<Regex>, value: &Option<String>) -> bool {
+    regex
+        .as_ref()
+        .is_none_or(|regex| regex.is_match(value.as_deref().unwrap_or("")))
+}
+
+fn includes(regex: &Option<Regex>, value: &Option<String>) -> bool {
+    match value {
+        Some(value) => regex.as_ref().is_none_or(|regex| regex.is_match(value)),
+        None => true,
+    }
+}
+
+impl Rule {
+    fn is_empty(&self) -> bool {
+        self.pod.is_none()
+            && self.container.is_none()
+            && self.namespace.is_none()
+            && self.environment.is_none()
+    }
+
+    fn matches(&self, labels: &MappedLabels) -> bool {
+        matches(&self.pod, &labels.pod)
+            && matches(&self.container, &labels.container)
+            && matches(&self.namespace, &labels.namespace)
+            && matches(&self.environment, &labels.environment)
+    }
+
+    fn includes(&self, labels: &MappedLabels) -> bool {
+        includes(&self.pod, &labels.pod)
+            && includes(&self.container, &labels.container)
+            && includes(&self.namespace, &labels.namespace)
+            && includes(&self.environment, &labels.environment)
+    }
+}
+
+/// Decides which series are stored. A series is kept if it matches any of
+/// the include rules, or if there are none, and none of the exclude rules.
+/// Every section that is left out of a filter file keeps its defaults, and
+/// every section that is given replaces them.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+    /// Hashes of the series dropped so far, so that each is counted once.
+    #[serde(skip)]
+    dropped: Arc<DashSet<u64>>,
+}
+
+impl Default for Filters {
+    /// Excludes the pause containers and the usual system pods.
+    fn default() -> Self {
+        let rule = |name: &str, container: Option<&str>, pod: Option<&str>| {
+            Rule::try_from(RuleConfig {
+                name: Some(name.to_string()),
+                pod: pod.map(str::to_string),
+                container: container.map(str::to_string),
+                namespace: None,
+                environment: None,
+            })
+            .unwrap()
+        };
+        Filters {
+            include: Vec::new(),
+            exclude: vec![
+                rule("pause", Some("POD"), None),
+                rule(
+                    "system",
+                    None,
+                    Some("(daemonset|deployment|kube|node|ebs|efs)-.*"),
+                ),
+            ],
+            dropped: Arc::default(),
+        }
+    }
+}
+
+impl Filters {
+    /// Reads the filters from a YAML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_yaml::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Whether a series is to be stored. Dropped series are counted in
+    /// `FILTERED_SERIES` the first time they are seen. The write-ahead log
+    /// holds only accepted series, so replaying it never comes here.
+    pub fn accept(&self, labels: &MappedLabels) -> bool {
+        if !self.include.is_empty() && !self.include.iter().any(|rule| rule.includes(labels)) {
+            self.count(labels, NOT_INCLUDED);
+            return false;
+        }
+        match self
+            .exclude
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(labels))
+        {
+            Some((index, rule)) => {
+                let name = match &rule.name {
+                    Some(name) => name.clone(),
+                    None => format!("exclude[{}]", index),
+                };
+                self.count(labels, &name);
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn count(&self, labels: &MappedLabels, rule: &str) {
+        let mut hasher = DefaultHasher::new();
+        labels.hash(&mut hasher);
+        if self.dropped.insert(hasher.finish()) {
+            FILTERED_SERIES.with_label_values(&[rule]).inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pod: &str, container: Option<&str>, namespace: Option<&str>) -> MappedLabels {
+        MappedLabels {
+            pod: Some(pod.to_string()),
+            container: container.map(str::to_string),
+            namespace: namespace.map(str::to_string),
+            environment: Some("prod".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn dropped(rule: &str) -> u64 {
+        FILTERED_SERIES.with_label_values(&[rule]).get()
+    }
+
+    #[test]
+    fn test_defaults_skip_pause_and_system_pods() {
+        let filters = Filters::default();
+        assert!(!filters.accept(&labels("test_pod", Some("POD"), None)));
+        assert!(!filters.accept(&labels("daemonset-test", Some("c"), None)));
+        assert!(!filters.accept(&labels("kube-proxy-1", None, None)));
+        assert!(filters.accept(&labels("test_pod", Some("c"), None)));
+        assert!(filters.accept(&labels("kubelet-1", Some("c"), None)));
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filters: Filters = serde_yaml::from_str(
+            "include:\n  - namespace: 'team-.*'\n  - pod: 'node-exporter-proxy-.*'\n\
+             exclude:\n  - name: test_sidecars\n    container: 'istio-proxy|linkerd-proxy'\n  \
+             - namespace: team-sandbox\n    environment: prod\n",
+        )
+        .unwrap();
+
+        assert!(filters.accept(&labels("app-1", Some("app"), Some("team-a"))));
+        assert!(filters.accept(&labels("node-exporter-proxy-1", Some("p"), None)));
+
+        let before = dropped("test_sidecars");
+        assert!(!filters.accept(&labels("app-1", Some("istio-proxy"), Some("team-a"))));
+        assert!(!filters.accept(&labels("app-1", Some("istio-proxy"), Some("team-a"))));
+        assert_eq!(dropped("test_sidecars"), before + 1);
+        assert!(!filters.accept(&labels("app-2", Some("istio-proxy"), Some("team-a"))));
+        assert_eq!(dropped("test_sidecars"), before + 2);
+
+        let before = dropped("exclude[1]");
+        assert!(!filters.accept(&labels("app-1", Some("app"), Some("team-sandbox"))));
+        assert_eq!(dropped("exclude[1]"), before + 1);
+
+        let before = dropped(NOT_INCLUDED);
+        assert!(!filters.accept(&labels("app-1", Some("app"), Some("default"))));
+        assert_eq!(dropped(NOT_INCLUDED), before + 1);
+    }
+
+    #[test]
+    fn test_include_rules_skip_missing_labels() {
+        let filters: Filters =
+            serde_yaml::from_str("include:\n  - container: app\n    namespace: 'team-.*'\n")
+                .unwrap();
+
+        // Owner series have neither a pod nor a container.
+        let owner = MappedLabels {
+            namespace: Some("team-a".to_string()),
+            environment: Some("prod".to_string()),
+            replicaset: Some("app-5d4f".to_string()),
+            ..Default::default()
+        };
+        assert!(filters.accept(&owner));
+        assert!(filters.accept(&labels("app-1", None, Some("team-a"))));
+        assert!(filters.accept(&labels("app-1", Some("app"), Some("team-a"))));
+        assert!(!filters.accept(&labels("app-1", Some("sidecar"), Some("team-a"))));
+        assert!(!filters.accept(&labels("app-1", None, Some("default"))));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let parse = |yaml| serde_yaml::from_str::<Filters>(yaml);
+        assert!(parse("exclude:\n  - name: empty\n").is_err());
+        assert!(parse("exclude:\n  - pod: '('\n").is_err());
+        assert!(parse("exclude:\n  - owner: a\n").is_err());
+    }
+}
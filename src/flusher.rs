@@ -0,0 +1,230 @@
+use crate::buffer_manager::BufferManager;
+use crate::sink::Sink;
+use crate::wal::Checkpoint;
+use log::{debug, error};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+/// Wait between two looks at the buffers for buckets that are due.
+pub const DEFAULT_FLUSH_PERIOD: Duration = Duration::from_secs(10);
+
+/// Moves due buckets from the buffers into the sink on its own timer.
+/// Keeping the inserts out of the request handler means that a slow batch
+/// insert never delays a remote write request, and that buckets still reach
+/// the database when no further traffic arrives.
+pub struct Flusher {
+    buffer_manager: Arc<BufferManager>,
+    sink: Box<dyn Sink>,
+    period: Duration,
+    /// Checkpoints of flushes whose rows the sink has not stored for good
+    /// yet, oldest first.
+    held: Mutex<Vec<Checkpoint>>,
+}
+
+impl Flusher {
+    pub fn new(buffer_manager: Arc<BufferManager>, sink: Box<dyn Sink>, period: Duration) -> Self {
+        Self {
+            buffer_manager,
+            sink,
+            period,
+            held: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn flush(&self) {
+        // Older rows go first, so that a recovered sink sees them in order.
+        self.sink.retry_pending();
+
+        let flushed = self.buffer_manager.flush();
+        debug!(
+            "Flushing {} metrics, {} owners, {} attributes and {} workloads",
+            flushed.metrics.len(),
+            flushed.owners.len(),
+            flushed.attributes.len(),
+            flushed.workloads.len()
+        );
+
+        if !flushed.metrics.is_empty() {
+            self.sink.insert_metrics(flushed.metrics);
+        }
+
+        if !flushed.owners.is_empty() {
+            self.sink.insert_owners(flushed.owners);
+        }
+
+        if !flushed.attributes.is_empty() {
+            self.sink.insert_attributes(flushed.attributes);
+        }
+
+        if !flushed.workloads.is_empty() {
+            self.sink.insert_workloads(flushed.workloads);
+        }
+
+        // The write-ahead log may only let go of rows that are stored for
+        // good, not of those waiting for a retry in memory.
+        let mut held = self.held.lock().unwrap();
+        held.extend(flushed.checkpoint);
+        match self.sink.sync() {
+            Ok(true) => {
+                for checkpoint in held.drain(..) {
+                    self.buffer_manager.commit(&checkpoint);
+                }
+            }
+            Ok(false) => debug!("Keeping the write-ahead log until the sink has stored the rows"),
+            Err(e) => error!(
+                "Keeping the write-ahead log, as the sink failed to store the rows: {}",
+                e
+            ),
+        }
+    }
+
+    pub async fn run(self) {
+        let flusher = Arc::new(self);
+        let mut ticker = tokio::time::interval(flusher.period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            // Sinks may block, e.g., the MySQL client, so keep them off the
+            // runtime's worker threads.
+            let worker = flusher.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || worker.flush()).await {
+                error!("Flushing the buffers failed: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics_buffer::MetricsBuffer;
+    use crate::metrics_buffer::{Key, Metrics};
+    use crate::owner_buffer::{AttributeKey, AttributeValue, Workload};
+    use crate::owner_buffer::{OwnerBuffer, OwnerKey, OwnerValue};
+    use crate::prometheus::{Label, Sample, TimeSeries, WriteRequest};
+    use crate::sink::testing::MemorySink;
+    use crate::wal::{DEFAULT_SEGMENT_SIZE, Wal};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::SystemTime;
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn owner_request() -> WriteRequest {
+        WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![
+                    label("cluster", "prod"),
+                    label("namespace", "shop"),
+                    label("pod", "pod-1"),
+                    label("__name__", "kube_pod_labels"),
+                    label("label_owner", "team-a"),
+                ],
+                samples: vec![Sample {
+                    value: 1.0,
+                    timestamp: 60_000,
+                }],
+                exemplars: vec![],
+                histograms: vec![],
+            }],
+            metadata: vec![],
+        }
+    }
+
+    fn segments(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("wal".as_ref()))
+            .count()
+    }
+
+    /// Stores rows for good only when told to.
+    struct Retrying(Arc<AtomicBool>);
+
+    impl Sink for Retrying {
+        fn create_tables(&self) {}
+
+        fn insert_metrics(&self, _metrics: Vec<(Key, Metrics)>) {}
+
+        fn insert_owners(&self, _owners: Vec<(OwnerKey, OwnerValue)>) {}
+
+        fn insert_attributes(&self, _attributes: Vec<(AttributeKey, AttributeValue)>) {}
+
+        fn insert_workloads(&self, _workloads: Vec<(OwnerKey, Workload)>) {}
+
+        fn sync(&self) -> Result<bool, String> {
+            Ok(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    #[test]
+    fn test_flush_hands_rows_to_sink() {
+        let buffer_manager = Arc::new(BufferManager::new(
+            MetricsBuffer::new(60000, 5),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+        ));
+        let sink = MemorySink::default();
+        let flusher = Flusher::new(
+            buffer_manager.clone(),
+            Box::new(sink.clone()),
+            DEFAULT_FLUSH_PERIOD,
+        );
+
+        buffer_manager
+            .process_write_request(owner_request())
+            .unwrap();
+        flusher.flush();
+
+        assert_eq!(
+            *sink.owners.lock().unwrap(),
+            vec![(
+                OwnerKey {
+                    environment: "prod".to_string(),
+                    namespace: "shop".to_string(),
+                    pod: "pod-1".to_string(),
+                },
+                OwnerValue {
+                    owner: "team-a".to_string(),
+                    valid_from: 60_000,
+                }
+            )]
+        );
+        assert!(sink.metrics.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_log_is_kept_until_sink_has_stored_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer_manager = Arc::new(BufferManager::with_wal(
+            MetricsBuffer::new(60000, 5),
+            OwnerBuffer::new(300, SystemTime::UNIX_EPOCH),
+            Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap(),
+        ));
+        let stored = Arc::new(AtomicBool::new(false));
+        let flusher = Flusher::new(
+            buffer_manager.clone(),
+            Box::new(Retrying(stored.clone())),
+            DEFAULT_FLUSH_PERIOD,
+        );
+
+        buffer_manager
+            .process_write_request(owner_request())
+            .unwrap();
+        flusher.flush();
+        assert_eq!(segments(dir.path()), 1);
+
+        // Once the retry went through, the next flush lets go of the owner,
+        // even though it no longer flushes any.
+        stored.store(true, Ordering::SeqCst);
+        flusher.flush();
+        assert_eq!(segments(dir.path()), 0);
+    }
+}
@@ -0,0 +1,189 @@
+use sketches_ddsketch::{Config, DDSketch};
+use std::fmt;
+use std::str::FromStr;
+
+/// Which statistics of the samples of a gauge are kept per bucket. The
+/// quantile needs a sketch per bucket, the others a few numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Aggregations {
+    pub count: bool,
+    pub min: bool,
+    pub max: bool,
+    pub avg: bool,
+    pub p95: bool,
+}
+
+impl Default for Aggregations {
+    fn default() -> Self {
+        Aggregations {
+            count: true,
+            min: true,
+            max: true,
+            avg: true,
+            p95: true,
+        }
+    }
+}
+
+impl FromStr for Aggregations {
+    type Err = String;
+
+    /// Parses a comma-separated list such as `avg,max,p95`. The average
+    /// comes with the count, which weights it when late samples are merged.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut aggregations = Aggregations {
+            count: false,
+            min: false,
+            max: false,
+            avg: false,
+            p95: false,
+        };
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name.to_lowercase().as_str() {
+                "count" => aggregations.count = true,
+                "min" => aggregations.min = true,
+                "max" => aggregations.max = true,
+                "avg" => aggregations.avg = true,
+                "p95" => aggregations.p95 = true,
+                _ => return Err(format!("Unknown aggregation {:?}", name)),
+            }
+        }
+        aggregations.count |= aggregations.avg;
+        Ok(aggregations)
+    }
+}
+
+/// The samples of a gauge within one bucket, e.g., of the memory usage.
+#[derive(Clone)]
+pub struct Gauge {
+    aggregations: Aggregations,
+    count: u32,
+    sum: f64,
+    min: f64,
+    max: f64,
+    /// Approximates the quantiles within 1% of the value.
+    sketch: Option<DDSketch>,
+}
+
+impl Gauge {
+    pub fn new(aggregations: Aggregations) -> Self {
+        Gauge {
+            aggregations,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sketch: aggregations.p95.then(|| DDSketch::new(Config::defaults())),
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if let Some(sketch) = &mut self.sketch {
+            sketch.add(value);
+        }
+    }
+
+    /// The value of an aggregation, if it is kept and there are samples.
+    fn get<T>(&self, kept: bool, value: impl FnOnce() -> T) -> Option<T> {
+        (kept && self.count > 0).then(value)
+    }
+
+    pub fn count(&self) -> Option<u32> {
+        self.get(self.aggregations.count, || self.count)
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.get(self.aggregations.min, || self.min)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.get(self.aggregations.max, || self.max)
+    }
+
+    pub fn avg(&self) -> Option<f64> {
+        self.get(self.aggregations.avg, || self.sum / self.count as f64)
+    }
+
+    pub fn p95(&self) -> Option<f64> {
+        self.sketch
+            .as_ref()
+            .and_then(|sketch| sketch.quantile(0.95).ok().flatten())
+    }
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Gauge::new(Aggregations::default())
+    }
+}
+
+impl fmt::Debug for Gauge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Gauge")
+            .field("count", &self.count())
+            .field("min", &self.min())
+            .field("max", &self.max())
+            .field("avg", &self.avg())
+            .field("p95", &self.p95())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gauge_aggregates_samples() {
+        let mut gauge = Gauge::default();
+        for value in 1..=100 {
+            gauge.observe(value as f64 * 1_000_000.0);
+        }
+
+        assert_eq!(gauge.count(), Some(100));
+        assert_eq!(gauge.min(), Some(1_000_000.0));
+        assert_eq!(gauge.max(), Some(100_000_000.0));
+        assert_eq!(gauge.avg(), Some(50_500_000.0));
+        let p95 = gauge.p95().unwrap();
+        assert!((p95 - 95_000_000.0).abs() <= 0.02 * 95_000_000.0, "{}", p95);
+    }
+
+    #[test]
+    fn test_empty_gauge_has_no_aggregations() {
+        let gauge = Gauge::default();
+
+        assert_eq!(gauge.count(), None);
+        assert_eq!(gauge.max(), None);
+        assert_eq!(gauge.p95(), None);
+    }
+
+    #[test]
+    fn test_only_configured_aggregations_are_kept() {
+        let mut gauge = Gauge::new("min, max".parse().unwrap());
+        gauge.observe(1.0);
+        gauge.observe(3.0);
+
+        assert_eq!(gauge.count(), None);
+        assert_eq!(gauge.min(), Some(1.0));
+        assert_eq!(gauge.max(), Some(3.0));
+        assert_eq!(gauge.avg(), None);
+        assert_eq!(gauge.p95(), None);
+    }
+
+    #[test]
+    fn test_parse_aggregations() {
+        assert_eq!(
+            "count,min,max,avg,p95".parse::<Aggregations>(),
+            Ok(Aggregations::default())
+        );
+        assert_eq!(
+            "avg".parse::<Aggregations>().map(|a| (a.avg, a.count)),
+            Ok((true, true))
+        );
+        assert!("p99".parse::<Aggregations>().is_err());
+    }
+}
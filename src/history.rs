@@ -0,0 +1,220 @@
+use crate::spool::pb;
+
+/// A table that keeps the history of a value, with one row per key and
+/// validity interval. `valid_to` is NULL for the current value.
+///
+/// Every backend writes an observation in two steps: it closes the current
+/// row of the key if the value differs and the row is older, then opens a row
+/// for the value if the key no longer has a current one. An empty value,
+/// i.e., one that is no longer reported, only closes. Both steps can be
+/// repeated, and an observation that arrives after a newer one is ignored, so
+/// a retried chunk does no harm.
+pub struct History {
+    pub table: &'static str,
+    /// Columns that identify whose value a row holds.
+    pub keys: &'static [&'static str],
+    pub value: &'static str,
+}
+
+pub const OWNERS: History = History {
+    table: "microowner",
+    keys: &["environment", "namespace", "pod"],
+    value: "owner",
+};
+
+pub const ATTRIBUTES: History = History {
+    table: "microattribute",
+    keys: &["environment", "namespace", "pod", "attribute"],
+    value: "value",
+};
+
+/// A value as observed for a key of a `History`.
+pub struct Version<'a> {
+    pub keys: Vec<&'a str>,
+    pub value: &'a str,
+    /// Milliseconds since the epoch.
+    pub valid_from: u64,
+}
+
+impl<'a> From<&'a pb::OwnerRow> for Version<'a> {
+    fn from(row: &'a pb::OwnerRow) -> Self {
+        Version {
+            keys: vec![&row.environment, &row.namespace, &row.pod],
+            value: &row.owner,
+            valid_from: row.valid_from,
+        }
+    }
+}
+
+impl<'a> From<&'a pb::AttributeRow> for Version<'a> {
+    fn from(row: &'a pb::AttributeRow) -> Self {
+        Version {
+            keys: vec![&row.environment, &row.namespace, &row.pod, &row.attribute],
+            value: &row.value,
+            valid_from: row.valid_from,
+        }
+    }
+}
+
+impl Version<'_> {
+    /// Parameters of `History::close`, given `valid_from` as the backend
+    /// expects it.
+    pub fn close_params<'a>(&'a self, valid_from: &'a str) -> Vec<&'a str> {
+        let mut params = vec![valid_from];
+        params.extend(&self.keys);
+        params.extend([self.value, valid_from]);
+        params
+    }
+
+    /// Parameters of `History::open`, like `close_params`.
+    pub fn open_params<'a>(&'a self, valid_from: &'a str) -> Vec<&'a str> {
+        let mut params = self.keys.clone();
+        params.extend([self.value, valid_from, self.value]);
+        params.extend(&self.keys);
+        params
+    }
+}
+
+impl History {
+    fn columns(&self) -> Vec<&'static str> {
+        let mut columns = self.keys.to_vec();
+        columns.extend([self.value, "valid_from"]);
+        columns
+    }
+
+    /// Matches the key columns with placeholders.
+    fn key_is_given(&self) -> String {
+        self.keys
+            .iter()
+            .map(|key| format!("{} = ?", key))
+            .collect::<Vec<_>>()
+            .join(" AND ")
+    }
+
+    /// Matches the key columns of the rows `o` with those of the rows `n`.
+    fn key_is_shared(&self) -> String {
+        self.keys
+            .iter()
+            .map(|key| format!("o.{key} = n.{key}"))
+            .collect::<Vec<_>>()
+            .join(" AND ")
+    }
+
+    /// Closes the current row of a key, for MySQL and SQLite.
+    pub fn close(&self) -> String {
+        format!(
+            "UPDATE {} SET valid_to = ? WHERE {} AND valid_to IS NULL AND {} <> ? AND valid_from < ?",
+            self.table,
+            self.key_is_given(),
+            self.value,
+        )
+    }
+
+    /// Opens a row for a key without a current one, for MySQL and SQLite.
+    /// `insert` is how the backend ignores duplicates, and `from` the table
+    /// it selects constants from, if it needs one.
+    pub fn open(&self, insert: &str, from: &str) -> String {
+        let columns = self.columns();
+        format!(
+            "{} INTO {} ({}) SELECT {} {} WHERE ? <> '' AND NOT EXISTS \
+             (SELECT 1 FROM {} WHERE {} AND valid_to IS NULL)",
+            insert,
+            self.table,
+            columns.join(", "),
+            vec!["?"; columns.len()].join(", "),
+            from,
+            self.table,
+            self.key_is_given(),
+        )
+    }
+
+    /// The observations passed as one array per column, for PostgreSQL. All
+    /// columns are text but `valid_from`.
+    fn unnest(&self) -> String {
+        let columns = self.columns();
+        let arrays: Vec<String> = (1..=columns.len())
+            .map(|i| {
+                if i == columns.len() {
+                    format!("${}::timestamp[]", i)
+                } else {
+                    format!("${}::varchar[]", i)
+                }
+            })
+            .collect();
+        format!(
+            "UNNEST({}) AS n ({})",
+            arrays.join(", "),
+            columns.join(", ")
+        )
+    }
+
+    /// Closes the current rows of all keys at once, for PostgreSQL.
+    pub fn close_unnest(&self) -> String {
+        format!(
+            "UPDATE {table} o SET valid_to = n.valid_from FROM {} WHERE {} \
+             AND o.valid_to IS NULL AND o.{value} <> n.{value} AND o.valid_from < n.valid_from",
+            self.unnest(),
+            self.key_is_shared(),
+            table = self.table,
+            value = self.value,
+        )
+    }
+
+    /// Opens rows for all keys without a current one, for PostgreSQL.
+    pub fn open_unnest(&self) -> String {
+        format!(
+            "INSERT INTO {table} ({}) SELECT * FROM {} WHERE n.{} <> '' AND NOT EXISTS \
+             (SELECT 1 FROM {table} o WHERE {} AND o.valid_to IS NULL) \
+             ON CONFLICT ({}, valid_from) DO NOTHING",
+            self.columns().join(", "),
+            self.unnest(),
+            self.value,
+            self.key_is_shared(),
+            self.keys.join(", "),
+            table = self.table,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statements_match_their_parameters() {
+        let row = pb::AttributeRow {
+            environment: "prod".to_string(),
+            namespace: "shop".to_string(),
+            pod: "pod-1".to_string(),
+            attribute: "cost_center".to_string(),
+            value: "cc-42".to_string(),
+            valid_from: 60_000,
+        };
+        let version = Version::from(&row);
+        let placeholders = |sql: String| sql.matches('?').count();
+
+        assert_eq!(
+            placeholders(ATTRIBUTES.close()),
+            version.close_params("").len()
+        );
+        assert_eq!(
+            placeholders(ATTRIBUTES.open("INSERT IGNORE", "FROM DUAL")),
+            version.open_params("").len()
+        );
+        assert_eq!(
+            OWNERS.close(),
+            "UPDATE microowner SET valid_to = ? WHERE environment = ? AND namespace = ? \
+             AND pod = ? AND valid_to IS NULL AND owner <> ? AND valid_from < ?"
+        );
+        assert_eq!(
+            OWNERS.open_unnest(),
+            "INSERT INTO microowner (environment, namespace, pod, owner, valid_from) \
+             SELECT * FROM UNNEST($1::varchar[], $2::varchar[], $3::varchar[], \
+             $4::varchar[], $5::timestamp[]) AS n (environment, namespace, pod, owner, \
+             valid_from) WHERE n.owner <> '' AND NOT EXISTS (SELECT 1 FROM microowner o \
+             WHERE o.environment = n.environment AND o.namespace = n.namespace \
+             AND o.pod = n.pod AND o.valid_to IS NULL) \
+             ON CONFLICT (environment, namespace, pod, valid_from) DO NOTHING"
+        );
+    }
+}
@@ -1,107 +1,232 @@
 use crate::prometheus::Label;
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::Path;
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, Eq, Hash)]
 pub struct MappedLabels {
     pub name: Option<String>,
     pub environment: Option<String>,
     pub pod: Option<String>,
     pub container: Option<String>,
+    pub namespace: Option<String>,
     pub owner: Option<String>,
+    pub owner_kind: Option<String>,
+    pub owner_name: Option<String>,
+    pub replicaset: Option<String>,
+    pub job: Option<String>,
+    /// Values of the labels listed in `attributes`, by attribute name.
+    pub attributes: BTreeMap<String, String>,
 }
 
-static LABEL_TO_COLUMN: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
-    [
-        ("container_label_io_kubernetes_pod_name", "pod"),
-        ("pod", "pod"),
-        ("container_label_io_kubernetes_container_name", "container"),
-        ("container", "container"),
-        ("cluster", "environment"),
-        ("cumulocity_environment", "environment"),
-        ("resource", "resource"),
-        ("label_owner", "owner"),
-        ("__name__", "dp_name"),
-    ]
-    .iter()
-    .cloned()
-    .collect()
-});
-
-static NAME_TO_COLUMN: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
-    [
-        ("container_cpu_usage_seconds_total", "cpu_usage_total"),
-        ("container_memory_working_set_bytes", "memory_usage"),
-        ("kube_pod_labels", "owner"),
-    ]
-    .iter()
-    .cloned()
-    .collect()
-});
-
-static POD_PREFIX_BLACKLIST: Lazy<Vec<&'static str>> = Lazy::new(|| {
-    vec![
-        "daemonset-",
-        "deployment-",
-        "kube-",
-        "node-",
-        "ebs-",
-        "efs-",
-    ]
-});
-
-pub fn map(labels: &[Label]) -> Option<MappedLabels> {
-    let mut result = MappedLabels::default();
-
-    for label in labels {
-        if let Some(&mapped_key) = LABEL_TO_COLUMN.get(label.name.as_str()) {
-            match mapped_key {
-                "pod" => result.pod = Some(label.value.clone()),
-                "container" => result.container = Some(label.value.clone()),
-                "environment" => result.environment = Some(label.value.clone()),
-                "owner" => result.owner = Some(label.value.clone()),
-                "dp_name" => result.name = Some(label.value.clone()),
-                _ => {}
-            }
+/// Columns that label values can be mapped to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Column {
+    Pod,
+    Container,
+    Namespace,
+    Environment,
+    Owner,
+    /// The kind of the controller in a `*_owner` series, e.g. `ReplicaSet`.
+    OwnerKind,
+    /// The name of the controller in a `*_owner` series.
+    OwnerName,
+    /// The ReplicaSet that a `replicaset_owner` series is about.
+    Replicaset,
+    /// The Job that a `job_owner` series is about.
+    Job,
+    /// The resource of a `resource_metrics` series, such as `cpu`.
+    Resource,
+    /// The metric name, which is looked up in `metrics` and `resource_metrics`.
+    Name,
+}
+
+/// Fields that a metric can be stored as.
+const FIELDS: &[&str] = &[
+    "cpu_usage_total",
+    "cpu_created",
+    "memory_usage",
+    "owner",
+    "namespace_labels",
+    "pod_owner",
+    "replicaset_owner",
+    "job_owner",
+];
+/// Resources whose limits and requests are stored.
+const RESOURCES: &[&str] = &["cpu", "memory"];
+/// Kinds of resource metrics.
+const KINDS: &[&str] = &["limit", "request"];
+
+/// How incoming labels and metric names are translated into columns and
+/// fields. Every section that is left out of a mapping file keeps its
+/// defaults, and every section that is given replaces them.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LabelMapping {
+    /// Label names and the columns their values go into.
+    pub labels: HashMap<String, Column>,
+    /// Metric names and the fields their samples are stored as.
+    pub metrics: HashMap<String, String>,
+    /// Metric names that are split by the resource column, and whether they
+    /// are limits or requests. Their samples are stored as
+    /// `<resource>_<kind>`, e.g. `cpu_limit`.
+    pub resource_metrics: HashMap<String, String>,
+    /// Values of the resource column and the resources they stand for.
+    pub resources: HashMap<String, String>,
+    /// Labels of `owner` and `namespace_labels` series that are stored as
+    /// attributes, and the names of the attributes.
+    pub attributes: HashMap<String, String>,
+}
+
+fn owned(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+impl Default for LabelMapping {
+    fn default() -> Self {
+        LabelMapping {
+            labels: [
+                ("container_label_io_kubernetes_pod_name", Column::Pod),
+                ("pod", Column::Pod),
+                (
+                    "container_label_io_kubernetes_container_name",
+                    Column::Container,
+                ),
+                ("container", Column::Container),
+                (
+                    "container_label_io_kubernetes_pod_namespace",
+                    Column::Namespace,
+                ),
+                ("namespace", Column::Namespace),
+                ("cluster", Column::Environment),
+                ("cumulocity_environment", Column::Environment),
+                ("resource", Column::Resource),
+                ("label_owner", Column::Owner),
+                ("owner_kind", Column::OwnerKind),
+                ("owner_name", Column::OwnerName),
+                ("replicaset", Column::Replicaset),
+                ("job_name", Column::Job),
+                ("__name__", Column::Name),
+            ]
+            .iter()
+            .map(|(name, column)| (name.to_string(), *column))
+            .collect(),
+            metrics: owned(&[
+                ("container_cpu_usage_seconds_total", "cpu_usage_total"),
+                ("container_cpu_usage_seconds_total_created", "cpu_created"),
+                ("container_memory_working_set_bytes", "memory_usage"),
+                ("kube_pod_labels", "owner"),
+                ("kube_namespace_labels", "namespace_labels"),
+                ("kube_pod_owner", "pod_owner"),
+                ("kube_replicaset_owner", "replicaset_owner"),
+                ("kube_job_owner", "job_owner"),
+            ]),
+            resource_metrics: owned(&[
+                ("kube_pod_container_resource_limits", "limit"),
+                ("kube_pod_container_resource_requests", "request"),
+            ]),
+            resources: owned(&[("cpu", "cpu"), ("memory", "memory")]),
+            attributes: HashMap::new(),
         }
     }
+}
+
+fn check(section: &str, values: &HashMap<String, String>, known: &[&str]) -> Result<(), String> {
+    match values.values().find(|v| !known.contains(&v.as_str())) {
+        Some(value) => Err(format!(
+            "Unknown value {:?} in {}, expected one of {:?}",
+            value, section, known
+        )),
+        None => Ok(()),
+    }
+}
+
+impl LabelMapping {
+    /// Reads a mapping from a YAML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mapping: LabelMapping =
+            serde_yaml::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e))?;
+        mapping.validate()?;
+        Ok(mapping)
+    }
 
-    if let Some(dp_name) = &result.name {
-        if let Some(&mapped_name) = NAME_TO_COLUMN.get(dp_name.as_str()) {
-            result.name = Some(mapped_name.to_string());
-        } else if dp_name == "kube_pod_container_resource_limits" {
-            if let Some(resource) = labels.iter().find(|l| l.name == "resource") {
-                if resource.value == "cpu" {
-                    result.name = Some("cpu_limit".to_string());
-                } else if resource.value == "memory" {
-                    result.name = Some("memory_limit".to_string());
+    fn validate(&self) -> Result<(), String> {
+        check("metrics", &self.metrics, FIELDS)?;
+        check("resource_metrics", &self.resource_metrics, KINDS)?;
+        check("resources", &self.resources, RESOURCES)?;
+        match self.attributes.iter().find(|(_, name)| name.is_empty()) {
+            Some((label, _)) => Err(format!("Empty attribute name for label {:?}", label)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn map(&self, labels: &[Label]) -> Option<MappedLabels> {
+        let mut result = MappedLabels::default();
+        let mut resource = None;
+
+        for label in labels {
+            if let Some(attribute) = self.attributes.get(label.name.as_str()) {
+                result
+                    .attributes
+                    .insert(attribute.clone(), label.value.clone());
+            }
+            if let Some(column) = self.labels.get(label.name.as_str()) {
+                let value = Some(label.value.clone());
+                match column {
+                    Column::Pod => result.pod = value,
+                    Column::Container => result.container = value,
+                    Column::Namespace => result.namespace = value,
+                    Column::Environment => result.environment = value,
+                    Column::Owner => result.owner = value,
+                    Column::OwnerKind => result.owner_kind = value,
+                    Column::OwnerName => result.owner_name = value,
+                    Column::Replicaset => result.replicaset = value,
+                    Column::Job => result.job = value,
+                    Column::Resource => resource = value,
+                    Column::Name => result.name = value,
                 }
             }
         }
-    }
 
-    if result.container.as_deref() == Some("POD")
-        || result.pod.is_none()
-        || result
-            .pod
-            .as_ref()
-            .map(|pod| {
-                POD_PREFIX_BLACKLIST
-                    .iter()
-                    .any(|prefix| pod.starts_with(prefix))
-            })
-            .unwrap_or(false)
-    {
-        return None;
-    }
+        if let Some(dp_name) = &result.name {
+            if let Some(field) = self.metrics.get(dp_name) {
+                result.name = Some(field.clone());
+            } else if let Some(kind) = self.resource_metrics.get(dp_name)
+                && let Some(resource) = resource.and_then(|r| self.resources.get(&r))
+            {
+                result.name = Some(format!("{}_{}", resource, kind));
+            }
+        }
+
+        // Apart from the owners of ReplicaSets and Jobs and the labels of
+        // namespaces, every series is about a pod.
+        let about_pod = !matches!(
+            result.name.as_deref(),
+            Some("replicaset_owner" | "job_owner" | "namespace_labels")
+        );
+        if about_pod && result.pod.is_none() {
+            return None;
+        }
 
-    Some(result)
+        Some(result)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn map(labels: &[Label]) -> Option<MappedLabels> {
+        LabelMapping::default().map(labels)
+    }
+
     #[test]
     fn test_map_straight() {
         let labels = vec![
@@ -191,39 +316,34 @@ mod tests {
     }
 
     #[test]
-    fn test_map_skip_pod() {
-        let labels = vec![
-            Label {
-                name: "container".to_string(),
-                value: "POD".to_string(),
-            },
-            Label {
-                name: "pod".to_string(),
-                value: "test_pod".to_string(),
-            },
-        ];
+    fn test_map_ksm_requests() {
+        for (resource, name) in [("cpu", "cpu_request"), ("memory", "memory_request")] {
+            let labels = vec![
+                Label {
+                    name: "resource".to_string(),
+                    value: resource.to_string(),
+                },
+                Label {
+                    name: "__name__".to_string(),
+                    value: "kube_pod_container_resource_requests".to_string(),
+                },
+                Label {
+                    name: "pod".to_string(),
+                    value: "test_pod".to_string(),
+                },
+            ];
 
-        let result = map(&labels);
+            let result = map(&labels);
 
-        assert_eq!(result, None);
-    }
-
-    #[test]
-    fn test_map_skip_blacklisted_pod() {
-        let labels = vec![
-            Label {
-                name: "container".to_string(),
-                value: "test_container".to_string(),
-            },
-            Label {
-                name: "pod".to_string(),
-                value: "daemonset-test".to_string(),
-            },
-        ];
-
-        let result = map(&labels);
-
-        assert_eq!(result, None);
+            assert_eq!(
+                result,
+                Some(MappedLabels {
+                    name: Some(name.to_string()),
+                    pod: Some("test_pod".to_string()),
+                    ..Default::default()
+                })
+            );
+        }
     }
 
     #[test]
@@ -267,4 +387,116 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_map_replicaset_owner() {
+        let labels = vec![
+            Label {
+                name: "__name__".to_string(),
+                value: "kube_replicaset_owner".to_string(),
+            },
+            Label {
+                name: "replicaset".to_string(),
+                value: "web-5d4f".to_string(),
+            },
+            Label {
+                name: "owner_kind".to_string(),
+                value: "Deployment".to_string(),
+            },
+            Label {
+                name: "owner_name".to_string(),
+                value: "web".to_string(),
+            },
+        ];
+
+        let result = map(&labels);
+
+        assert_eq!(
+            result,
+            Some(MappedLabels {
+                name: Some("replicaset_owner".to_string()),
+                replicaset: Some("web-5d4f".to_string()),
+                owner_kind: Some("Deployment".to_string()),
+                owner_name: Some("web".to_string()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_map_namespace_attributes() {
+        let mapping: LabelMapping = serde_yaml::from_str(
+            "attributes:\n  label_owner: owner\n  label_cost_center: cost_center\n",
+        )
+        .unwrap();
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+
+        let result = mapping.map(&[
+            label("__name__", "kube_namespace_labels"),
+            label("namespace", "shop"),
+            label("label_cost_center", "cc-42"),
+            label("label_product", "ignored"),
+        ]);
+
+        assert_eq!(
+            result,
+            Some(MappedLabels {
+                name: Some("namespace_labels".to_string()),
+                namespace: Some("shop".to_string()),
+                attributes: [("cost_center".to_string(), "cc-42".to_string())].into(),
+                ..Default::default()
+            })
+        );
+    }
+
+    fn load(yaml: &str) -> Result<LabelMapping, String> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, yaml.as_bytes()).unwrap();
+        LabelMapping::load(file.path())
+    }
+
+    #[test]
+    fn test_load_replaces_given_sections() {
+        let mapping = load(
+            "labels:\n  kubernetes_pod: pod\n  container: container\n  site: environment\n  \
+             gpu_resource: resource\n  __name__: name\n\
+             resource_metrics:\n  kube_pod_container_resource_limits_custom: limit\n\
+             resources:\n  nvidia.com/cpu: cpu\n",
+        )
+        .unwrap();
+        assert_eq!(mapping.metrics, LabelMapping::default().metrics);
+
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let result = mapping.map(&[
+            label("__name__", "kube_pod_container_resource_limits_custom"),
+            label("gpu_resource", "nvidia.com/cpu"),
+            label("kubernetes_pod", "test_pod"),
+            label("site", "test_prod"),
+            label("cluster", "ignored"),
+        ]);
+        assert_eq!(
+            result,
+            Some(MappedLabels {
+                name: Some("cpu_limit".to_string()),
+                environment: Some("test_prod".to_string()),
+                pod: Some("test_pod".to_string()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_unknown_values() {
+        assert!(load("labels:\n  pod: workload\n").is_err());
+        assert!(load("metrics:\n  container_network_bytes: network\n").is_err());
+        assert!(load("resources:\n  gpu: gpu\n").is_err());
+        assert!(load("unknown:\n  a: b\n").is_err());
+        assert!(load("attributes:\n  label_owner: ''\n").is_err());
+    }
 }
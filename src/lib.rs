@@ -1,10 +1,14 @@
 use actix_web::{App, HttpResponse, HttpServer, Responder, middleware::Logger, web};
 use actix_web_prometheus::PrometheusMetricsBuilder;
 use buffer_manager::BufferManager;
-use database::Database;
+use flusher::Flusher;
+use log::error;
 use prometheus::WriteRequest;
 use prost::Message;
+use sink::Sink;
 use snap::raw::Decoder;
+use std::sync::Arc;
+use std::time::Duration;
 use sysinfo::System;
 
 pub mod prometheus {
@@ -12,33 +16,75 @@ pub mod prometheus {
 }
 
 pub mod buffer_manager;
+pub mod clock;
 pub mod database;
+pub mod durable;
+pub mod filter;
+pub mod flusher;
+pub mod gauge;
+pub mod history;
 pub mod labels;
 pub mod metrics_buffer;
+pub mod migrations;
 pub mod owner_buffer;
+pub mod parquet_sink;
+pub mod postgresql;
+pub mod relabel;
+pub mod rollup;
+pub mod sink;
+pub mod spool;
+pub mod sqlite;
+pub mod wal;
 
 static MAX_PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
 
 pub struct Server {
-    buffer_manager: BufferManager,
-    database: Database,
+    buffer_manager: Arc<BufferManager>,
+    flusher: Flusher,
 }
 
 impl Server {
-    pub fn new(buffer_manager: BufferManager, database: Database) -> Self {
+    pub fn new(
+        buffer_manager: BufferManager,
+        sink: impl Sink + 'static,
+        flush_period: Duration,
+    ) -> Self {
+        let buffer_manager = Arc::new(buffer_manager);
+        let flusher = Flusher::new(buffer_manager.clone(), Box::new(sink), flush_period);
         Self {
             buffer_manager,
-            database,
+            flusher,
         }
     }
 
     pub async fn run(self) -> std::io::Result<actix_web::dev::Server> {
-        let server_data = web::Data::new(self);
+        let server_data = web::Data::from(self.buffer_manager);
+        tokio::spawn(self.flusher.run());
 
         let prometheus = PrometheusMetricsBuilder::new("api")
             .endpoint("/metrics")
             .build()
             .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(filter::FILTERED_SERIES.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(metrics_buffer::LATE_SAMPLES.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(metrics_buffer::MERGED_SAMPLES.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(metrics_buffer::DROPPED_SAMPLES.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(spool::DROPPED_ROWS.clone()))
+            .unwrap();
 
         let server = HttpServer::new(move || {
             App::new()
@@ -75,7 +121,10 @@ async fn health() -> impl Responder {
     ))
 }
 
-async fn receive_data(server: web::Data<Server>, body: web::Bytes) -> impl Responder {
+async fn receive_data(
+    buffer_manager: web::Data<BufferManager>,
+    body: web::Bytes,
+) -> impl Responder {
     let mut decoder = Decoder::new();
     let decompressed_data = match decoder.decompress_vec(&body) {
         Ok(data) => data,
@@ -87,16 +136,15 @@ async fn receive_data(server: web::Data<Server>, body: web::Bytes) -> impl Respo
         Err(_) => return HttpResponse::BadRequest().body("Failed to parse WriteRequest"),
     };
 
-    let (processed_samples, metrics_to_flush, owners_to_flush) =
-        server.buffer_manager.process_write_request(write_request);
-
-    if !metrics_to_flush.is_empty() {
-        server.database.insert_metrics(metrics_to_flush);
-    }
-
-    if !owners_to_flush.is_empty() {
-        server.database.insert_owners(owners_to_flush);
-    }
+    // Prometheus retries on server errors, so a request that could not be
+    // logged durably is sent again rather than lost.
+    let processed_samples = match buffer_manager.process_write_request(write_request) {
+        Ok(samples) => samples,
+        Err(e) => {
+            error!("Failed to write to the write-ahead log: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to persist samples");
+        }
+    };
 
     HttpResponse::NoContent()
         .insert_header((
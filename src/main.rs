@@ -1,11 +1,26 @@
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use microinsight::{
     Server,
     buffer_manager::BufferManager,
-    database::{DEFAULT_CONNECT_ATTEMPTS, DEFAULT_CONNECT_BASE_DELAY, Database},
-    metrics_buffer::MetricsBuffer,
-    owner_buffer::OwnerBuffer,
+    database::{
+        DEFAULT_CONNECT_ATTEMPTS, DEFAULT_CONNECT_BASE_DELAY, DEFAULT_MAINTENANCE_PERIOD,
+        DEFAULT_WRITE_ATTEMPTS, Database, Maintenance, PartitionPeriod,
+    },
+    filter::Filters,
+    flusher::DEFAULT_FLUSH_PERIOD,
+    gauge::Aggregations,
+    labels::LabelMapping,
+    metrics_buffer::{LatePolicy, MetricsBuffer},
+    owner_buffer::{OwnerBuffer, OwnerPrecedence},
+    parquet_sink::{DEFAULT_MAX_FILE_AGE, DEFAULT_MAX_FILE_SIZE, ParquetSink},
+    postgresql::PostgresDatabase,
+    relabel::Relabeler,
+    rollup::{DEFAULT_DAILY_RETENTION_DAYS, DEFAULT_HOURLY_RETENTION_DAYS, Rollups},
+    sink::{FanOut, Sink},
+    spool::{DEFAULT_QUEUE_SIZE, Spool},
+    sqlite::SqliteDatabase,
+    wal::{DEFAULT_SEGMENT_SIZE, Wal},
 };
 
 fn init_logging() {
@@ -18,11 +33,10 @@ fn init_logging() {
         .init();
 }
 
-fn init_db() -> Database {
-    let db_host = std::env::var("DB_HOST").expect("DB_HOST must be set");
-    let db_user = std::env::var("DB_USER").expect("DB_USER must be set");
-    let db_pass = std::env::var("DB_PASS").expect("DB_PASS must be set");
-    let db_name = std::env::var("DB_NAME").expect("DB_NAME must be set");
+/// `DB_URL` selects the backend by its scheme. Without it, the MySQL URL is
+/// assembled from the individual `DB_*` settings. Partition maintenance is
+/// only available for MySQL.
+fn init_db() -> (Box<dyn Sink>, Option<Maintenance>) {
     let chunk_size = std::env::var("CHUNK_SIZE")
         .ok()
         .and_then(|v| v.parse().ok())
@@ -32,45 +46,279 @@ fn init_db() -> Database {
         .ok()
         .and_then(|v| v.parse().ok())
         .unwrap_or(DEFAULT_CONNECT_ATTEMPTS);
+    let write_attempts = std::env::var("DB_WRITE_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_WRITE_ATTEMPTS);
+
+    let db_url = match std::env::var("DB_URL") {
+        Ok(url) if !url.is_empty() => url,
+        _ => {
+            let db_host = std::env::var("DB_HOST").expect("DB_HOST must be set");
+            let db_user = std::env::var("DB_USER").expect("DB_USER must be set");
+            let db_pass = std::env::var("DB_PASS").expect("DB_PASS must be set");
+            let db_name = std::env::var("DB_NAME").expect("DB_NAME must be set");
+            format!("mysql://{}:{}@{}/{}", db_user, db_pass, db_host, db_name)
+        }
+    };
 
-    let db_url = format!("mysql://{}:{}@{}/{}", db_user, db_pass, db_host, db_name);
-    let database = Database::connect(
-        &db_url,
-        chunk_size,
-        connect_attempts,
-        DEFAULT_CONNECT_BASE_DELAY,
-    );
-    database.create_tables();
-    database
+    let (scheme, location) = db_url.split_once("://").unwrap_or_default();
+    let partition_period = init_partition_period();
+    if partition_period.is_some() && scheme != "mysql" {
+        panic!("PARTITION_BY is only supported for MySQL");
+    }
+    match scheme {
+        "mysql" => {
+            let database = Database::connect(
+                &db_url,
+                chunk_size,
+                connect_attempts,
+                DEFAULT_CONNECT_BASE_DELAY,
+            )
+            .with_spool(init_spool(), write_attempts)
+            .with_rollups(init_rollups());
+            let maintenance = partition_period.map(|(period, retention_days)| {
+                database.maintenance(period, retention_days, DEFAULT_MAINTENANCE_PERIOD)
+            });
+            (Box::new(database), maintenance)
+        }
+        "postgres" | "postgresql" => (
+            Box::new(
+                PostgresDatabase::connect(
+                    &db_url,
+                    chunk_size,
+                    connect_attempts,
+                    DEFAULT_CONNECT_BASE_DELAY,
+                )
+                .with_spool(init_spool(), write_attempts)
+                .with_rollups(init_rollups()),
+            ),
+            None,
+        ),
+        "sqlite" => (
+            Box::new(
+                SqliteDatabase::open(location, chunk_size)
+                    .with_spool(init_spool(), write_attempts)
+                    .with_rollups(init_rollups()),
+            ),
+            None,
+        ),
+        _ => panic!("Unsupported database {:?} in DB_URL", scheme),
+    }
 }
 
-fn init_buffers() -> BufferManager {
-    let metrics_interval = std::env::var("INTERVAL")
+/// `PARTITION_BY` and the optional `RETENTION_DAYS`. Retention drops whole
+/// partitions, so it requires partitioning.
+fn init_partition_period() -> Option<(PartitionPeriod, Option<u32>)> {
+    let period = match std::env::var("PARTITION_BY") {
+        Ok(period) if !period.is_empty() => Some(period.parse().expect("Invalid PARTITION_BY")),
+        _ => None,
+    };
+    let retention_days = std::env::var("RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&days: &u32| days > 0);
+    match (period, retention_days) {
+        (Some(period), retention_days) => Some((period, retention_days)),
+        (None, Some(_)) => panic!("RETENTION_DAYS requires PARTITION_BY"),
+        (None, None) => None,
+    }
+}
+
+/// With `PARQUET_DIR`, the metrics are additionally exported as Parquet files.
+fn init_export(sink: Box<dyn Sink>) -> Box<dyn Sink> {
+    let parquet_dir = match std::env::var("PARQUET_DIR") {
+        Ok(dir) if !dir.is_empty() => dir,
+        _ => return sink,
+    };
+    let max_file_size = std::env::var("PARQUET_MAX_FILE_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_FILE_SIZE);
+    let max_file_age = std::env::var("PARQUET_MAX_FILE_AGE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_MAX_FILE_AGE);
+
+    let parquet = ParquetSink::new(parquet_dir, max_file_size, max_file_age);
+    Box::new(FanOut::new(vec![sink, Box::new(parquet)]))
+}
+
+fn init_spool() -> Spool {
+    let queue_size = std::env::var("RETRY_QUEUE_SIZE")
         .ok()
         .and_then(|v| v.parse().ok())
-        .unwrap_or(60);
+        .unwrap_or(DEFAULT_QUEUE_SIZE);
+
+    match std::env::var("SPOOL_DIR") {
+        Ok(dir) if !dir.is_empty() => {
+            Spool::open(queue_size, dir).expect("Failed to open spool directory")
+        }
+        _ => Spool::in_memory(queue_size),
+    }
+}
+
+/// Length of a bucket in seconds.
+fn init_interval() -> u64 {
+    std::env::var("INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60)
+}
+
+/// Retention of the rollups, where 0 keeps them forever.
+fn init_rollups() -> Rollups {
+    let retention_days = |name: &str, default: u32| {
+        let days = std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default);
+        Some(days).filter(|&days| days > 0)
+    };
+    Rollups {
+        interval_secs: init_interval(),
+        hourly_retention_days: retention_days(
+            "HOURLY_RETENTION_DAYS",
+            DEFAULT_HOURLY_RETENTION_DAYS,
+        ),
+        daily_retention_days: retention_days("DAILY_RETENTION_DAYS", DEFAULT_DAILY_RETENTION_DAYS),
+    }
+}
+
+/// `LABEL_MAPPING` names a YAML file that replaces the default translation of
+/// labels and metric names.
+fn init_label_mapping() -> LabelMapping {
+    match std::env::var("LABEL_MAPPING") {
+        Ok(path) if !path.is_empty() => {
+            LabelMapping::load(&path).expect("Failed to load label mapping")
+        }
+        _ => LabelMapping::default(),
+    }
+}
+
+/// `RELABEL_CONFIG` names a YAML file with Prometheus-style relabeling rules
+/// for incoming series.
+fn init_relabeler() -> Relabeler {
+    match std::env::var("RELABEL_CONFIG") {
+        Ok(path) if !path.is_empty() => {
+            Relabeler::load(&path).expect("Failed to load relabeling rules")
+        }
+        _ => Relabeler::default(),
+    }
+}
+
+/// `FILTER_CONFIG` names a YAML file with include and exclude rules that
+/// replace the default filters.
+fn init_filters() -> Filters {
+    match std::env::var("FILTER_CONFIG") {
+        Ok(path) if !path.is_empty() => Filters::load(&path).expect("Failed to load filters"),
+        _ => Filters::default(),
+    }
+}
+
+fn init_buffers() -> BufferManager {
+    let metrics_interval = init_interval();
     let metrics_max_delay = std::env::var("MAX_DELAY")
         .ok()
         .and_then(|v| v.parse().ok())
         .unwrap_or(5);
+    let memory_aggregations = match std::env::var("MEMORY_AGGREGATIONS") {
+        Ok(aggregations) if !aggregations.is_empty() => {
+            aggregations.parse().expect("Invalid MEMORY_AGGREGATIONS")
+        }
+        _ => Aggregations::default(),
+    };
+    let late_policy = match std::env::var("LATE_POLICY") {
+        Ok(policy) if !policy.is_empty() => policy.parse().expect("Invalid LATE_POLICY"),
+        _ => LatePolicy::default(),
+    };
     let owner_flush_interval = std::env::var("OWNER_FLUSH_INTERVAL")
         .ok()
         .and_then(|v| v.parse().ok())
         .unwrap_or(300);
+    let owner_precedence = match std::env::var("OWNER_PRECEDENCE") {
+        Ok(precedence) if !precedence.is_empty() => {
+            precedence.parse().expect("Invalid OWNER_PRECEDENCE")
+        }
+        _ => OwnerPrecedence::default(),
+    };
+
+    let mut metrics_buffer = MetricsBuffer::new(metrics_interval * 1000, metrics_max_delay)
+        .with_aggregations(memory_aggregations)
+        .with_late_policy(late_policy);
+    if let Some(idle_timeout) = std::env::var("IDLE_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        metrics_buffer = metrics_buffer.with_idle_timeout(Duration::from_secs(idle_timeout));
+    }
+    let owner_buffer =
+        OwnerBuffer::new(owner_flush_interval, SystemTime::now()).with_precedence(owner_precedence);
+
+    let wal_dir = match std::env::var("WAL_DIR") {
+        Ok(dir) if !dir.is_empty() => dir,
+        _ => {
+            return BufferManager::new(metrics_buffer, owner_buffer)
+                .with_relabeler(init_relabeler())
+                .with_label_mapping(init_label_mapping())
+                .with_filters(init_filters());
+        }
+    };
+    let wal_segment_size = std::env::var("WAL_SEGMENT_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SEGMENT_SIZE);
 
-    let metrics_buffer = MetricsBuffer::new(metrics_interval * 1000, metrics_max_delay);
-    let owner_buffer = OwnerBuffer::new(owner_flush_interval, SystemTime::now());
+    let wal = Wal::open(&wal_dir, wal_segment_size).expect("Failed to open write-ahead log");
+    let buffer_manager = BufferManager::with_wal(metrics_buffer, owner_buffer, wal)
+        .with_relabeler(init_relabeler())
+        .with_label_mapping(init_label_mapping())
+        .with_filters(init_filters());
+    buffer_manager
+        .replay_wal()
+        .expect("Failed to replay write-ahead log");
+    buffer_manager
+}
 
-    BufferManager::new(metrics_buffer, owner_buffer)
+/// With `MIGRATE_DRY_RUN=true`, the pending migrations are printed instead of
+/// applied, and microinsight exits.
+fn migrate_dry_run() -> bool {
+    std::env::var("MIGRATE_DRY_RUN")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(false)
+}
+
+fn init_flush_period() -> Duration {
+    std::env::var("FLUSH_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_FLUSH_PERIOD)
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
+// The database clients are set up before the runtime starts, as the
+// PostgreSQL client runs its own runtime and must not be nested in another.
+fn main() -> std::io::Result<()> {
     init_logging();
-    let database = init_db();
+    let (sink, maintenance) = init_db();
+    let sink = init_export(sink);
+    if migrate_dry_run() {
+        for migration in sink.pending_migrations() {
+            println!("{}", migration);
+        }
+        return Ok(());
+    }
+    sink.create_tables();
     let buffer_manager = init_buffers();
+    let flush_period = init_flush_period();
 
-    let server = Server::new(buffer_manager, database);
-    server.run().await?.await?;
-    Ok(())
+    tokio::runtime::Runtime::new()?.block_on(async {
+        if let Some(maintenance) = maintenance {
+            tokio::spawn(maintenance.run());
+        }
+        let server = Server::new(buffer_manager, sink, flush_period);
+        server.run().await?.await
+    })
 }
@@ -1,29 +1,268 @@
+use crate::clock::{Clock, SystemClock};
+use crate::gauge::{Aggregations, Gauge};
 use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts};
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Samples for buckets that had already been flushed, by environment.
+pub static LATE_SAMPLES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "microinsight_late_samples_total",
+            "Samples for buckets that had already been flushed",
+        ),
+        &["environment"],
+    )
+    .unwrap()
+});
+
+/// Late samples that were merged into the stored rows, by environment.
+pub static MERGED_SAMPLES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "microinsight_merged_samples_total",
+            "Late samples merged into the stored rows",
+        ),
+        &["environment"],
+    )
+    .unwrap()
+});
+
+/// Late samples that were discarded, by environment.
+pub static DROPPED_SAMPLES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "microinsight_dropped_samples_total",
+            "Late samples that were discarded",
+        ),
+        &["environment"],
+    )
+    .unwrap()
+});
+
+/// What happens to samples for buckets that have already been flushed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LatePolicy {
+    Drop,
+    /// The samples are collected in a new bucket, which the sinks merge into
+    /// the stored row.
+    #[default]
+    Merge,
+}
+
+impl FromStr for LatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop" => Ok(LatePolicy::Drop),
+            "merge" => Ok(LatePolicy::Merge),
+            _ => Err(format!("Unknown late data policy {:?}", s)),
+        }
+    }
+}
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug)]
 pub struct Key {
     pub timestamp: u64,
     pub environment: String,
+    pub namespace: String,
     pub pod: String,
     pub container: String,
 }
 
+/// The buckets of one container, i.e., a `Key` without the timestamp.
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+struct Series {
+    environment: String,
+    namespace: String,
+    pod: String,
+    container: String,
+}
+
+impl Series {
+    fn of(key: &Key) -> Self {
+        Series {
+            environment: key.environment.clone(),
+            namespace: key.namespace.clone(),
+            pod: key.pod.clone(),
+            container: key.container.clone(),
+        }
+    }
+
+    fn key(&self, timestamp: u64) -> Key {
+        Key {
+            timestamp,
+            environment: self.environment.clone(),
+            namespace: self.namespace.clone(),
+            pod: self.pod.clone(),
+            container: self.container.clone(),
+        }
+    }
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct Metrics {
     pub cpu_usage_total: Option<f64>,
+    /// When the CPU counter was created, in seconds since the epoch, as
+    /// reported by its `_created` series.
+    pub cpu_created: Option<f64>,
     pub cpu_usage: Option<f64>,
+    /// Number of times the CPU counter was reset since the previous bucket,
+    /// e.g., because the container restarted.
+    pub cpu_restarts: Option<u32>,
     pub cpu_limit: Option<f64>,
+    /// The memory sample that arrived last.
     pub memory_usage: Option<f64>,
+    /// All memory samples of the bucket.
+    pub memory_usage_stats: Gauge,
     pub memory_limit: Option<f64>,
+    pub cpu_request: Option<f64>,
+    pub memory_request: Option<f64>,
+    /// Whether the bucket had already been flushed when its samples arrived,
+    /// so that they are to be merged into the stored row.
+    pub late: bool,
+}
+
+/// The limits and requests of a series as of one bucket, which the buckets
+/// filled in after it inherit, so that their usage is not left out for lack
+/// of them.
+#[derive(Clone, Copy, Debug, Default)]
+struct Resources {
+    cpu_limit: Option<f64>,
+    memory_limit: Option<f64>,
+    cpu_request: Option<f64>,
+    memory_request: Option<f64>,
+}
+
+impl Resources {
+    /// Takes over the values that `metrics` has and fills in those it lacks.
+    fn inherit(&mut self, metrics: &mut Metrics) {
+        self.cpu_limit = metrics.cpu_limit.or(self.cpu_limit);
+        self.memory_limit = metrics.memory_limit.or(self.memory_limit);
+        self.cpu_request = metrics.cpu_request.or(self.cpu_request);
+        self.memory_request = metrics.memory_request.or(self.memory_request);
+        metrics.cpu_limit = self.cpu_limit;
+        metrics.memory_limit = self.memory_limit;
+        metrics.cpu_request = self.cpu_request;
+        metrics.memory_request = self.memory_request;
+    }
+}
+
+/// The CPU counter of a series as of one bucket.
+#[derive(Clone, Copy, Debug)]
+struct Counter {
+    timestamp: u64,
+    total: f64,
+    created: Option<f64>,
+    resources: Resources,
+}
+
+impl Counter {
+    fn of(timestamp: u64, metrics: &Metrics) -> Option<Self> {
+        Some(Counter {
+            timestamp,
+            total: metrics.cpu_usage_total?,
+            created: metrics.cpu_created,
+            resources: Resources {
+                cpu_limit: metrics.cpu_limit,
+                memory_limit: metrics.memory_limit,
+                cpu_request: metrics.cpu_request,
+                memory_request: metrics.memory_request,
+            },
+        })
+    }
+
+    /// The CPU used since `previous`, and whether the counter was reset in
+    /// between. Like Prometheus' `increase`, a counter that went down is
+    /// taken to have started again from zero, so its whole value was used
+    /// since the reset. A newer created timestamp reveals a reset even if the
+    /// counter has already grown past its previous value.
+    fn increase_since(&self, previous: &Counter) -> (f64, u32) {
+        let recreated = matches!(
+            (previous.created, self.created),
+            (Some(previous_created), Some(created)) if created > previous_created
+        );
+        if recreated || self.total < previous.total {
+            (self.total, 1)
+        } else {
+            (self.total - previous.total, 0)
+        }
+    }
 }
 
+/// The newest sample of an environment, by its timestamp.
+#[derive(Clone, Copy, Debug)]
+struct Watermark {
+    timestamp: u64,
+    /// Wall-clock time at which the timestamp last moved forward.
+    advanced_at: SystemTime,
+    /// All buckets before it have been flushed.
+    flushed_before: u64,
+}
+
+/// Start of the oldest bucket that is still kept in memory for late data, per
+/// environment.
+#[derive(Clone, Debug)]
+pub struct Thresholds {
+    environments: HashMap<String, u64>,
+    others: u64,
+}
+
+impl Thresholds {
+    /// The same threshold for every environment.
+    pub fn all(threshold: u64) -> Self {
+        Thresholds {
+            environments: HashMap::new(),
+            others: threshold,
+        }
+    }
+
+    pub fn of(&self, environment: &str) -> u64 {
+        self.environments
+            .get(environment)
+            .copied()
+            .unwrap_or(self.others)
+    }
+
+    /// Every sample before it has been flushed, whatever its environment.
+    pub fn min(&self) -> u64 {
+        self.environments
+            .values()
+            .copied()
+            .fold(self.others, u64::min)
+    }
+}
+
+/// Buckets of samples per container and interval.
+///
+/// `insert` and `flush_before` must not run at the same time: a sample that
+/// is inserted while its bucket is taken can end up in a new bucket that is
+/// not marked as late, whose row then replaces the stored one.
+/// `BufferManager` serializes them with its ingest lock.
 pub struct MetricsBuffer {
     /// Bucket width in milliseconds, matching the unit of the sample timestamps
     /// in the Prometheus remote write protocol.
     interval: u64,
     max_delay: usize,
     buffer: DashMap<Key, Arc<Mutex<Metrics>>>,
+    aggregations: Aggregations,
+    /// The newest flushed CPU counter of every series, from which the usage
+    /// of its next bucket is calculated.
+    counters: DashMap<Series, Counter>,
+    watermarks: DashMap<String, Watermark>,
+    /// Wall-clock time after which an environment without newer samples has
+    /// all of its buckets flushed.
+    idle_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    late_policy: LatePolicy,
+    /// Per environment, start of the oldest bucket that a previous run may
+    /// not have flushed.
+    flushed_before: HashMap<String, u64>,
 }
 
 impl MetricsBuffer {
@@ -32,6 +271,51 @@ impl MetricsBuffer {
             interval,
             max_delay,
             buffer: DashMap::new(),
+            aggregations: Aggregations::default(),
+            counters: DashMap::new(),
+            watermarks: DashMap::new(),
+            idle_timeout: Duration::from_millis(interval * max_delay as u64),
+            clock: Arc::new(SystemClock),
+            late_policy: LatePolicy::default(),
+            flushed_before: HashMap::new(),
+        }
+    }
+
+    /// Keeps only `aggregations` of the memory samples of each bucket.
+    pub fn with_aggregations(self, aggregations: Aggregations) -> Self {
+        Self {
+            aggregations,
+            ..self
+        }
+    }
+
+    /// Flushes the buckets of an environment that did not send newer samples
+    /// for `idle_timeout`, instead of the default of `max_delay` intervals.
+    pub fn with_idle_timeout(self, idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    pub fn with_late_policy(self, late_policy: LatePolicy) -> Self {
+        Self {
+            late_policy,
+            ..self
+        }
+    }
+
+    /// Takes the buckets of each environment before `flushed_before` as
+    /// flushed, e.g., by a previous run, so that samples for them are late
+    /// rather than replacing the stored rows.
+    pub fn with_flushed_before(self, flushed_before: HashMap<String, u64>) -> Self {
+        Self {
+            flushed_before,
+            ..self
         }
     }
 
@@ -39,82 +323,260 @@ impl MetricsBuffer {
         (timestamp / self.interval) * self.interval
     }
 
+    /// Longest time between two CPU counters of a series across which the
+    /// usage is spread over the buckets in between.
+    fn max_gap(&self) -> u64 {
+        self.interval * self.max_delay as u64
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn insert(
         &self,
         name: &str,
         environment: &str,
+        namespace: &str,
         pod: &str,
         container: &str,
         timestamp: u64,
         value: f64,
     ) {
+        self.advance_watermark(environment, timestamp);
         let truncated_timestamp = self.truncate_timestamp(timestamp);
         let key = Key {
             timestamp: truncated_timestamp,
             environment: environment.to_string(),
+            namespace: namespace.to_string(),
             pod: pod.to_string(),
             container: container.to_string(),
         };
 
-        // Prometheus remote write protocol specifies that metrics have to arrive in timestamp order
-        // for their database to work -- fingers crossed!
-        let mut previous_cpu_usage_total = Option::None;
-        if name == "cpu_usage_total" {
-            let previous_key = Key {
-                timestamp: truncated_timestamp.saturating_sub(self.interval),
-                environment: key.environment.clone(),
-                pod: key.pod.clone(),
-                container: key.container.clone(),
-            };
-
-            if let Some(previous_entry) = self.buffer.get(&previous_key) {
-                let previous_metrics = previous_entry.lock().unwrap();
-                previous_cpu_usage_total = previous_metrics.cpu_usage_total;
-            }
+        // Buckets that were held back for the CPU usage are still in the
+        // buffer, so only missing ones have been flushed.
+        let flushed = self
+            .watermarks
+            .get(environment)
+            .is_some_and(|watermark| truncated_timestamp < watermark.flushed_before)
+            && !self.buffer.contains_key(&key);
+        // The stored row already has the usage from the counters around it,
+        // so a late counter cannot change it.
+        let counter = matches!(name, "cpu_usage_total" | "cpu_created");
+        if flushed && (counter || self.late_policy == LatePolicy::Drop) {
+            LATE_SAMPLES.with_label_values(&[environment]).inc();
+            DROPPED_SAMPLES.with_label_values(&[environment]).inc();
+            return;
         }
 
-        let entry = self
-            .buffer
-            .entry(key.clone())
-            .or_insert_with(|| Arc::new(Mutex::new(Metrics::default())));
+        let entry = self.buffer.entry(key).or_insert_with(|| {
+            Arc::new(Mutex::new(Metrics {
+                memory_usage_stats: Gauge::new(self.aggregations),
+                late: flushed,
+                ..Default::default()
+            }))
+        });
 
+        // The CPU usage is calculated when the bucket is flushed, once late
+        // samples of it and of its neighbors had a chance to arrive.
         let mut metrics = entry.lock().unwrap();
-        match name {
-            "cpu_usage_total" => {
-                metrics.cpu_usage_total = Some(value);
-                if let Some(previous_value) = previous_cpu_usage_total {
-                    if value >= previous_value {
-                        metrics.cpu_usage = Some(value - previous_value);
-                    }
-                }
+        if metrics.late {
+            LATE_SAMPLES.with_label_values(&[environment]).inc();
+            if counter {
+                DROPPED_SAMPLES.with_label_values(&[environment]).inc();
+                return;
             }
+            MERGED_SAMPLES.with_label_values(&[environment]).inc();
+        }
+        match name {
+            "cpu_usage_total" => metrics.cpu_usage_total = Some(value),
+            "cpu_created" => metrics.cpu_created = Some(value),
             "cpu_limit" => metrics.cpu_limit = Some(value),
-            "memory_usage" => metrics.memory_usage = Some(value),
+            "memory_usage" => {
+                metrics.memory_usage = Some(value);
+                metrics.memory_usage_stats.observe(value);
+            }
             "memory_limit" => metrics.memory_limit = Some(value),
+            "cpu_request" => metrics.cpu_request = Some(value),
+            "memory_request" => metrics.memory_request = Some(value),
             _ => {}
         }
     }
 
+    fn advance_watermark(&self, environment: &str, timestamp: u64) {
+        if let Some(mut watermark) = self.watermarks.get_mut(environment) {
+            if timestamp > watermark.timestamp {
+                watermark.timestamp = timestamp;
+                watermark.advanced_at = self.clock.now();
+            }
+            return;
+        }
+        self.watermarks.insert(
+            environment.to_string(),
+            Watermark {
+                timestamp,
+                advanced_at: self.clock.now(),
+                flushed_before: self.flushed_before.get(environment).copied().unwrap_or(0),
+            },
+        );
+    }
+
+    /// The buckets of an environment are kept for `max_delay` intervals
+    /// before its newest sample, so that neither backfilled data nor clock
+    /// skew between Prometheus and microinsight flushes them early. Once an
+    /// environment has been idle for `idle_timeout`, all of its buckets are
+    /// due.
+    pub fn thresholds(&self) -> Thresholds {
+        let now = self.clock.now();
+        let environments = self
+            .watermarks
+            .iter()
+            .map(|entry| {
+                let watermark = entry.value();
+                let idle = now
+                    .duration_since(watermark.advanced_at)
+                    .is_ok_and(|idle| idle >= self.idle_timeout);
+                let threshold = if idle {
+                    u64::MAX
+                } else {
+                    self.truncate_timestamp(watermark.timestamp)
+                        .saturating_sub(self.interval * self.max_delay as u64)
+                };
+                (entry.key().clone(), threshold)
+            })
+            .collect();
+        // Environments without samples have no buckets to keep.
+        Thresholds {
+            environments,
+            others: u64::MAX,
+        }
+    }
+
+    /// Start of the oldest bucket in memory, if any. After a flush, it can be
+    /// older than the threshold for buckets that were held back.
+    pub fn oldest(&self) -> Option<u64> {
+        self.buffer.iter().map(|entry| entry.key().timestamp).min()
+    }
+
+    /// Per environment, every bucket before it has been flushed, without
+    /// the buckets that were held back.
+    pub fn flushed_before(&self) -> HashMap<String, u64> {
+        let mut flushed_before: HashMap<String, u64> = self
+            .watermarks
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().flushed_before))
+            .collect();
+        for entry in self.buffer.iter() {
+            if let Some(before) = flushed_before.get_mut(&entry.key().environment) {
+                *before = (*before).min(entry.key().timestamp);
+            }
+        }
+        flushed_before
+    }
+
     pub fn flush(&self) -> Vec<(Key, Metrics)> {
-        let mut flushed = Vec::new();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
-        let threshold = self
-            .truncate_timestamp(now)
-            .saturating_sub(self.interval * self.max_delay as u64);
+        self.flush_before(&self.thresholds())
+    }
 
+    /// Takes all buckets that start before the threshold of their
+    /// environment, with the CPU usage
+    /// calculated from the sorted CPU counters of each series. If buckets
+    /// between two counters lack one, e.g., after a missed scrape, the usage
+    /// is spread evenly over them, as long as the counters are at most
+    /// `max_delay` intervals apart. Buckets that still wait for the next
+    /// counter of their series are held back until it arrives or that time
+    /// has passed.
+    pub fn flush_before(&self, thresholds: &Thresholds) -> Vec<(Key, Metrics)> {
+        // The watermarks move first, so that samples for the buckets taken
+        // below count as late.
+        for mut watermark in self.watermarks.iter_mut() {
+            // Idle environments have all of their buckets flushed, up to the
+            // one of their newest sample.
+            let newest = self.truncate_timestamp(watermark.timestamp) + self.interval;
+            let threshold = thresholds.of(watermark.key()).min(newest);
+            watermark.flushed_before = watermark.flushed_before.max(threshold);
+        }
+
+        let mut due: HashMap<Series, Vec<(u64, Metrics)>> = HashMap::new();
         self.buffer.retain(|key, value| {
-            if key.timestamp < threshold {
+            if key.timestamp < thresholds.of(&key.environment) {
                 let metrics = value.lock().unwrap().clone();
-                flushed.push((key.clone(), metrics));
+                due.entry(Series::of(key))
+                    .or_default()
+                    .push((key.timestamp, metrics));
                 false
             } else {
                 true
             }
         });
 
+        let mut flushed = Vec::new();
+        for (series, mut buckets) in due {
+            buckets.sort_by_key(|(timestamp, _)| *timestamp);
+            let threshold = thresholds.of(&series.environment);
+            let mut previous = self.counters.get(&series).map(|counter| *counter);
+            let mut gap: HashMap<u64, Metrics> = HashMap::new();
+
+            for (timestamp, mut metrics) in buckets {
+                // Late buckets have no counters, and the stored row keeps its
+                // usage.
+                if metrics.late {
+                    flushed.push((series.key(timestamp), metrics));
+                    continue;
+                }
+                let counter = Counter::of(timestamp, &metrics);
+                match (counter, previous) {
+                    (Some(counter), Some(last)) if timestamp > last.timestamp => {
+                        if timestamp - last.timestamp <= self.max_gap() {
+                            let (increase, restarts) = counter.increase_since(&last);
+                            let steps = (timestamp - last.timestamp) / self.interval;
+                            let share = increase / steps.max(1) as f64;
+                            let mut resources = last.resources;
+                            for step in 1..steps {
+                                let between = last.timestamp + step * self.interval;
+                                let mut filled = gap.remove(&between).unwrap_or_else(|| Metrics {
+                                    memory_usage_stats: Gauge::new(self.aggregations),
+                                    ..Default::default()
+                                });
+                                resources.inherit(&mut filled);
+                                filled.cpu_usage = Some(share);
+                                filled.cpu_restarts = Some(0);
+                                flushed.push((series.key(between), filled));
+                            }
+                            metrics.cpu_usage = Some(share);
+                            metrics.cpu_restarts = Some(restarts);
+                        }
+                        flushed.extend(gap.drain().map(|(t, m)| (series.key(t), m)));
+                        previous = Some(counter);
+                    }
+                    (Some(counter), None) => {
+                        flushed.extend(gap.drain().map(|(t, m)| (series.key(t), m)));
+                        previous = Some(counter);
+                    }
+                    (None, Some(last)) if timestamp > last.timestamp => {
+                        gap.insert(timestamp, metrics);
+                        continue;
+                    }
+                    // Anything else came too late to have a usage.
+                    _ => {}
+                }
+                flushed.push((series.key(timestamp), metrics));
+            }
+
+            let waiting = previous.is_some_and(|last| last.timestamp + self.max_gap() >= threshold);
+            for (timestamp, metrics) in gap {
+                if waiting {
+                    self.buffer
+                        .insert(series.key(timestamp), Arc::new(Mutex::new(metrics)));
+                } else {
+                    flushed.push((series.key(timestamp), metrics));
+                }
+            }
+            if let Some(last) = previous {
+                self.counters.insert(series, last);
+            }
+        }
+        self.counters.retain(|series, last| {
+            last.timestamp + self.max_gap() >= thresholds.of(&series.environment)
+        });
+
         flushed
     }
 }
@@ -122,11 +584,13 @@ impl MetricsBuffer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::tests::ManualClock;
 
     fn create_key(timestamp: u64) -> Key {
         Key {
             timestamp,
             environment: "env1".to_string(),
+            namespace: "ns1".to_string(),
             pod: "pod1".to_string(),
             container: "container1".to_string(),
         }
@@ -138,7 +602,15 @@ mod tests {
         let timestamp = 120;
         let value = 100.0;
 
-        buffer.insert("cpu_limit", "env1", "pod1", "container1", timestamp, value);
+        buffer.insert(
+            "cpu_limit",
+            "env1",
+            "ns1",
+            "pod1",
+            "container1",
+            timestamp,
+            value,
+        );
 
         let key = create_key(buffer.truncate_timestamp(timestamp));
         let entry = buffer.buffer.get(&key).unwrap();
@@ -148,26 +620,51 @@ mod tests {
     }
 
     #[test]
-    fn test_insert_cpu_usage_no_previous() {
+    fn test_same_pod_in_different_namespaces() {
         let buffer = MetricsBuffer::new(60, 5);
-        let timestamp = 120;
-        let value = 100.0;
+        buffer.insert("cpu_limit", "env1", "ns1", "pod1", "container1", 120, 1.0);
+        buffer.insert("cpu_limit", "env1", "ns2", "pod1", "container1", 120, 2.0);
+
+        let key = create_key(120);
+        assert_eq!(buffer.buffer.len(), 2);
+        assert_eq!(
+            buffer.buffer.get(&key).unwrap().lock().unwrap().cpu_limit,
+            Some(1.0)
+        );
+    }
 
+    fn insert_cpu(buffer: &MetricsBuffer, timestamp: u64, value: f64) {
         buffer.insert(
             "cpu_usage_total",
             "env1",
+            "ns1",
             "pod1",
             "container1",
             timestamp,
             value,
         );
+    }
 
-        let key = create_key(buffer.truncate_timestamp(timestamp));
-        let entry = buffer.buffer.get(&key).unwrap();
-        let metrics = entry.lock().unwrap();
+    /// The flushed buckets before `threshold`, by their start.
+    fn flush_before(buffer: &MetricsBuffer, threshold: u64) -> HashMap<u64, Metrics> {
+        buffer
+            .flush_before(&Thresholds::all(threshold))
+            .into_iter()
+            .map(|(key, metrics)| (key.timestamp, metrics))
+            .collect()
+    }
 
-        assert_eq!(metrics.cpu_usage, None);
-        assert_eq!(metrics.cpu_usage_total, Some(value));
+    #[test]
+    fn test_insert_cpu_usage_no_previous() {
+        let buffer = MetricsBuffer::new(60, 5);
+        let timestamp = 120;
+        let value = 100.0;
+
+        insert_cpu(&buffer, timestamp, value);
+
+        let flushed = flush_before(&buffer, u64::MAX);
+        assert_eq!(flushed[&timestamp].cpu_usage, None);
+        assert_eq!(flushed[&timestamp].cpu_usage_total, Some(value));
     }
 
     #[test]
@@ -178,35 +675,19 @@ mod tests {
         let second_timestamp = 180;
         let second_value = 150.0;
 
-        buffer.insert(
-            "cpu_usage_total",
-            "env1",
-            "pod1",
-            "container1",
-            first_timestamp,
-            first_value,
-        );
-        buffer.insert(
-            "cpu_usage_total",
-            "env1",
-            "pod1",
-            "container1",
-            second_timestamp,
-            second_value,
-        );
+        insert_cpu(&buffer, first_timestamp, first_value);
+        insert_cpu(&buffer, second_timestamp, second_value);
 
-        let first_key = create_key(buffer.truncate_timestamp(first_timestamp));
-        let second_key = create_key(buffer.truncate_timestamp(second_timestamp));
+        let flushed = flush_before(&buffer, u64::MAX);
 
-        let first_entry = buffer.buffer.get(&first_key).unwrap();
-        let first_metrics = first_entry.lock().unwrap();
+        let first_metrics = &flushed[&first_timestamp];
         assert_eq!(first_metrics.cpu_usage, None);
         assert_eq!(first_metrics.cpu_usage_total, Some(first_value));
 
-        let second_entry = buffer.buffer.get(&second_key).unwrap();
-        let second_metrics = second_entry.lock().unwrap();
+        let second_metrics = &flushed[&second_timestamp];
         assert_eq!(second_metrics.cpu_usage, Some(second_value - first_value));
         assert_eq!(second_metrics.cpu_usage_total, Some(second_value));
+        assert_eq!(second_metrics.cpu_restarts, Some(0));
     }
 
     #[test]
@@ -220,31 +701,22 @@ mod tests {
         buffer.insert(
             "memory_usage",
             "env1",
+            "ns1",
             "pod1",
             "container1",
             first_timestamp,
             first_value,
         );
-        buffer.insert(
-            "cpu_usage_total",
-            "env1",
-            "pod1",
-            "container1",
-            second_timestamp,
-            second_value,
-        );
+        insert_cpu(&buffer, second_timestamp, second_value);
 
-        let first_key = create_key(buffer.truncate_timestamp(first_timestamp));
-        let second_key = create_key(buffer.truncate_timestamp(second_timestamp));
+        let flushed = flush_before(&buffer, u64::MAX);
 
-        let first_entry = buffer.buffer.get(&first_key).unwrap();
-        let first_metrics = first_entry.lock().unwrap();
+        let first_metrics = &flushed[&first_timestamp];
         assert_eq!(first_metrics.memory_usage, Some(first_value));
         assert_eq!(first_metrics.cpu_usage, None);
         assert_eq!(first_metrics.cpu_usage_total, None);
 
-        let second_entry = buffer.buffer.get(&second_key).unwrap();
-        let second_metrics = second_entry.lock().unwrap();
+        let second_metrics = &flushed[&second_timestamp];
         assert_eq!(second_metrics.cpu_usage, None);
         assert_eq!(second_metrics.cpu_usage_total, Some(second_value));
     }
@@ -257,35 +729,182 @@ mod tests {
         let second_timestamp = 180;
         let second_value = 50.0;
 
+        insert_cpu(&buffer, first_timestamp, first_value);
+        insert_cpu(&buffer, second_timestamp, second_value);
+
+        let flushed = flush_before(&buffer, u64::MAX);
+
+        let first_metrics = &flushed[&first_timestamp];
+        assert_eq!(first_metrics.cpu_usage, None);
+        assert_eq!(first_metrics.cpu_usage_total, Some(first_value));
+
+        let second_metrics = &flushed[&second_timestamp];
+        // The counter was reset, so all of it was used since.
+        assert_eq!(second_metrics.cpu_usage, Some(second_value));
+        assert_eq!(second_metrics.cpu_usage_total, Some(second_value));
+        assert_eq!(second_metrics.cpu_restarts, Some(1));
+    }
+
+    #[test]
+    fn test_insert_cpu_usage_with_created_timestamp() {
+        let buffer = MetricsBuffer::new(60, 5);
+        let first_timestamp = 120;
+        let first_value = 100.0;
+        let second_timestamp = 180;
+        let second_value = 150.0;
+
         buffer.insert(
-            "cpu_usage_total",
+            "cpu_created",
             "env1",
+            "ns1",
             "pod1",
             "container1",
             first_timestamp,
-            first_value,
+            10.0,
         );
+        insert_cpu(&buffer, first_timestamp, first_value);
+        // The counter was created anew after the first bucket, so it was
+        // reset even though it grew.
+        insert_cpu(&buffer, second_timestamp, second_value);
         buffer.insert(
-            "cpu_usage_total",
+            "cpu_created",
             "env1",
+            "ns1",
             "pod1",
             "container1",
             second_timestamp,
-            second_value,
+            150.0,
         );
 
-        let first_key = create_key(buffer.truncate_timestamp(first_timestamp));
-        let second_key = create_key(buffer.truncate_timestamp(second_timestamp));
-
-        let first_entry = buffer.buffer.get(&first_key).unwrap();
-        let first_metrics = first_entry.lock().unwrap();
-        assert_eq!(first_metrics.cpu_usage, None);
-        assert_eq!(first_metrics.cpu_usage_total, Some(first_value));
+        let flushed = flush_before(&buffer, u64::MAX);
 
-        let second_entry = buffer.buffer.get(&second_key).unwrap();
-        let second_metrics = second_entry.lock().unwrap();
-        assert_eq!(second_metrics.cpu_usage, None);
+        let second_metrics = &flushed[&second_timestamp];
+        assert_eq!(second_metrics.cpu_usage, Some(second_value));
         assert_eq!(second_metrics.cpu_usage_total, Some(second_value));
+        assert_eq!(second_metrics.cpu_restarts, Some(1));
+    }
+
+    #[test]
+    fn test_cpu_usage_spans_flushes() {
+        let buffer = MetricsBuffer::new(60, 5);
+        insert_cpu(&buffer, 120, 100.0);
+        insert_cpu(&buffer, 180, 150.0);
+
+        let flushed = flush_before(&buffer, 180);
+        assert_eq!(flushed[&120].cpu_usage, None);
+
+        let flushed = flush_before(&buffer, 240);
+        assert_eq!(flushed[&180].cpu_usage, Some(50.0));
+    }
+
+    #[test]
+    fn test_cpu_usage_is_spread_over_missed_scrapes() {
+        let buffer = MetricsBuffer::new(60, 5);
+        insert_cpu(&buffer, 120, 100.0);
+        buffer.insert("cpu_limit", "env1", "ns1", "pod1", "container1", 180, 1.0);
+        insert_cpu(&buffer, 300, 190.0);
+
+        // The bucket at 180 waits for the next counter.
+        let flushed = flush_before(&buffer, 240);
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(buffer.oldest(), Some(180));
+
+        let flushed = flush_before(&buffer, 360);
+        assert_eq!(flushed.len(), 3);
+        assert_eq!(flushed[&180].cpu_usage, Some(30.0));
+        assert_eq!(flushed[&180].cpu_limit, Some(1.0));
+        assert_eq!(flushed[&240].cpu_usage, Some(30.0));
+        assert_eq!(flushed[&240].cpu_limit, Some(1.0));
+        assert_eq!(flushed[&300].cpu_usage, Some(30.0));
+        assert_eq!(flushed[&300].cpu_restarts, Some(0));
+    }
+
+    #[test]
+    fn test_cpu_usage_is_not_spread_over_long_gaps() {
+        let buffer = MetricsBuffer::new(60, 2);
+        insert_cpu(&buffer, 120, 100.0);
+        buffer.insert("cpu_limit", "env1", "ns1", "pod1", "container1", 180, 1.0);
+        flush_before(&buffer, 180);
+
+        // Once the next counter can no longer be close enough, the bucket
+        // is flushed without a usage.
+        let flushed = flush_before(&buffer, 300);
+        assert_eq!(flushed[&180].cpu_usage, None);
+
+        insert_cpu(&buffer, 360, 190.0);
+        let flushed = flush_before(&buffer, 420);
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[&360].cpu_usage, None);
+    }
+
+    #[test]
+    fn test_late_cpu_sample_corrects_neighbor() {
+        let buffer = MetricsBuffer::new(60, 5);
+        insert_cpu(&buffer, 120, 100.0);
+        insert_cpu(&buffer, 240, 160.0);
+        // Arrives after the newer bucket.
+        insert_cpu(&buffer, 180, 150.0);
+
+        let flushed = flush_before(&buffer, 300);
+
+        assert_eq!(flushed[&180].cpu_usage, Some(50.0));
+        assert_eq!(flushed[&240].cpu_usage, Some(10.0));
+    }
+
+    fn insert_sample(buffer: &MetricsBuffer, name: &str, env: &str, timestamp: u64, value: f64) {
+        buffer.insert(name, env, "ns1", "pod1", "container1", timestamp, value);
+    }
+
+    #[test]
+    fn test_late_samples_are_merged() {
+        let buffer = MetricsBuffer::new(60, 5);
+        insert_sample(&buffer, "cpu_usage_total", "env-merge", 120, 100.0);
+        insert_sample(&buffer, "cpu_usage_total", "env-merge", 180, 150.0);
+        insert_sample(&buffer, "memory_usage", "env-merge", 480, 1.0);
+        let flushed = buffer.flush();
+        assert_eq!(flushed.len(), 1);
+        assert!(!flushed[0].1.late);
+
+        insert_sample(&buffer, "cpu_usage_total", "env-merge", 60, 90.0);
+        insert_sample(&buffer, "memory_usage", "env-merge", 120, 2.0);
+        insert_sample(&buffer, "cpu_usage_total", "env-merge", 120, 110.0);
+        insert_sample(&buffer, "memory_usage", "env-merge", 240, 2.0);
+
+        let flushed = flush_before(&buffer, u64::MAX);
+        assert!(!flushed.contains_key(&60));
+        assert!(flushed[&120].late);
+        assert_eq!(flushed[&120].memory_usage_stats.count(), Some(1));
+        // The stored usage is based on the counters that were there in time.
+        assert_eq!(flushed[&120].cpu_usage, None);
+        assert!(!flushed[&240].late);
+        assert_eq!(flushed[&180].cpu_usage, Some(50.0));
+        // Late counters are of no use, so they count as dropped.
+        assert_eq!(LATE_SAMPLES.with_label_values(&["env-merge"]).get(), 3);
+        assert_eq!(MERGED_SAMPLES.with_label_values(&["env-merge"]).get(), 1);
+        assert_eq!(DROPPED_SAMPLES.with_label_values(&["env-merge"]).get(), 2);
+    }
+
+    #[test]
+    fn test_late_samples_are_dropped() {
+        let buffer = MetricsBuffer::new(60, 5).with_late_policy(LatePolicy::Drop);
+        insert_sample(&buffer, "memory_usage", "env-drop", 120, 1.0);
+        insert_sample(&buffer, "memory_usage", "env-drop", 480, 1.0);
+        assert_eq!(buffer.flush().len(), 1);
+
+        insert_sample(&buffer, "memory_usage", "env-drop", 120, 2.0);
+        insert_sample(&buffer, "memory_usage", "env-drop", 180, 2.0);
+
+        assert_eq!(buffer.oldest(), Some(180));
+        assert_eq!(LATE_SAMPLES.with_label_values(&["env-drop"]).get(), 1);
+        assert_eq!(DROPPED_SAMPLES.with_label_values(&["env-drop"]).get(), 1);
+        assert_eq!(MERGED_SAMPLES.with_label_values(&["env-drop"]).get(), 0);
+    }
+
+    #[test]
+    fn test_parse_late_policy() {
+        assert_eq!("drop".parse(), Ok(LatePolicy::Drop));
+        assert_eq!("Merge".parse(), Ok(LatePolicy::Merge));
+        assert!("keep".parse::<LatePolicy>().is_err());
     }
 
     #[test]
@@ -297,6 +916,7 @@ mod tests {
         buffer.insert(
             "memory_usage",
             "env1",
+            "ns1",
             "pod1",
             "container1",
             timestamp,
@@ -310,6 +930,31 @@ mod tests {
         assert_eq!(metrics.memory_usage, Some(value));
     }
 
+    #[test]
+    fn test_memory_samples_are_aggregated() {
+        let buffer = MetricsBuffer::new(60, 5).with_aggregations("avg,max".parse().unwrap());
+        for (timestamp, value) in [(120, 200.0), (140, 400.0), (160, 300.0)] {
+            buffer.insert(
+                "memory_usage",
+                "env1",
+                "ns1",
+                "pod1",
+                "container1",
+                timestamp,
+                value,
+            );
+        }
+
+        let flushed = flush_before(&buffer, u64::MAX);
+
+        let metrics = &flushed[&120];
+        assert_eq!(metrics.memory_usage, Some(300.0));
+        assert_eq!(metrics.memory_usage_stats.avg(), Some(300.0));
+        assert_eq!(metrics.memory_usage_stats.max(), Some(400.0));
+        assert_eq!(metrics.memory_usage_stats.min(), None);
+        assert_eq!(metrics.memory_usage_stats.p95(), None);
+    }
+
     /// Prometheus remote write reports sample timestamps in milliseconds, and
     /// `main.rs` scales `INTERVAL` by 1000 accordingly, so every value handled by
     /// the buffer is in milliseconds. Keep the test in the same unit.
@@ -326,11 +971,12 @@ mod tests {
         let buffer = MetricsBuffer::new(interval, 5);
         let now = now_millis();
         let old_timestamp = now - 6 * interval; // Older than max_delay
-        let recent_timestamp = now - 2 * interval; // Within max_delay
+        let recent_timestamp = now; // The newest sample
 
         buffer.insert(
             "cpu_usage_total",
             "env1",
+            "ns1",
             "pod1",
             "container1",
             old_timestamp,
@@ -339,6 +985,7 @@ mod tests {
         buffer.insert(
             "cpu_usage_total",
             "env1",
+            "ns1",
             "pod1",
             "container1",
             recent_timestamp,
@@ -356,6 +1003,7 @@ mod tests {
         assert!(buffer.buffer.contains_key(&Key {
             timestamp: buffer.truncate_timestamp(recent_timestamp),
             environment: "env1".to_string(),
+            namespace: "ns1".to_string(),
             pod: "pod1".to_string(),
             container: "container1".to_string(),
         }));
@@ -372,11 +1020,13 @@ mod tests {
         buffer.insert(
             "cpu_limit",
             "env1",
+            "ns1",
             "pod1",
             "container1",
             old_timestamp,
             1.0,
         );
+        buffer.insert("cpu_limit", "env1", "ns1", "pod2", "container1", now, 1.0);
 
         let flushed = buffer.flush();
 
@@ -385,7 +1035,7 @@ mod tests {
             1,
             "bucket older than max_delay was not flushed"
         );
-        assert_eq!(buffer.buffer.len(), 0);
+        assert_eq!(buffer.buffer.len(), 1);
     }
 
     #[test]
@@ -394,7 +1044,7 @@ mod tests {
         let buffer = MetricsBuffer::new(interval, 5);
         let now = now_millis();
 
-        buffer.insert("cpu_limit", "env1", "pod1", "container1", now, 1.0);
+        buffer.insert("cpu_limit", "env1", "ns1", "pod1", "container1", now, 1.0);
 
         assert!(buffer.flush().is_empty());
         assert_eq!(buffer.buffer.len(), 1);
@@ -403,26 +1053,89 @@ mod tests {
     #[test]
     fn test_flush_near_epoch_does_not_panic() {
         // Guards against underflow when the buffer holds timestamps close to the
-        // epoch, as the e2e test does.
-        let buffer = MetricsBuffer::new(60_000, 5);
-        buffer.insert("cpu_limit", "env1", "pod1", "container1", 0, 1.0);
+        // epoch.
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+        let buffer = MetricsBuffer::new(60_000, 5).with_clock(clock.clone());
+        buffer.insert("cpu_limit", "env1", "ns1", "pod1", "container1", 0, 1.0);
 
-        let flushed = buffer.flush();
+        assert!(buffer.flush().is_empty());
+
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(buffer.flush().len(), 1);
+    }
+
+    #[test]
+    fn test_environments_are_flushed_by_their_own_watermark() {
+        let interval = 60_000;
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+        let buffer = MetricsBuffer::new(interval, 5).with_clock(clock.clone());
+        // env1 is backfilled with data from long ago, env2 is live.
+        buffer.insert("cpu_limit", "env1", "ns1", "pod1", "container1", 0, 1.0);
+        buffer.insert(
+            "cpu_limit",
+            "env2",
+            "ns1",
+            "pod1",
+            "container1",
+            1000 * interval,
+            1.0,
+        );
+
+        assert!(buffer.flush().is_empty());
 
+        buffer.insert(
+            "cpu_limit",
+            "env1",
+            "ns1",
+            "pod1",
+            "container1",
+            6 * interval,
+            1.0,
+        );
+        let flushed = buffer.flush();
         assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].0.environment, "env1");
+        assert_eq!(flushed[0].0.timestamp, 0);
+    }
+
+    #[test]
+    fn test_idle_environment_is_flushed_after_timeout() {
+        let interval = 60_000;
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+        let buffer = MetricsBuffer::new(interval, 5)
+            .with_idle_timeout(Duration::from_secs(60))
+            .with_clock(clock.clone());
+        insert_cpu(&buffer, 100 * interval, 100.0);
+        insert_cpu(&buffer, 101 * interval, 150.0);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(buffer.flush().is_empty());
+
+        // Late samples do not keep an environment alive.
+        insert_cpu(&buffer, 99 * interval, 50.0);
+        clock.advance(Duration::from_secs(1));
+        let flushed: HashMap<u64, Metrics> = buffer
+            .flush()
+            .into_iter()
+            .map(|(key, metrics)| (key.timestamp, metrics))
+            .collect();
+        assert_eq!(flushed.len(), 3);
+        assert_eq!(flushed[&(101 * interval)].cpu_usage, Some(50.0));
+        assert!(buffer.buffer.is_empty());
     }
 
     #[test]
     fn test_insert_near_epoch_does_not_panic() {
-        // The previous-bucket lookup for the CPU delta must not underflow when the
-        // timestamp is younger than one interval.
+        // Calculating the CPU delta must not underflow when the timestamp is
+        // younger than one interval.
         let buffer = MetricsBuffer::new(60_000, 5);
 
-        buffer.insert("cpu_usage_total", "env1", "pod1", "container1", 0, 100.0);
+        insert_cpu(&buffer, 0, 100.0);
+        insert_cpu(&buffer, 60_000, 150.0);
 
-        let key = create_key(0);
-        let entry = buffer.buffer.get(&key).unwrap();
-        assert_eq!(entry.lock().unwrap().cpu_usage_total, Some(100.0));
+        let flushed = flush_before(&buffer, u64::MAX);
+        assert_eq!(flushed[&0].cpu_usage_total, Some(100.0));
+        assert_eq!(flushed[&60_000].cpu_usage, Some(50.0));
     }
 
     #[test]
@@ -0,0 +1,108 @@
+use log::info;
+use std::fmt;
+
+/// Table in which every backend records the migrations applied to it.
+pub const VERSION_TABLE: &str = "microinsight_schema_version";
+
+/// One step in the evolution of a schema. Steps are applied in the order of
+/// their versions, and each one exactly once per database.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+/// Prints the migration as an SQL script, for dry runs.
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "-- Version {}: {}", self.version, self.description)?;
+        for statement in self.statements {
+            writeln!(f, "{};", statement.trim())?;
+        }
+        Ok(())
+    }
+}
+
+/// The migrations that a database at version `current` has not seen yet.
+pub fn pending(migrations: &'static [Migration], current: u32) -> Vec<&'static Migration> {
+    debug_assert!(
+        migrations.windows(2).all(|w| w[0].version < w[1].version),
+        "migrations must be ordered by version"
+    );
+    migrations.iter().filter(|m| m.version > current).collect()
+}
+
+/// Applies the pending migrations one by one with `apply`, which is expected
+/// to run the statements and record the version. Stops at the first failure,
+/// so that the recorded version always matches the schema.
+pub(crate) fn migrate<E>(
+    migrations: &'static [Migration],
+    current: u32,
+    mut apply: impl FnMut(&Migration) -> Result<(), E>,
+) -> Result<u32, E> {
+    let mut version = current;
+    for migration in pending(migrations, current) {
+        info!(
+            "Migrating the schema to version {}: {}",
+            migration.version, migration.description
+        );
+        apply(migration)?;
+        version = migration.version;
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "Create t",
+            statements: &["CREATE TABLE t (a INT)"],
+        },
+        Migration {
+            version: 2,
+            description: "Add b",
+            statements: &["ALTER TABLE t ADD b INT", "UPDATE t SET b = a"],
+        },
+    ];
+
+    #[test]
+    fn test_pending_skips_applied_versions() {
+        let versions = |current| -> Vec<u32> {
+            pending(MIGRATIONS, current)
+                .iter()
+                .map(|m| m.version)
+                .collect()
+        };
+        assert_eq!(versions(0), [1, 2]);
+        assert_eq!(versions(1), [2]);
+        assert!(versions(2).is_empty());
+    }
+
+    #[test]
+    fn test_migrate_stops_at_first_failure() {
+        let mut applied = Vec::new();
+        let result = migrate(MIGRATIONS, 0, |m| {
+            if m.version == 2 {
+                return Err("syntax error");
+            }
+            applied.push(m.version);
+            Ok(())
+        });
+        assert_eq!(result, Err("syntax error"));
+        assert_eq!(applied, [1]);
+
+        assert_eq!(migrate(MIGRATIONS, 1, |_| Ok::<_, ()>(())), Ok(2));
+    }
+
+    #[test]
+    fn test_display_prints_script() {
+        assert_eq!(
+            MIGRATIONS[1].to_string(),
+            "-- Version 2: Add b\nALTER TABLE t ADD b INT;\nUPDATE t SET b = a;\n"
+        );
+    }
+}
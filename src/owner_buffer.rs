@@ -1,46 +1,267 @@
 use dashmap::DashMap;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 
+/// Longest chain of owner references that is followed from a pod, which
+/// also ends reference cycles.
+const MAX_OWNER_DEPTH: usize = 8;
+
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct OwnerKey {
     pub environment: String,
+    pub namespace: String,
     pub pod: String,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct OwnerValue {
     pub owner: String,
+    /// When the owner was first observed, in milliseconds since the epoch.
+    pub valid_from: u64,
+}
+
+/// Which owner a pod is attributed to if both the pod and its namespace have
+/// one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OwnerPrecedence {
+    /// The owner of the pod, falling back to the one of its namespace.
+    #[default]
+    Pod,
+    /// The owner of the namespace, falling back to the one of the pod.
+    Namespace,
+}
+
+impl FromStr for OwnerPrecedence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pod" => Ok(OwnerPrecedence::Pod),
+            "namespace" => Ok(OwnerPrecedence::Namespace),
+            _ => Err(format!("Unknown owner precedence {:?}", s)),
+        }
+    }
+}
+
+/// An attribute of a pod, or of a namespace if `pod` is empty.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct AttributeKey {
+    pub environment: String,
+    pub namespace: String,
+    pub pod: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributeValue {
+    pub value: String,
+    /// When the value was first observed, in milliseconds since the epoch.
+    pub valid_from: u64,
+}
+
+/// The top-level controller of a pod, e.g., a Deployment.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Workload {
+    pub kind: String,
+    pub name: String,
+}
+
+/// An object that is controlled by another one.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct ObjectKey {
+    environment: String,
+    namespace: String,
+    kind: String,
+    name: String,
 }
 
 pub struct OwnerBuffer {
+    /// The owners of the pods, empty for pods without one.
     buffer: DashMap<OwnerKey, OwnerValue>,
+    /// The owners of the namespaces, keyed with an empty pod. Unlike the
+    /// rest, they are kept across flushes for the pods that inherit them.
+    namespaces: DashMap<OwnerKey, OwnerValue>,
+    precedence: OwnerPrecedence,
+    attributes: DashMap<AttributeKey, AttributeValue>,
+    /// The controller of every object, with whether it was observed since
+    /// the previous flush. The references of pods are taken by every flush,
+    /// those of other objects are kept for one more flush interval, so that
+    /// a pod still resolves to its Deployment if the reference of its
+    /// ReplicaSet was observed before the last flush.
+    references: DashMap<ObjectKey, (Workload, bool)>,
     last_flush: Arc<Mutex<SystemTime>>,
     flush_interval: Duration,
 }
 
+/// What one flush took from the owner buffer.
+#[derive(Debug, Default)]
+pub struct FlushedOwners {
+    pub owners: Vec<(OwnerKey, OwnerValue)>,
+    pub attributes: Vec<(AttributeKey, AttributeValue)>,
+    pub workloads: Vec<(OwnerKey, Workload)>,
+}
+
+impl FlushedOwners {
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty() && self.attributes.is_empty() && self.workloads.is_empty()
+    }
+}
+
+/// A value that is kept with the time from which it was observed, such as
+/// an owner or an attribute.
+trait Versioned {
+    fn new(value: &str, valid_from: u64) -> Self;
+    fn parts(&mut self) -> (&mut String, &mut u64);
+}
+
+impl Versioned for OwnerValue {
+    fn new(owner: &str, valid_from: u64) -> Self {
+        OwnerValue {
+            owner: owner.to_string(),
+            valid_from,
+        }
+    }
+
+    fn parts(&mut self) -> (&mut String, &mut u64) {
+        (&mut self.owner, &mut self.valid_from)
+    }
+}
+
+impl Versioned for AttributeValue {
+    fn new(value: &str, valid_from: u64) -> Self {
+        AttributeValue {
+            value: value.to_string(),
+            valid_from,
+        }
+    }
+
+    fn parts(&mut self) -> (&mut String, &mut u64) {
+        (&mut self.value, &mut self.valid_from)
+    }
+}
+
+/// Records `value` for `key` as observed at `timestamp`. Keeps the first
+/// observation of the current value, unless a later one brings a different
+/// value.
+fn observe<K: Eq + Hash, V: Versioned>(
+    values: &DashMap<K, V>,
+    key: K,
+    value: &str,
+    timestamp: u64,
+) {
+    values
+        .entry(key)
+        .and_modify(|current| {
+            let (current, valid_from) = current.parts();
+            if current == value {
+                *valid_from = (*valid_from).min(timestamp);
+            } else if timestamp >= *valid_from {
+                *current = value.to_string();
+                *valid_from = timestamp;
+            }
+        })
+        .or_insert_with(|| V::new(value, timestamp));
+}
+
 impl OwnerBuffer {
     pub fn new(flush_interval_secs: u64, last_flush: SystemTime) -> Self {
         OwnerBuffer {
             buffer: DashMap::new(),
+            namespaces: DashMap::new(),
+            precedence: OwnerPrecedence::default(),
+            attributes: DashMap::new(),
+            references: DashMap::new(),
             last_flush: Arc::new(Mutex::new(last_flush)),
             flush_interval: Duration::from_secs(flush_interval_secs),
         }
     }
 
-    pub fn insert(&self, environment: &str, pod: &str, owner: &str) {
+    /// Decides between the owners of pods and namespaces with `precedence`
+    /// instead of preferring the pods.
+    pub fn with_precedence(self, precedence: OwnerPrecedence) -> Self {
+        Self { precedence, ..self }
+    }
+
+    /// Records the owner of a pod, or of a namespace if `pod` is empty, as
+    /// observed at `timestamp`. An empty owner records that there is none.
+    /// If the owner changes within a flush interval, only the latest one is
+    /// kept.
+    pub fn insert(
+        &self,
+        environment: &str,
+        namespace: &str,
+        pod: &str,
+        owner: &str,
+        timestamp: u64,
+    ) {
         let key = OwnerKey {
             environment: environment.to_string(),
+            namespace: namespace.to_string(),
             pod: pod.to_string(),
         };
-        let value = OwnerValue {
-            owner: owner.to_string(),
+        let owners = if pod.is_empty() {
+            &self.namespaces
+        } else {
+            &self.buffer
+        };
+        observe(owners, key, owner, timestamp);
+    }
+
+    /// Records an attribute of a pod, or of a namespace if `pod` is empty, as
+    /// observed at `timestamp`, like `insert` does for owners.
+    pub fn insert_attribute(
+        &self,
+        environment: &str,
+        namespace: &str,
+        pod: &str,
+        name: &str,
+        value: &str,
+        timestamp: u64,
+    ) {
+        let key = AttributeKey {
+            environment: environment.to_string(),
+            namespace: namespace.to_string(),
+            pod: pod.to_string(),
+            name: name.to_string(),
+        };
+        observe(&self.attributes, key, value, timestamp);
+    }
+
+    /// Records that the object `kind`/`name` is controlled by
+    /// `owner_kind`/`owner_name`, as reported by `kube_pod_owner`,
+    /// `kube_replicaset_owner` and `kube_job_owner`.
+    pub fn insert_reference(
+        &self,
+        environment: &str,
+        namespace: &str,
+        kind: &str,
+        name: &str,
+        owner_kind: &str,
+        owner_name: &str,
+    ) {
+        let key = ObjectKey {
+            environment: environment.to_string(),
+            namespace: namespace.to_string(),
+            kind: kind.to_string(),
+            name: name.to_string(),
         };
-        self.buffer.insert(key, value);
+        let owner = Workload {
+            kind: owner_kind.to_string(),
+            name: owner_name.to_string(),
+        };
+        self.references.insert(key, (owner, true));
     }
 
-    pub fn flush(&self) -> Vec<(String, String, String)> {
-        let mut flushed = Vec::new();
+    /// Takes the owners, attributes and workloads of the pods once the flush
+    /// interval has passed. Each pod gets the owner of its own or of its
+    /// namespace, as `precedence` decides, and is attributed to the end of its
+    /// chain of owner references, e.g., a Deployment rather than its current
+    /// ReplicaSet.
+    pub fn flush(&self) -> FlushedOwners {
+        let mut flushed = FlushedOwners::default();
         let now = SystemTime::now();
 
         let mut last_flush = self.last_flush.lock().unwrap();
@@ -48,15 +269,230 @@ impl OwnerBuffer {
             *last_flush = now;
 
             self.buffer.retain(|key, value| {
-                flushed.push((
-                    key.environment.clone(),
-                    key.pod.clone(),
-                    value.owner.clone(),
-                ));
+                if let Some(owner) = self.owner(key, value) {
+                    flushed.owners.push((key.clone(), owner));
+                }
                 false
             });
+            self.attributes.retain(|key, value| {
+                flushed.attributes.push((key.clone(), value.clone()));
+                false
+            });
+
+            let mut references = HashMap::new();
+            self.references.retain(|key, (owner, observed)| {
+                references.insert(key.clone(), owner.clone());
+                let keep = *observed && key.kind != "Pod";
+                *observed = false;
+                keep
+            });
+            flushed.workloads = resolve(&references);
         }
 
         flushed
     }
+
+    /// The owner of the pod `key`, given that its own is `value`.
+    fn owner(&self, key: &OwnerKey, value: &OwnerValue) -> Option<OwnerValue> {
+        let namespace = OwnerKey {
+            pod: String::new(),
+            ..key.clone()
+        };
+        // The pod cannot have had the owner of its namespace before it was
+        // observed.
+        let inherited = self
+            .namespaces
+            .get(&namespace)
+            .filter(|inherited| !inherited.owner.is_empty())
+            .map(|inherited| OwnerValue {
+                owner: inherited.owner.clone(),
+                valid_from: inherited.valid_from.max(value.valid_from),
+            });
+        let own = Some(value.clone()).filter(|own| !own.owner.is_empty());
+        match self.precedence {
+            OwnerPrecedence::Pod => own.or(inherited),
+            OwnerPrecedence::Namespace => inherited.or(own),
+        }
+    }
+}
+
+/// Follows the owner references of every pod up to its top-level workload.
+fn resolve(references: &HashMap<ObjectKey, Workload>) -> Vec<(OwnerKey, Workload)> {
+    references
+        .iter()
+        .filter(|(object, _)| object.kind == "Pod")
+        .map(|(pod, owner)| {
+            let mut workload = owner;
+            for _ in 0..MAX_OWNER_DEPTH {
+                let key = ObjectKey {
+                    environment: pod.environment.clone(),
+                    namespace: pod.namespace.clone(),
+                    kind: workload.kind.clone(),
+                    name: workload.name.clone(),
+                };
+                match references.get(&key) {
+                    Some(owner) => workload = owner,
+                    None => break,
+                }
+            }
+            (
+                OwnerKey {
+                    environment: pod.environment.clone(),
+                    namespace: pod.namespace.clone(),
+                    pod: pod.name.clone(),
+                },
+                workload.clone(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(buffer: &OwnerBuffer) -> HashMap<String, (String, u64)> {
+        buffer
+            .flush()
+            .owners
+            .into_iter()
+            .map(|(key, value)| (key.pod, (value.owner, value.valid_from)))
+            .collect()
+    }
+
+    fn workloads(buffer: &OwnerBuffer) -> HashMap<String, (String, String)> {
+        buffer
+            .flush()
+            .workloads
+            .into_iter()
+            .map(|(key, workload)| (key.pod, (workload.kind, workload.name)))
+            .collect()
+    }
+
+    fn workload(kind: &str, name: &str) -> (String, String) {
+        (kind.to_string(), name.to_string())
+    }
+
+    #[test]
+    fn test_owner_keeps_first_observation_until_it_changes() {
+        let buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
+        buffer.insert("prod", "shop", "pod-1", "team-a", 2000);
+        buffer.insert("prod", "shop", "pod-1", "team-a", 1000);
+        buffer.insert("prod", "shop", "pod-1", "team-a", 3000);
+        buffer.insert("prod", "shop", "pod-2", "team-a", 1000);
+        buffer.insert("prod", "shop", "pod-2", "team-b", 4000);
+        buffer.insert("prod", "shop", "pod-2", "team-c", 3000);
+
+        let owners = owners(&buffer);
+
+        assert_eq!(owners["pod-1"], ("team-a".to_string(), 1000));
+        assert_eq!(owners["pod-2"], ("team-b".to_string(), 4000));
+    }
+
+    #[test]
+    fn test_pods_inherit_namespace_owner() {
+        let buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
+        buffer.insert("prod", "shop", "", "team-shop", 1000);
+        buffer.insert("prod", "shop", "pod-1", "", 500);
+        buffer.insert("prod", "shop", "pod-2", "team-a", 1000);
+        buffer.insert("prod", "blog", "pod-3", "", 1000);
+
+        let flushed = owners(&buffer);
+
+        assert_eq!(flushed.len(), 2);
+        assert_eq!(flushed["pod-1"], ("team-shop".to_string(), 1000));
+        assert_eq!(flushed["pod-2"], ("team-a".to_string(), 1000));
+
+        // The owners of namespaces are kept for later flushes.
+        buffer.insert("prod", "shop", "pod-4", "", 2000);
+        *buffer.last_flush.lock().unwrap() = SystemTime::UNIX_EPOCH;
+        assert_eq!(owners(&buffer)["pod-4"], ("team-shop".to_string(), 2000));
+    }
+
+    #[test]
+    fn test_namespace_owner_takes_precedence() {
+        let buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH)
+            .with_precedence("namespace".parse().unwrap());
+        buffer.insert("prod", "shop", "", "team-shop", 1000);
+        buffer.insert("prod", "shop", "pod-1", "team-a", 1000);
+        buffer.insert("prod", "blog", "", "", 1000);
+        buffer.insert("prod", "blog", "pod-2", "team-b", 1000);
+
+        let owners = owners(&buffer);
+
+        assert_eq!(owners["pod-1"], ("team-shop".to_string(), 1000));
+        assert_eq!(owners["pod-2"], ("team-b".to_string(), 1000));
+    }
+
+    #[test]
+    fn test_pods_resolve_to_top_level_workloads() {
+        let buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
+        let reference = |kind, name, owner_kind, owner_name| {
+            buffer.insert_reference("prod", "shop", kind, name, owner_kind, owner_name)
+        };
+        reference("Pod", "web-5d4f-abcde", "ReplicaSet", "web-5d4f");
+        reference("ReplicaSet", "web-5d4f", "Deployment", "web");
+        reference("Pod", "db-0", "StatefulSet", "db");
+        reference("Pod", "report-28977-xyz", "Job", "report-28977");
+        reference("Job", "report-28977", "CronJob", "report");
+        reference("Pod", "legacy-abcde", "ReplicaSet", "legacy");
+
+        let workloads = workloads(&buffer);
+
+        assert_eq!(workloads.len(), 4);
+        assert_eq!(workloads["web-5d4f-abcde"], workload("Deployment", "web"));
+        assert_eq!(workloads["db-0"], workload("StatefulSet", "db"));
+        assert_eq!(workloads["report-28977-xyz"], workload("CronJob", "report"));
+        // Without an owner of its own, the ReplicaSet is the workload.
+        assert_eq!(workloads["legacy-abcde"], workload("ReplicaSet", "legacy"));
+    }
+
+    #[test]
+    fn test_controller_references_outlive_a_flush() {
+        let buffer = OwnerBuffer::new(0, SystemTime::UNIX_EPOCH);
+        let reference = |kind, name, owner_kind, owner_name| {
+            buffer.insert_reference("prod", "shop", kind, name, owner_kind, owner_name)
+        };
+        reference("ReplicaSet", "web-5d4f", "Deployment", "web");
+        assert!(workloads(&buffer).is_empty());
+
+        reference("Pod", "web-5d4f-abcde", "ReplicaSet", "web-5d4f");
+        assert_eq!(
+            workloads(&buffer)["web-5d4f-abcde"],
+            workload("Deployment", "web")
+        );
+
+        // Not observed for a whole flush interval, the ReplicaSet is gone.
+        reference("Pod", "web-5d4f-abcde", "ReplicaSet", "web-5d4f");
+        assert_eq!(
+            workloads(&buffer)["web-5d4f-abcde"],
+            workload("ReplicaSet", "web-5d4f")
+        );
+    }
+
+    #[test]
+    fn test_references_stay_within_namespace() {
+        let buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
+        buffer.insert_reference("prod", "shop", "Pod", "web-1", "ReplicaSet", "web-5d4f");
+        buffer.insert_reference(
+            "prod",
+            "blog",
+            "ReplicaSet",
+            "web-5d4f",
+            "Deployment",
+            "web",
+        );
+
+        let workloads = workloads(&buffer);
+
+        assert_eq!(workloads["web-1"], workload("ReplicaSet", "web-5d4f"));
+    }
+
+    #[test]
+    fn test_reference_cycles_end() {
+        let buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
+        buffer.insert_reference("prod", "shop", "Pod", "a", "Pod", "a");
+
+        assert_eq!(workloads(&buffer)["a"], workload("Pod", "a"));
+    }
 }
@@ -0,0 +1,623 @@
+use crate::durable;
+use crate::metrics_buffer::{Key, Metrics};
+use crate::owner_buffer::{AttributeKey, AttributeValue, OwnerKey, OwnerValue, Workload};
+use crate::sink::Sink;
+use crate::spool::{metric_chunks, pb};
+use log::{debug, error, info, warn};
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int32Type, Int64Type};
+use parquet::errors::{ParquetError, Result};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use parquet::schema::types::Type;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Size after which a file is closed and the next batch starts a new one.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 128 * 1024 * 1024;
+/// Age after which a file is closed, so that data becomes visible to readers.
+pub const DEFAULT_MAX_FILE_AGE: Duration = Duration::from_secs(15 * 60);
+/// Readers like Spark and DuckDB skip files starting with an underscore.
+const MANIFEST_FILE: &str = "_manifest.jsonl";
+/// Suffix of files that are still written. They have no footer yet, so they
+/// cannot be read.
+const IN_PROGRESS_EXTENSION: &str = "inprogress";
+
+/// Environment and date are encoded in the directory names, so, as usual for
+/// Hive partitioning, they are not repeated in the files.
+const SCHEMA: &str = "message micrometrics {
+    REQUIRED INT64 time (TIMESTAMP(MILLIS, true));
+    REQUIRED BYTE_ARRAY namespace (UTF8);
+    REQUIRED BYTE_ARRAY pod (UTF8);
+    REQUIRED BYTE_ARRAY container (UTF8);
+    OPTIONAL DOUBLE cpu_usage;
+    OPTIONAL DOUBLE cpu_limit;
+    OPTIONAL DOUBLE cpu_request;
+    OPTIONAL INT64 memory_usage;
+    OPTIONAL INT64 memory_limit;
+    OPTIONAL INT64 memory_request;
+    OPTIONAL INT32 cpu_restarts;
+    OPTIONAL INT32 memory_usage_count;
+    OPTIONAL INT64 memory_usage_min;
+    OPTIONAL INT64 memory_usage_max;
+    OPTIONAL INT64 memory_usage_avg;
+    OPTIONAL INT64 memory_usage_p95;
+    REQUIRED BOOLEAN late;
+}";
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Partition {
+    environment: String,
+    date: String,
+}
+
+impl Partition {
+    fn dir(&self, root: &Path) -> PathBuf {
+        root.join(format!("environment={}", escape(&self.environment)))
+            .join(format!("date={}", self.date))
+    }
+}
+
+struct OpenFile {
+    writer: SerializedFileWriter<File>,
+    path: PathBuf,
+    opened: Instant,
+    /// The rows of the file, to write them again if it cannot be completed.
+    rows: Vec<pb::MetricRow>,
+    min_time: u64,
+    max_time: u64,
+}
+
+/// Writes the flushed metrics as Parquet files under
+/// `environment=<env>/date=<yyyy-mm-dd>/`, one row group per flush and
+/// partition. Every completed file is listed in a manifest at the root.
+/// Owners are not exported.
+///
+/// Open files are completed together, so that at times no row is in an
+/// unreadable file and the write-ahead log can let go of all of them. Rows
+/// that cannot be written are kept in memory and written again on the next
+/// sync, like the database sinks retry their chunks.
+pub struct ParquetSink {
+    root: PathBuf,
+    max_file_size: u64,
+    max_file_age: Duration,
+    schema: Arc<Type>,
+    properties: Arc<WriterProperties>,
+    files: Mutex<HashMap<Partition, OpenFile>>,
+    sequence: AtomicU64,
+    /// The first error since the last sync and the rows that are to be
+    /// written again because of it.
+    unwritten: Mutex<Option<(String, Vec<pb::MetricRow>)>>,
+    /// A file that was completed but could not be synced or listed in the
+    /// manifest. Its rows must neither be written twice nor be taken as
+    /// stored, so the write-ahead log is kept until a restart replays it.
+    failed: Mutex<Option<String>>,
+}
+
+impl ParquetSink {
+    pub fn new(root: impl AsRef<Path>, max_file_size: u64, max_file_age: Duration) -> Self {
+        ParquetSink {
+            root: root.as_ref().to_path_buf(),
+            max_file_size,
+            max_file_age,
+            schema: Arc::new(parse_message_type(SCHEMA).expect("Invalid Parquet schema")),
+            properties: Arc::new(
+                WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build(),
+            ),
+            files: Mutex::new(HashMap::new()),
+            sequence: AtomicU64::new(0),
+            unwritten: Mutex::new(None),
+            failed: Mutex::new(None),
+        }
+    }
+
+    fn write(&self, files: &mut HashMap<Partition, OpenFile>, rows: Vec<pb::MetricRow>) {
+        let mut partitions: HashMap<Partition, Vec<pb::MetricRow>> = HashMap::new();
+        for row in rows {
+            let Some(time) = chrono::DateTime::from_timestamp_millis(row.timestamp as i64) else {
+                continue;
+            };
+            let partition = Partition {
+                environment: row.environment.clone(),
+                date: time.format("%Y-%m-%d").to_string(),
+            };
+            partitions.entry(partition).or_default().push(row);
+        }
+
+        let mut full = false;
+        for (partition, rows) in partitions {
+            let file = match files.entry(partition.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match self.open(entry.key()) {
+                    Ok(file) => entry.insert(file),
+                    Err(e) => {
+                        self.keep(e, rows);
+                        continue;
+                    }
+                },
+            };
+            if let Err(e) = write_row_group(&mut file.writer, &rows) {
+                // The file cannot be completed any more, so all of its rows
+                // go to a new one.
+                let file = files.remove(&partition).unwrap();
+                self.abandon(file, e, rows);
+                continue;
+            }
+            for row in &rows {
+                file.min_time = file.min_time.min(row.timestamp);
+                file.max_time = file.max_time.max(row.timestamp);
+            }
+            file.rows.extend(rows);
+            full |= file.writer.bytes_written() as u64 >= self.max_file_size;
+        }
+
+        if full {
+            self.finish_all(files);
+        }
+    }
+
+    /// Completes all open files.
+    fn finish_all(&self, files: &mut HashMap<Partition, OpenFile>) {
+        for (partition, file) in files.drain() {
+            self.finish(&partition, file);
+        }
+    }
+
+    fn open(&self, partition: &Partition) -> Result<OpenFile> {
+        let dir = partition.dir(&self.root);
+        fs::create_dir_all(&dir)?;
+        let name = format!(
+            "part-{}-{}.parquet.{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.sequence.fetch_add(1, Ordering::Relaxed),
+            IN_PROGRESS_EXTENSION
+        );
+        let path = dir.join(name);
+        debug!("Starting Parquet file {:?}", path);
+        let writer = SerializedFileWriter::new(
+            File::create(&path)?,
+            self.schema.clone(),
+            self.properties.clone(),
+        )?;
+        Ok(OpenFile {
+            writer,
+            path,
+            opened: Instant::now(),
+            rows: Vec::new(),
+            min_time: u64::MAX,
+            max_time: 0,
+        })
+    }
+
+    /// Writes the footer, syncs the file and gives it its final name, then
+    /// records it in the manifest. A file that cannot be completed is
+    /// abandoned.
+    fn finish(&self, partition: &Partition, mut file: OpenFile) {
+        let bytes = file.writer.bytes_written();
+        let path = file.path.with_extension("");
+        let completed = file.writer.finish().and_then(|_| {
+            file.writer.inner().sync_all()?;
+            fs::rename(&file.path, &path)?;
+            Ok(())
+        });
+        if let Err(e) = completed {
+            self.abandon(file, e, Vec::new());
+            return;
+        }
+        info!(
+            "Completed Parquet file {:?} with {} rows",
+            path,
+            file.rows.len()
+        );
+
+        // The file is readable now, so its rows must not be written again.
+        if let Err(e) = self.publish(partition, &file, &path, bytes) {
+            self.failed.lock().unwrap().get_or_insert_with(|| {
+                format!(
+                    "Failed to sync Parquet file {:?}, keeping its rows for a restart: {}",
+                    path, e
+                )
+            });
+        }
+    }
+
+    /// Records a completed file in the manifest and syncs the directories up
+    /// to the root, so that neither the file nor its entry is lost.
+    fn publish(
+        &self,
+        partition: &Partition,
+        file: &OpenFile,
+        path: &Path,
+        bytes: usize,
+    ) -> Result<()> {
+        let entry = serde_json::json!({
+            "path": path.strip_prefix(&self.root).unwrap_or(path),
+            "environment": partition.environment,
+            "date": partition.date,
+            "rows": file.rows.len(),
+            "bytes": bytes,
+            "min_time": file.min_time,
+            "max_time": file.max_time,
+        });
+        let mut manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join(MANIFEST_FILE))?;
+        writeln!(manifest, "{}", entry)?;
+        manifest.sync_data()?;
+
+        let dir = partition.dir(&self.root);
+        for dir in dir
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&self.root))
+        {
+            durable::sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Completes all open files.
+    pub fn close(&self) {
+        let mut files = self.files.lock().unwrap();
+        self.finish_all(&mut files);
+        if let Some((e, rows)) = &*self.unwritten.lock().unwrap() {
+            error!("{} rows are left to the write-ahead log: {}", rows.len(), e);
+        }
+    }
+
+    /// Keeps `rows` to write them again on the next sync.
+    fn keep(&self, error: ParquetError, rows: Vec<pb::MetricRow>) {
+        let mut unwritten = self.unwritten.lock().unwrap();
+        let (_, kept) = unwritten.get_or_insert_with(|| {
+            (
+                format!(
+                    "Failed to export metrics to Parquet, writing them again: {}",
+                    error
+                ),
+                Vec::new(),
+            )
+        });
+        kept.extend(rows);
+    }
+
+    /// Removes a file that cannot be completed and keeps its rows, along
+    /// with `rows` that were meant for it.
+    fn abandon(&self, file: OpenFile, error: ParquetError, rows: Vec<pb::MetricRow>) {
+        if let Err(e) = fs::remove_file(&file.path) {
+            warn!(
+                "Failed to remove incomplete Parquet file {:?}: {}",
+                file.path, e
+            );
+        }
+        let mut kept = file.rows;
+        kept.extend(rows);
+        self.keep(error, kept);
+    }
+}
+
+impl Drop for ParquetSink {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn write_row_group(writer: &mut SerializedFileWriter<File>, rows: &[pb::MetricRow]) -> Result<()> {
+    let times: Vec<i64> = rows.iter().map(|r| r.timestamp as i64).collect();
+    let text: [fn(&pb::MetricRow) -> &str; 3] = [|r| &r.namespace, |r| &r.pod, |r| &r.container];
+    let cpu: [fn(&pb::MetricRow) -> Option<f64>; 3] =
+        [|r| r.cpu_usage, |r| r.cpu_limit, |r| r.cpu_request];
+    let memory: [fn(&pb::MetricRow) -> Option<i64>; 7] = [
+        |r| r.memory_usage_bytes(),
+        |r| r.memory_limit_bytes(),
+        |r| r.memory_request_bytes(),
+        |r| r.memory_usage_min_bytes(),
+        |r| r.memory_usage_max_bytes(),
+        |r| r.memory_usage_avg_bytes(),
+        |r| r.memory_usage_p95_bytes(),
+    ];
+    let counts: [fn(&pb::MetricRow) -> Option<i32>; 2] = [
+        |r| r.cpu_restarts.map(|v| v as i32),
+        |r| r.memory_usage_count.map(|v| v as i32),
+    ];
+
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match index {
+            0 => {
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&times, None, None)?;
+            }
+            1..=3 => {
+                let get = text[index - 1];
+                let values: Vec<ByteArray> = rows.iter().map(|r| get(r).into()).collect();
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            4..=6 => {
+                let get = cpu[index - 4];
+                let values: Vec<f64> = rows.iter().filter_map(get).collect();
+                let levels: Vec<i16> = rows.iter().map(|r| get(r).is_some() as i16).collect();
+                column
+                    .typed::<DoubleType>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+            7..=9 | 12..=15 => {
+                let get = memory[if index < 12 { index - 7 } else { index - 9 }];
+                let values: Vec<i64> = rows.iter().filter_map(get).collect();
+                let levels: Vec<i16> = rows.iter().map(|r| get(r).is_some() as i16).collect();
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+            10..=11 => {
+                let get = counts[index - 10];
+                let values: Vec<i32> = rows.iter().filter_map(get).collect();
+                let levels: Vec<i16> = rows.iter().map(|r| get(r).is_some() as i16).collect();
+                column
+                    .typed::<Int32Type>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+            16 => {
+                let values: Vec<bool> = rows.iter().map(|r| r.late).collect();
+                column
+                    .typed::<BoolType>()
+                    .write_batch(&values, None, None)?;
+            }
+            _ => return Err(ParquetError::General("Unexpected column".to_string())),
+        }
+        column.close()?;
+        index += 1;
+    }
+    row_group.close()?;
+    Ok(())
+}
+
+/// Percent-encodes everything but letters, digits and `._-`, so that any
+/// environment name is a safe directory name.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'_' | b'-') {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+impl Sink for ParquetSink {
+    fn create_tables(&self) {
+        fs::create_dir_all(&self.root).expect("Failed to create Parquet directory");
+        for entry in walk(&self.root) {
+            if entry.extension().and_then(|e| e.to_str()) == Some(IN_PROGRESS_EXTENSION) {
+                warn!(
+                    "Ignoring incomplete Parquet file {:?} of a previous run",
+                    entry
+                );
+            }
+        }
+    }
+
+    fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>) {
+        let rows: Vec<_> = metric_chunks(metrics, usize::MAX)
+            .into_iter()
+            .flat_map(|chunk| chunk.metrics)
+            .collect();
+        info!("Exporting {} metrics to Parquet", rows.len());
+        let mut files = self.files.lock().unwrap();
+        self.write(&mut files, rows);
+    }
+
+    fn insert_owners(&self, _owners: Vec<(OwnerKey, OwnerValue)>) {}
+
+    fn insert_attributes(&self, _attributes: Vec<(AttributeKey, AttributeValue)>) {}
+
+    fn insert_workloads(&self, _workloads: Vec<(OwnerKey, Workload)>) {}
+
+    /// Also completes the open files once the oldest of them is
+    /// `max_file_age` old, whether or not rows still arrive, and writes the
+    /// rows that failed before to new files.
+    fn sync(&self) -> std::result::Result<bool, String> {
+        let mut files = self.files.lock().unwrap();
+        let due = files
+            .values()
+            .any(|file| file.opened.elapsed() >= self.max_file_age);
+        if due {
+            self.finish_all(&mut files);
+        }
+        let retry = self.unwritten.lock().unwrap().take();
+        if let Some((_, rows)) = retry {
+            self.write(&mut files, rows);
+        }
+
+        if let Some(e) = &*self.failed.lock().unwrap() {
+            return Err(e.clone());
+        }
+        match &*self.unwritten.lock().unwrap() {
+            Some((e, _)) => Err(e.clone()),
+            None => Ok(files.is_empty()),
+        }
+    }
+}
+
+fn walk(dir: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                paths.extend(walk(&path));
+            } else {
+                paths.push(path);
+            }
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    fn metrics(environment: &str, timestamp: u64) -> (Key, Metrics) {
+        (
+            Key {
+                timestamp,
+                environment: environment.to_string(),
+                namespace: "shop".to_string(),
+                pod: "pod-1".to_string(),
+                container: "container-1".to_string(),
+            },
+            Metrics {
+                cpu_limit: Some(60.0),
+                memory_usage: Some(512.0),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn parquet_files(root: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<_> = walk(root)
+            .into_iter()
+            .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("parquet"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn rows(path: &Path) -> i64 {
+        SerializedFileReader::new(File::open(path).unwrap())
+            .unwrap()
+            .metadata()
+            .file_metadata()
+            .num_rows()
+    }
+
+    #[test]
+    fn test_partitions_by_environment_and_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ParquetSink::new(dir.path(), DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_FILE_AGE);
+        sink.create_tables();
+
+        let day = 24 * 60 * 60 * 1000;
+        sink.insert_metrics(vec![
+            metrics("prod", 60_000),
+            metrics("prod", 120_000),
+            metrics("prod", day),
+            metrics("dev/1", 60_000),
+        ]);
+        // Nothing is readable before the files are completed.
+        assert!(parquet_files(dir.path()).is_empty());
+        sink.close();
+
+        let files = parquet_files(dir.path());
+        let relative: Vec<_> = files
+            .iter()
+            .map(|p| p.parent().unwrap().strip_prefix(dir.path()).unwrap())
+            .collect();
+        assert_eq!(
+            relative,
+            vec![
+                Path::new("environment=dev%2F1/date=1970-01-01"),
+                Path::new("environment=prod/date=1970-01-01"),
+                Path::new("environment=prod/date=1970-01-02"),
+            ]
+        );
+        assert_eq!(files.iter().map(|f| rows(f)).collect::<Vec<_>>(), [1, 2, 1]);
+
+        let manifest = fs::read_to_string(dir.path().join(MANIFEST_FILE)).unwrap();
+        assert_eq!(manifest.lines().count(), 3);
+        let entry: serde_json::Value =
+            serde_json::from_str(manifest.lines().find(|l| l.contains("\"rows\":2")).unwrap())
+                .unwrap();
+        assert_eq!(entry["environment"], "prod");
+        assert_eq!(entry["min_time"], 60_000);
+        assert_eq!(entry["max_time"], 120_000);
+    }
+
+    #[test]
+    fn test_rolls_over_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ParquetSink::new(dir.path(), 1, DEFAULT_MAX_FILE_AGE);
+
+        sink.insert_metrics(vec![metrics("prod", 60_000)]);
+        sink.insert_metrics(vec![metrics("prod", 120_000), metrics("dev", 120_000)]);
+
+        // Files are completed together, even before they are full.
+        assert_eq!(parquet_files(dir.path()).len(), 3);
+        assert_eq!(sink.sync(), Ok(true));
+    }
+
+    #[test]
+    fn test_rolls_over_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ParquetSink::new(dir.path(), DEFAULT_MAX_FILE_SIZE, Duration::ZERO);
+
+        sink.insert_metrics(vec![metrics("prod", 60_000)]);
+        assert!(parquet_files(dir.path()).is_empty());
+
+        // Without further rows, the next flush completes the file.
+        assert_eq!(sink.sync(), Ok(true));
+        let files = parquet_files(dir.path());
+        assert_eq!(files.len(), 1);
+        assert_eq!(rows(&files[0]), 1);
+    }
+
+    #[test]
+    fn test_rows_in_open_files_are_not_durable() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ParquetSink::new(dir.path(), DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_FILE_AGE);
+
+        assert_eq!(sink.sync(), Ok(true));
+        sink.insert_metrics(vec![metrics("prod", 60_000)]);
+        assert_eq!(sink.sync(), Ok(false));
+        sink.close();
+        assert_eq!(sink.sync(), Ok(true));
+    }
+
+    #[test]
+    fn test_write_errors_reach_the_flusher() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("not-a-directory");
+        fs::write(&root, "").unwrap();
+        let sink = ParquetSink::new(&root, DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_FILE_AGE);
+
+        sink.insert_metrics(vec![metrics("prod", 60_000)]);
+
+        assert!(sink.sync().is_err());
+    }
+
+    #[test]
+    fn test_failed_rows_are_written_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("export");
+        fs::write(&root, "").unwrap();
+        let sink = ParquetSink::new(&root, DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_FILE_AGE);
+
+        sink.insert_metrics(vec![metrics("prod", 60_000)]);
+        assert!(sink.sync().is_err());
+
+        // Once the export directory can be created, the rows go to a new
+        // file and the error is gone.
+        fs::remove_file(&root).unwrap();
+        assert_eq!(sink.sync(), Ok(false));
+        sink.close();
+        let files = parquet_files(&root);
+        assert_eq!(files.iter().map(|f| rows(f)).collect::<Vec<_>>(), [1]);
+        assert_eq!(sink.sync(), Ok(true));
+    }
+}
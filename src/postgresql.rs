@@ -0,0 +1,742 @@
+use crate::database::{DEFAULT_WRITE_ATTEMPTS, retry_with_backoff};
+use crate::history::{ATTRIBUTES, History, OWNERS, Version};
+use crate::metrics_buffer::{Key, Metrics};
+use crate::migrations::{Migration, VERSION_TABLE, migrate, pending};
+use crate::owner_buffer::{AttributeKey, AttributeValue, OwnerKey, OwnerValue, Workload};
+use crate::rollup::{Expiry, Rollups, Series};
+use crate::sink::Sink;
+use crate::spool::{
+    DEFAULT_QUEUE_SIZE, Spool, attribute_chunks, metric_chunks, owner_chunks, pb, workload_chunks,
+};
+use chrono::NaiveDateTime;
+use log::{debug, info, warn};
+use postgres::types::ToSql;
+use postgres::{Client, Error, GenericClient, NoTls};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Advisory lock key that serializes migrations across replicas.
+const MIGRATION_LOCK: i64 = 0x6d69_6372_6f69_6e73;
+
+/// See the MySQL backend.
+const INSERT_METRICS: &str = r"INSERT INTO micrometrics
+    (time, environment, namespace, pod, container, cpu_usage, cpu_limit, memory_usage,
+    memory_limit, cpu_request, memory_request, cpu_restarts, memory_usage_count,
+    memory_usage_min, memory_usage_max, memory_usage_avg, memory_usage_p95, merged_chunk)
+    SELECT * FROM UNNEST(
+        $1::timestamp[], $2::varchar[], $3::varchar[], $4::varchar[],
+        $5::varchar[], $6::float8[], $7::float8[], $8::bigint[], $9::bigint[],
+        $10::float8[], $11::bigint[], $12::int4[], $13::int4[], $14::bigint[],
+        $15::bigint[], $16::bigint[], $17::bigint[], $18::bigint[])
+    ON CONFLICT (time, environment, namespace, pod, container) DO UPDATE SET
+    cpu_usage = COALESCE(EXCLUDED.cpu_usage, micrometrics.cpu_usage),
+    cpu_restarts = COALESCE(EXCLUDED.cpu_restarts, micrometrics.cpu_restarts),
+    memory_usage_count = COALESCE(EXCLUDED.memory_usage_count, micrometrics.memory_usage_count),
+    memory_usage_min = COALESCE(EXCLUDED.memory_usage_min, micrometrics.memory_usage_min),
+    memory_usage_max = COALESCE(EXCLUDED.memory_usage_max, micrometrics.memory_usage_max),
+    memory_usage_avg = COALESCE(EXCLUDED.memory_usage_avg, micrometrics.memory_usage_avg),
+    memory_usage_p95 = COALESCE(EXCLUDED.memory_usage_p95, micrometrics.memory_usage_p95),
+    cpu_limit = COALESCE(EXCLUDED.cpu_limit, micrometrics.cpu_limit),
+    memory_usage = COALESCE(EXCLUDED.memory_usage, micrometrics.memory_usage),
+    memory_limit = COALESCE(EXCLUDED.memory_limit, micrometrics.memory_limit),
+    cpu_request = COALESCE(EXCLUDED.cpu_request, micrometrics.cpu_request),
+    memory_request = COALESCE(EXCLUDED.memory_request, micrometrics.memory_request)";
+
+/// See the MySQL backend. `LEAST` and `GREATEST` skip NULLs in PostgreSQL.
+const MERGE_METRICS: &str = r"INSERT INTO micrometrics
+    (time, environment, namespace, pod, container, cpu_usage, cpu_limit, memory_usage,
+    memory_limit, cpu_request, memory_request, cpu_restarts, memory_usage_count,
+    memory_usage_min, memory_usage_max, memory_usage_avg, memory_usage_p95, merged_chunk)
+    SELECT * FROM UNNEST(
+        $1::timestamp[], $2::varchar[], $3::varchar[], $4::varchar[],
+        $5::varchar[], $6::float8[], $7::float8[], $8::bigint[], $9::bigint[],
+        $10::float8[], $11::bigint[], $12::int4[], $13::int4[], $14::bigint[],
+        $15::bigint[], $16::bigint[], $17::bigint[], $18::bigint[])
+    ON CONFLICT (time, environment, namespace, pod, container) DO UPDATE SET
+    memory_usage_avg = COALESCE(
+        (micrometrics.memory_usage_avg * micrometrics.memory_usage_count
+            + EXCLUDED.memory_usage_avg * EXCLUDED.memory_usage_count)
+            / (micrometrics.memory_usage_count + EXCLUDED.memory_usage_count),
+        EXCLUDED.memory_usage_avg, micrometrics.memory_usage_avg),
+    memory_usage_count = COALESCE(micrometrics.memory_usage_count + EXCLUDED.memory_usage_count,
+        EXCLUDED.memory_usage_count, micrometrics.memory_usage_count),
+    memory_usage_min = LEAST(micrometrics.memory_usage_min, EXCLUDED.memory_usage_min),
+    memory_usage_max = GREATEST(micrometrics.memory_usage_max, EXCLUDED.memory_usage_max),
+    cpu_limit = COALESCE(EXCLUDED.cpu_limit, micrometrics.cpu_limit),
+    memory_usage = COALESCE(EXCLUDED.memory_usage, micrometrics.memory_usage),
+    memory_limit = COALESCE(EXCLUDED.memory_limit, micrometrics.memory_limit),
+    cpu_request = COALESCE(EXCLUDED.cpu_request, micrometrics.cpu_request),
+    memory_request = COALESCE(EXCLUDED.memory_request, micrometrics.memory_request),
+    merged_chunk = EXCLUDED.merged_chunk
+    WHERE micrometrics.merged_chunk IS DISTINCT FROM EXCLUDED.merged_chunk";
+
+/// See the MySQL migrations. Version 1 is the schema from before migrations.
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create micrometrics and microowner",
+        statements: &[
+            r"CREATE TABLE IF NOT EXISTS micrometrics (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage REAL,
+                cpu_limit REAL,
+                memory_usage REAL,
+                memory_limit REAL,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+            r"CREATE TABLE IF NOT EXISTS microowner (
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                owner VARCHAR(255),
+                PRIMARY KEY (environment, pod)
+            )",
+            // Only with TimescaleDB, which plain SQL can decide in a DO block.
+            r"DO $$
+                BEGIN
+                    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+                        PERFORM create_hypertable('micrometrics', 'time',
+                            if_not_exists => TRUE, migrate_data => TRUE);
+                    END IF;
+                END
+                $$",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Store CPU as DOUBLE PRECISION/NUMERIC and memory as integer bytes",
+        statements: &[r"ALTER TABLE micrometrics
+                ALTER COLUMN cpu_usage TYPE DOUBLE PRECISION,
+                ALTER COLUMN cpu_limit TYPE NUMERIC(12, 3),
+                ALTER COLUMN memory_usage TYPE BIGINT USING round(memory_usage)::bigint,
+                ALTER COLUMN memory_limit TYPE BIGINT USING round(memory_limit)::bigint"],
+    },
+    Migration {
+        version: 3,
+        description: "Create hourly and daily rollups",
+        statements: &[
+            r"CREATE TABLE micrometrics_hourly (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage DOUBLE PRECISION,
+                cpu_limit_seconds DOUBLE PRECISION,
+                memory_usage_avg DOUBLE PRECISION,
+                memory_usage_max BIGINT,
+                memory_limit_max BIGINT,
+                samples INTEGER,
+                memory_samples INTEGER,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+            r"CREATE TABLE micrometrics_daily (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage DOUBLE PRECISION,
+                cpu_limit_seconds DOUBLE PRECISION,
+                memory_usage_avg DOUBLE PRECISION,
+                memory_usage_max BIGINT,
+                memory_limit_max BIGINT,
+                samples INTEGER,
+                memory_samples INTEGER,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Add resource requests",
+        statements: &[
+            r"ALTER TABLE micrometrics
+                ADD COLUMN cpu_request NUMERIC(12, 3), ADD COLUMN memory_request BIGINT",
+            r"ALTER TABLE micrometrics_hourly
+                ADD COLUMN cpu_request_seconds DOUBLE PRECISION, ADD COLUMN memory_request_max BIGINT",
+            r"ALTER TABLE micrometrics_daily
+                ADD COLUMN cpu_request_seconds DOUBLE PRECISION, ADD COLUMN memory_request_max BIGINT",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Add namespace to the primary keys",
+        statements: &[
+            r"ALTER TABLE micrometrics
+                ADD COLUMN namespace VARCHAR(255) NOT NULL DEFAULT '',
+                DROP CONSTRAINT micrometrics_pkey,
+                ADD PRIMARY KEY (time, environment, namespace, pod, container)",
+            r"ALTER TABLE micrometrics_hourly
+                ADD COLUMN namespace VARCHAR(255) NOT NULL DEFAULT '',
+                DROP CONSTRAINT micrometrics_hourly_pkey,
+                ADD PRIMARY KEY (time, environment, namespace, pod, container)",
+            r"ALTER TABLE micrometrics_daily
+                ADD COLUMN namespace VARCHAR(255) NOT NULL DEFAULT '',
+                DROP CONSTRAINT micrometrics_daily_pkey,
+                ADD PRIMARY KEY (time, environment, namespace, pod, container)",
+            r"ALTER TABLE microowner
+                ADD COLUMN namespace VARCHAR(255) NOT NULL DEFAULT '',
+                DROP CONSTRAINT microowner_pkey,
+                ADD PRIMARY KEY (environment, namespace, pod)",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Create microworkload",
+        statements: &[r"CREATE TABLE microworkload (
+                environment VARCHAR(255),
+                namespace VARCHAR(255),
+                pod VARCHAR(255),
+                kind VARCHAR(255),
+                workload VARCHAR(255),
+                PRIMARY KEY (environment, namespace, pod)
+            )"],
+    },
+    Migration {
+        version: 7,
+        description: "Track the validity of owners",
+        statements: &[r"ALTER TABLE microowner
+                ADD COLUMN valid_from TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00',
+                ADD COLUMN valid_to TIMESTAMP,
+                DROP CONSTRAINT microowner_pkey,
+                ADD PRIMARY KEY (environment, namespace, pod, valid_from)"],
+    },
+    Migration {
+        version: 8,
+        description: "Create microattribute",
+        statements: &[r"CREATE TABLE microattribute (
+                environment VARCHAR(255),
+                namespace VARCHAR(255),
+                pod VARCHAR(255),
+                attribute VARCHAR(255),
+                value VARCHAR(255),
+                valid_from TIMESTAMP NOT NULL,
+                valid_to TIMESTAMP,
+                PRIMARY KEY (environment, namespace, pod, attribute, valid_from)
+            )"],
+    },
+    Migration {
+        version: 9,
+        description: "Count CPU restarts",
+        statements: &[
+            "ALTER TABLE micrometrics ADD COLUMN cpu_restarts INTEGER",
+            "ALTER TABLE micrometrics_hourly ADD COLUMN cpu_restarts INTEGER",
+            "ALTER TABLE micrometrics_daily ADD COLUMN cpu_restarts INTEGER",
+        ],
+    },
+    Migration {
+        version: 10,
+        description: "Aggregate memory samples",
+        statements: &[r"ALTER TABLE micrometrics
+                ADD COLUMN memory_usage_count INTEGER, ADD COLUMN memory_usage_min BIGINT,
+                ADD COLUMN memory_usage_max BIGINT, ADD COLUMN memory_usage_avg BIGINT,
+                ADD COLUMN memory_usage_p95 BIGINT"],
+    },
+    Migration {
+        version: 11,
+        description: "Remember the chunk that late samples were merged from",
+        statements: &[r"ALTER TABLE micrometrics ADD COLUMN merged_chunk BIGINT"],
+    },
+];
+
+/// The same tables as the MySQL backend, in PostgreSQL types. With the
+/// TimescaleDB extension, `micrometrics` additionally becomes a hypertable.
+pub struct PostgresDatabase {
+    url: String,
+    client: Mutex<Client>,
+    chunk_size: usize,
+    connect_attempts: u32,
+    connect_base_delay: Duration,
+    write_attempts: u32,
+    spool: Spool,
+    rollups: Option<Rollups>,
+    expiry: Expiry,
+}
+
+impl PostgresDatabase {
+    pub fn connect(url: &str, chunk_size: usize, attempts: u32, base_delay: Duration) -> Self {
+        let client = retry_with_backoff(
+            || Client::connect(url, NoTls),
+            attempts,
+            base_delay,
+            std::thread::sleep,
+        )
+        .expect("Failed to connect to the database");
+        PostgresDatabase {
+            url: url.to_string(),
+            client: Mutex::new(client),
+            chunk_size,
+            connect_attempts: attempts,
+            connect_base_delay: base_delay,
+            write_attempts: DEFAULT_WRITE_ATTEMPTS,
+            spool: Spool::in_memory(DEFAULT_QUEUE_SIZE),
+            rollups: None,
+            expiry: Expiry::default(),
+        }
+    }
+
+    /// See `Database::with_spool`.
+    pub fn with_spool(mut self, spool: Spool, write_attempts: u32) -> Self {
+        self.spool = spool;
+        self.write_attempts = write_attempts;
+        self
+    }
+
+    /// See `Database::with_rollups`.
+    pub fn with_rollups(mut self, rollups: Rollups) -> Self {
+        self.rollups = Some(rollups);
+        self
+    }
+
+    /// Runs `f` with a live client. Unlike the MySQL pool, a single client
+    /// does not recover by itself once the server closed the connection.
+    fn with_client<T>(&self, f: impl FnOnce(&mut Client) -> Result<T, Error>) -> Result<T, Error> {
+        let mut client = self.client.lock().unwrap();
+        if client.is_closed() {
+            debug!("Reconnecting to the database");
+            *client = Client::connect(&self.url, NoTls)?;
+        }
+        f(&mut client)
+    }
+
+    fn write_with_retry(&self, chunk: pb::Chunk) {
+        self.spool.write(
+            chunk,
+            self.write_attempts,
+            self.connect_base_delay,
+            |chunk| self.write_chunk(chunk),
+            is_permanent,
+        );
+    }
+
+    /// Inserts all rows of the chunk with a single statement per table by
+    /// passing every column as an array, all in one transaction.
+    fn write_chunk(&self, chunk: &pb::Chunk) -> Result<(), Error> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            if !chunk.metrics.is_empty() {
+                let (late, in_time): (Vec<_>, Vec<_>) =
+                    chunk.metrics.iter().partition(|row| row.late);
+                let merges = [
+                    (INSERT_METRICS, in_time, None),
+                    (MERGE_METRICS, late, Some(chunk.id as i64)),
+                ];
+                for (query, rows, merged_chunk) in merges {
+                    if !rows.is_empty() {
+                        upsert_metrics(&mut tx, query, &rows, merged_chunk)?;
+                    }
+                }
+
+                if let Some(rollups) = &self.rollups {
+                    refresh_rollups(&mut tx, rollups, &chunk.metrics)?;
+                }
+            }
+
+            write_history(&mut tx, &OWNERS, &chunk.owners)?;
+            write_history(&mut tx, &ATTRIBUTES, &chunk.attributes)?;
+
+            if !chunk.workloads.is_empty() {
+                let environments: Vec<&str> = chunk
+                    .workloads
+                    .iter()
+                    .map(|r| r.environment.as_str())
+                    .collect();
+                let namespaces: Vec<&str> = chunk
+                    .workloads
+                    .iter()
+                    .map(|r| r.namespace.as_str())
+                    .collect();
+                let pods: Vec<&str> = chunk.workloads.iter().map(|r| r.pod.as_str()).collect();
+                let kinds: Vec<&str> = chunk.workloads.iter().map(|r| r.kind.as_str()).collect();
+                let workloads: Vec<&str> = chunk
+                    .workloads
+                    .iter()
+                    .map(|r| r.workload.as_str())
+                    .collect();
+
+                tx.execute(
+                    r"INSERT INTO microworkload (environment, namespace, pod, kind, workload)
+                    SELECT * FROM UNNEST(
+                        $1::varchar[], $2::varchar[], $3::varchar[], $4::varchar[], $5::varchar[])
+                    ON CONFLICT (environment, namespace, pod) DO UPDATE SET
+                    kind = EXCLUDED.kind, workload = EXCLUDED.workload",
+                    &[&environments, &namespaces, &pods, &kinds, &workloads],
+                )?;
+            }
+
+            tx.commit()
+        })
+    }
+}
+
+impl Sink for PostgresDatabase {
+    fn create_tables(&self) {
+        retry_with_backoff(
+            || {
+                self.with_client(|client| {
+                    client.batch_execute(&format!(
+                        r"CREATE TABLE IF NOT EXISTS {} (
+                            version INTEGER PRIMARY KEY,
+                            description VARCHAR(255),
+                            applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+                        )",
+                        VERSION_TABLE
+                    ))?;
+                    // Serializes replicas that start at the same time. Unlike
+                    // MySQL, PostgreSQL runs DDL in transactions, so every
+                    // migration is applied completely or not at all.
+                    client.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK])?;
+                    let result = schema_version(client).and_then(|current| {
+                        migrate(MIGRATIONS, current, |migration| {
+                            let mut transaction = client.transaction()?;
+                            for statement in migration.statements {
+                                transaction.batch_execute(statement)?;
+                            }
+                            transaction.execute(
+                                &format!(
+                                    "INSERT INTO {} (version, description) VALUES ($1, $2)",
+                                    VERSION_TABLE
+                                ),
+                                &[&(migration.version as i32), &migration.description],
+                            )?;
+                            transaction.commit()
+                        })
+                    });
+                    client.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK])?;
+                    result.map(|_| ())
+                })
+            },
+            self.connect_attempts,
+            self.connect_base_delay,
+            std::thread::sleep,
+        )
+        .expect("Failed to migrate schema");
+    }
+
+    fn pending_migrations(&self) -> Vec<&'static Migration> {
+        let current = retry_with_backoff(
+            || self.with_client(schema_version),
+            self.connect_attempts,
+            self.connect_base_delay,
+            std::thread::sleep,
+        )
+        .expect("Failed to read schema version");
+        pending(MIGRATIONS, current)
+    }
+
+    fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>) {
+        info!("Inserting {} metrics into the database", metrics.len());
+        for chunk in metric_chunks(metrics, self.chunk_size) {
+            debug!("Inserting a chunk of {} metrics", chunk.metrics.len());
+            self.write_with_retry(chunk);
+        }
+
+        if let Some(rollups) = &self.rollups
+            && self.expiry.due()
+            && let Err(e) = self.with_client(|client| expire_rollups(client, rollups))
+        {
+            warn!("Failed to delete expired rollups: {}", e);
+        }
+    }
+
+    fn insert_owners(&self, owners: Vec<(OwnerKey, OwnerValue)>) {
+        info!("Inserting {} owners into the database", owners.len());
+        for chunk in owner_chunks(owners, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn insert_attributes(&self, attributes: Vec<(AttributeKey, AttributeValue)>) {
+        info!(
+            "Inserting {} attributes into the database",
+            attributes.len()
+        );
+        for chunk in attribute_chunks(attributes, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn insert_workloads(&self, workloads: Vec<(OwnerKey, Workload)>) {
+        info!("Inserting {} workloads into the database", workloads.len());
+        for chunk in workload_chunks(workloads, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn retry_pending(&self) {
+        self.spool
+            .retry(|chunk| self.write_chunk(chunk), is_permanent);
+    }
+
+    fn sync(&self) -> Result<bool, String> {
+        Ok(self.spool.is_durable())
+    }
+}
+
+/// Inserts metric rows with `query`, passing every column as an array.
+fn upsert_metrics(
+    client: &mut impl GenericClient,
+    query: &str,
+    rows: &[&pb::MetricRow],
+    merged_chunk: Option<i64>,
+) -> Result<(), Error> {
+    let rows: Vec<_> = rows
+        .iter()
+        .filter_map(|row| {
+            chrono::DateTime::from_timestamp_millis(row.timestamp as i64)
+                .map(|timestamp| (timestamp.naive_utc(), row))
+        })
+        .collect();
+    let times: Vec<NaiveDateTime> = rows.iter().map(|(time, _)| *time).collect();
+    let environments: Vec<&str> = rows.iter().map(|(_, r)| r.environment.as_str()).collect();
+    let namespaces: Vec<&str> = rows.iter().map(|(_, r)| r.namespace.as_str()).collect();
+    let pods: Vec<&str> = rows.iter().map(|(_, r)| r.pod.as_str()).collect();
+    let containers: Vec<&str> = rows.iter().map(|(_, r)| r.container.as_str()).collect();
+    let cpu_usage: Vec<Option<f64>> = rows.iter().map(|(_, r)| r.cpu_usage).collect();
+    let cpu_limit: Vec<Option<f64>> = rows.iter().map(|(_, r)| r.cpu_limit).collect();
+    let memory_usage: Vec<Option<i64>> = rows.iter().map(|(_, r)| r.memory_usage_bytes()).collect();
+    let memory_limit: Vec<Option<i64>> = rows.iter().map(|(_, r)| r.memory_limit_bytes()).collect();
+    let cpu_request: Vec<Option<f64>> = rows.iter().map(|(_, r)| r.cpu_request).collect();
+    let memory_request: Vec<Option<i64>> =
+        rows.iter().map(|(_, r)| r.memory_request_bytes()).collect();
+    let cpu_restarts: Vec<Option<i32>> = rows
+        .iter()
+        .map(|(_, r)| r.cpu_restarts.map(|v| v as i32))
+        .collect();
+    let memory_usage_count: Vec<Option<i32>> = rows
+        .iter()
+        .map(|(_, r)| r.memory_usage_count.map(|v| v as i32))
+        .collect();
+    let memory_usage_min: Vec<Option<i64>> = rows
+        .iter()
+        .map(|(_, r)| r.memory_usage_min_bytes())
+        .collect();
+    let memory_usage_max: Vec<Option<i64>> = rows
+        .iter()
+        .map(|(_, r)| r.memory_usage_max_bytes())
+        .collect();
+    let memory_usage_avg: Vec<Option<i64>> = rows
+        .iter()
+        .map(|(_, r)| r.memory_usage_avg_bytes())
+        .collect();
+    let memory_usage_p95: Vec<Option<i64>> = rows
+        .iter()
+        .map(|(_, r)| r.memory_usage_p95_bytes())
+        .collect();
+    let merged_chunk = vec![merged_chunk; rows.len()];
+
+    client.execute(
+        query,
+        &[
+            &times,
+            &environments,
+            &namespaces,
+            &pods,
+            &containers,
+            &cpu_usage,
+            &cpu_limit,
+            &memory_usage,
+            &memory_limit,
+            &cpu_request,
+            &memory_request,
+            &cpu_restarts,
+            &memory_usage_count,
+            &memory_usage_min,
+            &memory_usage_max,
+            &memory_usage_avg,
+            &memory_usage_p95,
+            &merged_chunk,
+        ],
+    )?;
+    Ok(())
+}
+
+/// See the MySQL backend, with one statement per step for all rows.
+fn write_history<'a, T>(
+    client: &mut impl GenericClient,
+    history: &History,
+    rows: &'a [T],
+) -> Result<(), Error>
+where
+    Version<'a>: From<&'a T>,
+{
+    let rows: Vec<_> = rows
+        .iter()
+        .map(Version::from)
+        .filter_map(|version| {
+            chrono::DateTime::from_timestamp_millis(version.valid_from as i64)
+                .map(|valid_from| (valid_from.naive_utc(), version))
+        })
+        .collect();
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut columns: Vec<Vec<&str>> = (0..history.keys.len())
+        .map(|i| rows.iter().map(|(_, version)| version.keys[i]).collect())
+        .collect();
+    columns.push(rows.iter().map(|(_, version)| version.value).collect());
+    let valid_from: Vec<NaiveDateTime> = rows.iter().map(|(valid_from, _)| *valid_from).collect();
+    let mut params: Vec<&(dyn ToSql + Sync)> = columns
+        .iter()
+        .map(|column| column as &(dyn ToSql + Sync))
+        .collect();
+    params.push(&valid_from);
+
+    client.execute(&history.close_unnest(), &params)?;
+    client.execute(&history.open_unnest(), &params)?;
+    Ok(())
+}
+
+/// The columns of the hours or days of a `Refresh`, to pass as arrays.
+type RefreshColumns<'a> = (
+    Vec<NaiveDateTime>,
+    Vec<&'a str>,
+    Vec<&'a str>,
+    Vec<&'a str>,
+    Vec<&'a str>,
+);
+
+fn columns<'a>(keys: &[(NaiveDateTime, Series<'a>)]) -> RefreshColumns<'a> {
+    (
+        keys.iter().map(|(time, _)| *time).collect(),
+        keys.iter().map(|(_, series)| series.environment).collect(),
+        keys.iter().map(|(_, series)| series.namespace).collect(),
+        keys.iter().map(|(_, series)| series.pod).collect(),
+        keys.iter().map(|(_, series)| series.container).collect(),
+    )
+}
+
+/// See the MySQL backend.
+fn refresh_rollups(
+    client: &mut impl GenericClient,
+    rollups: &Rollups,
+    rows: &[pb::MetricRow],
+) -> Result<(), Error> {
+    let refresh = rollups.refresh(rows, chrono::Utc::now());
+
+    if !refresh.hours.is_empty() {
+        let (times, environments, namespaces, pods, containers) = columns(&refresh.hours);
+        client.execute(
+            r"INSERT INTO micrometrics_hourly
+            (time, environment, namespace, pod, container, cpu_usage, cpu_limit_seconds,
+            memory_usage_avg, memory_usage_max, memory_limit_max, samples, memory_samples,
+            cpu_request_seconds, memory_request_max, cpu_restarts)
+            SELECT k.time, m.environment, m.namespace, m.pod, m.container, SUM(m.cpu_usage),
+            SUM(m.cpu_limit) * $6::bigint, AVG(COALESCE(m.memory_usage_avg, m.memory_usage)),
+            MAX(COALESCE(m.memory_usage_max, m.memory_usage)), MAX(m.memory_limit),
+            COUNT(*), COUNT(m.memory_usage), SUM(m.cpu_request) * $6::bigint,
+            MAX(m.memory_request), SUM(m.cpu_restarts)
+            FROM UNNEST($1::timestamp[], $2::varchar[], $3::varchar[], $4::varchar[],
+                $5::varchar[]) AS k (time, environment, namespace, pod, container)
+            JOIN micrometrics m ON m.environment = k.environment AND m.namespace = k.namespace
+            AND m.pod = k.pod AND m.container = k.container
+            AND m.time >= k.time AND m.time < k.time + INTERVAL '1 hour'
+            GROUP BY k.time, m.environment, m.namespace, m.pod, m.container
+            ON CONFLICT (time, environment, namespace, pod, container) DO UPDATE SET
+            cpu_usage = EXCLUDED.cpu_usage,
+            cpu_limit_seconds = EXCLUDED.cpu_limit_seconds,
+            memory_usage_avg = EXCLUDED.memory_usage_avg,
+            memory_usage_max = EXCLUDED.memory_usage_max,
+            memory_limit_max = EXCLUDED.memory_limit_max,
+            samples = EXCLUDED.samples,
+            memory_samples = EXCLUDED.memory_samples,
+            cpu_request_seconds = EXCLUDED.cpu_request_seconds,
+            memory_request_max = EXCLUDED.memory_request_max,
+            cpu_restarts = EXCLUDED.cpu_restarts",
+            &[
+                &times,
+                &environments,
+                &namespaces,
+                &pods,
+                &containers,
+                &(rollups.interval_secs as i64),
+            ],
+        )?;
+    }
+    if !refresh.days.is_empty() {
+        let (times, environments, namespaces, pods, containers) = columns(&refresh.days);
+        client.execute(
+            r"INSERT INTO micrometrics_daily
+            (time, environment, namespace, pod, container, cpu_usage, cpu_limit_seconds,
+            memory_usage_avg, memory_usage_max, memory_limit_max, samples, memory_samples,
+            cpu_request_seconds, memory_request_max, cpu_restarts)
+            SELECT k.time, h.environment, h.namespace, h.pod, h.container, SUM(h.cpu_usage),
+            SUM(h.cpu_limit_seconds),
+            SUM(h.memory_usage_avg * h.memory_samples) / NULLIF(SUM(h.memory_samples), 0),
+            MAX(h.memory_usage_max), MAX(h.memory_limit_max), SUM(h.samples),
+            SUM(h.memory_samples), SUM(h.cpu_request_seconds), MAX(h.memory_request_max),
+            SUM(h.cpu_restarts)
+            FROM UNNEST($1::timestamp[], $2::varchar[], $3::varchar[], $4::varchar[],
+                $5::varchar[]) AS k (time, environment, namespace, pod, container)
+            JOIN micrometrics_hourly h ON h.environment = k.environment
+            AND h.namespace = k.namespace AND h.pod = k.pod AND h.container = k.container
+            AND h.time >= k.time AND h.time < k.time + INTERVAL '1 day'
+            GROUP BY k.time, h.environment, h.namespace, h.pod, h.container
+            ON CONFLICT (time, environment, namespace, pod, container) DO UPDATE SET
+            cpu_usage = EXCLUDED.cpu_usage,
+            cpu_limit_seconds = EXCLUDED.cpu_limit_seconds,
+            memory_usage_avg = EXCLUDED.memory_usage_avg,
+            memory_usage_max = EXCLUDED.memory_usage_max,
+            memory_limit_max = EXCLUDED.memory_limit_max,
+            samples = EXCLUDED.samples,
+            memory_samples = EXCLUDED.memory_samples,
+            cpu_request_seconds = EXCLUDED.cpu_request_seconds,
+            memory_request_max = EXCLUDED.memory_request_max,
+            cpu_restarts = EXCLUDED.cpu_restarts",
+            &[&times, &environments, &namespaces, &pods, &containers],
+        )?;
+    }
+    Ok(())
+}
+
+fn expire_rollups(client: &mut Client, rollups: &Rollups) -> Result<(), Error> {
+    let (hourly, daily) = rollups.cutoffs(chrono::Utc::now());
+    for (table, cutoff) in [
+        ("micrometrics_hourly", hourly),
+        ("micrometrics_daily", daily),
+    ] {
+        if let Some(cutoff) = cutoff {
+            client.execute(
+                &format!("DELETE FROM {} WHERE time < $1", table),
+                &[&cutoff],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The latest applied migration, or 0 for a database that has never been
+/// migrated.
+fn schema_version(client: &mut Client) -> Result<u32, Error> {
+    let row = client.query_one(
+        &format!(
+            "SELECT CASE WHEN to_regclass('{0}') IS NULL THEN 0
+            ELSE (SELECT COALESCE(MAX(version), 0) FROM {0}) END",
+            VERSION_TABLE
+        ),
+        &[],
+    )?;
+    Ok(row.get::<_, i32>(0) as u32)
+}
+
+/// Data exceptions (class 22), integrity constraint violations (23) and
+/// program limits (54) fail again no matter how often the rows are sent.
+fn is_permanent(e: &Error) -> bool {
+    e.code()
+        .is_some_and(|state| matches!(&state.code()[..2], "22" | "23" | "54"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_errors_are_not_permanent() {
+        // Nothing listens on port 1, so this fails without a server response.
+        match Client::connect("postgres://user@127.0.0.1:1/db", NoTls) {
+            Ok(_) => panic!("connected to a port without a server"),
+            Err(e) => assert!(!is_permanent(&e)),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use crate::prometheus::Label;
+use regex::Regex;
+use serde::Deserialize;
+use std::fs::File;
+use std::path::Path;
+
+/// What a relabeling rule does, with the semantics of Prometheus'
+/// `relabel_configs`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Sets `target_label` to `replacement` if `regex` matches the source value.
+    #[default]
+    Replace,
+    /// Drops the series unless `regex` matches the source value.
+    Keep,
+    /// Drops the series if `regex` matches the source value.
+    Drop,
+    /// Sets `target_label` to the hash of the source value modulo `modulus`.
+    HashMod,
+    /// Copies the labels whose names match `regex` to the names given by
+    /// `replacement`.
+    LabelMap,
+    /// Removes the labels whose names match `regex`.
+    LabelDrop,
+    /// Removes the labels whose names do not match `regex`.
+    LabelKeep,
+}
+
+/// A rule as written in the configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    #[serde(default)]
+    source_labels: Vec<String>,
+    #[serde(default = "default_separator")]
+    separator: String,
+    target_label: Option<String>,
+    #[serde(default = "default_regex")]
+    regex: String,
+    modulus: Option<u64>,
+    #[serde(default = "default_replacement")]
+    replacement: String,
+    #[serde(default)]
+    action: Action,
+}
+
+fn default_separator() -> String {
+    ";".to_string()
+}
+
+fn default_regex() -> String {
+    "(.*)".to_string()
+}
+
+fn default_replacement() -> String {
+    "$1".to_string()
+}
+
+/// One relabeling step. The regular expression is anchored at both ends, as
+/// in Prometheus.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RuleConfig")]
+pub struct Rule {
+    source_labels: Vec<String>,
+    separator: String,
+    target_label: String,
+    regex: Regex,
+    modulus: u64,
+    replacement: String,
+    action: Action,
+}
+
+impl TryFrom<RuleConfig> for Rule {
+    type Error = String;
+
+    fn try_from(config: RuleConfig) -> Result<Self, Self::Error> {
+        let regex = Regex::new(&format!("^(?:{})$", config.regex))
+            .map_err(|e| format!("Invalid regex {:?}: {}", config.regex, e))?;
+        if matches!(config.action, Action::Replace | Action::HashMod)
+            && config.target_label.is_none()
+        {
+            return Err(format!("{:?} requires a target_label", config.action));
+        }
+        if config.action == Action::HashMod && config.modulus.unwrap_or(0) == 0 {
+            return Err("hashmod requires a non-zero modulus".to_string());
+        }
+        Ok(Rule {
+            source_labels: config.source_labels,
+            separator: config.separator,
+            target_label: config.target_label.unwrap_or_default(),
+            regex,
+            modulus: config.modulus.unwrap_or(0),
+            replacement: config.replacement,
+            action: config.action,
+        })
+    }
+}
+
+fn get<'a>(labels: &'a [Label], name: &str) -> Option<&'a str> {
+    labels
+        .iter()
+        .find(|label| label.name == name)
+        .map(|label| label.value.as_str())
+}
+
+/// Sets a label, or removes it if `value` is empty.
+fn set(labels: &mut Vec<Label>, name: &str, value: String) {
+    if value.is_empty() {
+        labels.retain(|label| label.name != name);
+    } else if let Some(label) = labels.iter_mut().find(|label| label.name == name) {
+        label.value = value;
+    } else {
+        labels.push(Label {
+            name: name.to_string(),
+            value,
+        });
+    }
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The hash that Prometheus uses for `hashmod`: the last eight bytes of the
+/// MD5 digest, big-endian.
+fn sum64(value: &str) -> u64 {
+    let digest = md5::compute(value.as_bytes());
+    u64::from_be_bytes(digest[8..].try_into().unwrap())
+}
+
+impl Rule {
+    /// Applies the rule to `labels`. Returns `false` if the series is to be
+    /// dropped.
+    fn apply(&self, labels: &mut Vec<Label>) -> bool {
+        let value = || {
+            self.source_labels
+                .iter()
+                .map(|name| get(labels, name).unwrap_or(""))
+                .collect::<Vec<_>>()
+                .join(&self.separator)
+        };
+
+        match self.action {
+            Action::Keep => return self.regex.is_match(&value()),
+            Action::Drop => return !self.regex.is_match(&value()),
+            Action::Replace => {
+                let value = value();
+                if let Some(captures) = self.regex.captures(&value) {
+                    let mut target = String::new();
+                    captures.expand(&self.target_label, &mut target);
+                    if is_valid_label_name(&target) {
+                        let mut replaced = String::new();
+                        captures.expand(&self.replacement, &mut replaced);
+                        set(labels, &target, replaced);
+                    }
+                }
+            }
+            Action::HashMod => {
+                let hash = sum64(&value()) % self.modulus;
+                set(labels, &self.target_label, hash.to_string());
+            }
+            Action::LabelMap => {
+                let mapped: Vec<_> = labels
+                    .iter()
+                    .filter(|label| self.regex.is_match(&label.name))
+                    .map(|label| {
+                        (
+                            self.regex
+                                .replace_all(&label.name, self.replacement.as_str())
+                                .into_owned(),
+                            label.value.clone(),
+                        )
+                    })
+                    .collect();
+                for (name, value) in mapped {
+                    set(labels, &name, value);
+                }
+            }
+            Action::LabelDrop => labels.retain(|label| !self.regex.is_match(&label.name)),
+            Action::LabelKeep => labels.retain(|label| self.regex.is_match(&label.name)),
+        }
+        true
+    }
+}
+
+/// A list of relabeling rules that are applied to every incoming series in
+/// order, before the labels are mapped to columns.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Relabeler {
+    rules: Vec<Rule>,
+}
+
+impl Relabeler {
+    /// Reads the rules from a YAML file that contains a list in the format of
+    /// Prometheus' `relabel_configs`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_yaml::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Returns the relabeled labels, or `None` if the series is dropped.
+    pub fn relabel(&self, mut labels: Vec<Label>) -> Option<Vec<Label>> {
+        for rule in &self.rules {
+            if !rule.apply(&mut labels) {
+                return None;
+            }
+        }
+        Some(labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relabeler(yaml: &str) -> Relabeler {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<Label> {
+        pairs
+            .iter()
+            .map(|(name, value)| Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_keep_and_drop() {
+        let relabeler = relabeler(
+            "- source_labels: [__name__]\n  regex: container_.*|kube_pod_.*\n  action: keep\n\
+             - source_labels: [namespace, pod]\n  regex: kube-system;.*\n  action: drop\n",
+        );
+        let series = |name, namespace| labels(&[("__name__", name), ("namespace", namespace)]);
+
+        assert!(
+            relabeler
+                .relabel(series("container_cpu", "default"))
+                .is_some()
+        );
+        assert!(relabeler.relabel(series("node_cpu", "default")).is_none());
+        assert!(
+            relabeler
+                .relabel(series("kube_pod_labels", "kube-system"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_replace() {
+        let relabeler = relabeler(
+            "- source_labels: [k8s_cluster]\n  regex: 'prod-(.+)'\n  target_label: cluster\n  \
+             replacement: '${1}'\n\
+             - source_labels: [stage]\n  target_label: obsolete\n",
+        );
+
+        let relabeled = relabeler
+            .relabel(labels(&[("k8s_cluster", "prod-eu"), ("obsolete", "x")]))
+            .unwrap();
+        assert_eq!(
+            relabeled,
+            labels(&[("k8s_cluster", "prod-eu"), ("cluster", "eu")])
+        );
+
+        let unmatched = labels(&[("k8s_cluster", "test-eu")]);
+        assert_eq!(relabeler.relabel(unmatched.clone()), Some(unmatched));
+    }
+
+    #[test]
+    fn test_hashmod() {
+        let relabeler = relabeler(
+            "- source_labels: [pod]\n  target_label: shard\n  modulus: 1000\n  action: hashmod\n",
+        );
+        let relabeled = relabeler.relabel(labels(&[("pod", "a")])).unwrap();
+        // md5("a") = 0cc175b9c0f1b6a831c399e269772661
+        assert_eq!(
+            get(&relabeled, "shard"),
+            Some((0x31c399e269772661u64 % 1000).to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn test_labelmap_and_labeldrop() {
+        let relabeler = relabeler(
+            "- regex: 'label_(.+)'\n  action: labelmap\n\
+             - regex: 'label_.*'\n  action: labeldrop\n",
+        );
+        let relabeled = relabeler
+            .relabel(labels(&[("pod", "p"), ("label_owner", "a-team")]))
+            .unwrap();
+        assert_eq!(relabeled, labels(&[("pod", "p"), ("owner", "a-team")]));
+    }
+
+    #[test]
+    fn test_labelkeep() {
+        let relabeler = relabeler("- regex: '__name__|pod'\n  action: labelkeep\n");
+        let relabeled = relabeler
+            .relabel(labels(&[("__name__", "m"), ("pod", "p"), ("id", "1")]))
+            .unwrap();
+        assert_eq!(relabeled, labels(&[("__name__", "m"), ("pod", "p")]));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let parse = |yaml| serde_yaml::from_str::<Relabeler>(yaml);
+        assert!(parse("- source_labels: [a]\n").is_err());
+        assert!(parse("- target_label: a\n  action: hashmod\n").is_err());
+        assert!(parse("- regex: '('\n  action: drop\n").is_err());
+        assert!(parse("- action: lowercase\n").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::spool::pb;
+use chrono::{DateTime, Days, NaiveDateTime, Timelike, Utc};
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Days of hourly rollups kept by default.
+pub const DEFAULT_HOURLY_RETENTION_DAYS: u32 = 90;
+/// Days of daily rollups kept by default.
+pub const DEFAULT_DAILY_RETENTION_DAYS: u32 = 730;
+/// Wait between two deletions of expired rollups.
+pub const EXPIRY_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Settings for `micrometrics_hourly` and `micrometrics_daily`. Rather than
+/// adding up deltas, which would count rows twice when a chunk is retried,
+/// every write recomputes the hours of the containers it touched from
+/// `micrometrics`, and their days from `micrometrics_hourly`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rollups {
+    /// Length of a bucket in `micrometrics`, which turns CPU limits in cores
+    /// into CPU seconds.
+    pub interval_secs: u64,
+    /// Days after which rollups are deleted, or `None` to keep them.
+    pub hourly_retention_days: Option<u32>,
+    pub daily_retention_days: Option<u32>,
+}
+
+impl Rollups {
+    pub fn new(interval_secs: u64) -> Self {
+        Rollups {
+            interval_secs,
+            hourly_retention_days: Some(DEFAULT_HOURLY_RETENTION_DAYS),
+            daily_retention_days: Some(DEFAULT_DAILY_RETENTION_DAYS),
+        }
+    }
+
+    /// Rollups from before these times are to be deleted, as of `now`.
+    pub fn cutoffs(&self, now: DateTime<Utc>) -> (Option<NaiveDateTime>, Option<NaiveDateTime>) {
+        let cutoff = |days: Option<u32>| {
+            days.map(|days| {
+                (now.date_naive() - Days::new(days as u64))
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
+            })
+        };
+        (
+            cutoff(self.hourly_retention_days),
+            cutoff(self.daily_retention_days),
+        )
+    }
+}
+
+/// A container, the unit that rollups are kept for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Series<'a> {
+    pub environment: &'a str,
+    pub namespace: &'a str,
+    pub pod: &'a str,
+    pub container: &'a str,
+}
+
+impl<'a> From<&'a pb::MetricRow> for Series<'a> {
+    fn from(row: &'a pb::MetricRow) -> Self {
+        Series {
+            environment: &row.environment,
+            namespace: &row.namespace,
+            pod: &row.pod,
+            container: &row.container,
+        }
+    }
+}
+
+/// The rollups that a write changes, in ascending order.
+#[derive(Debug, Default, PartialEq)]
+pub struct Refresh<'a> {
+    /// Starts of the hours of every container that the write has rows for.
+    pub hours: Vec<(NaiveDateTime, Series<'a>)>,
+    /// Starts of the days of those hours.
+    pub days: Vec<(NaiveDateTime, Series<'a>)>,
+}
+
+impl Rollups {
+    /// The rollups to recompute for `rows`, as of `now`. Rollups past their
+    /// retention are left out, and with them the days whose hours are past
+    /// theirs, which could not be recomputed from the hours that are left.
+    pub fn refresh<'a>(&self, rows: &'a [pb::MetricRow], now: DateTime<Utc>) -> Refresh<'a> {
+        let (hourly, daily) = self.cutoffs(now);
+        let kept = |cutoff: Option<NaiveDateTime>, time: &NaiveDateTime| {
+            cutoff.is_none_or(|cutoff| *time >= cutoff)
+        };
+        // The cutoffs are at midnight, so either all hours of a day are
+        // kept or none.
+        let hours: BTreeSet<_> = rows
+            .iter()
+            .filter_map(|row| {
+                let time = DateTime::from_timestamp_millis(row.timestamp as i64)?;
+                let hour = time.date_naive().and_hms_opt(time.hour(), 0, 0).unwrap();
+                Some((hour, Series::from(row)))
+            })
+            .filter(|(hour, _)| kept(hourly, hour))
+            .collect();
+        let days: BTreeSet<_> = hours
+            .iter()
+            .map(|(hour, series)| (hour.date().and_hms_opt(0, 0, 0).unwrap(), *series))
+            .filter(|(day, _)| kept(daily, day))
+            .collect();
+        Refresh {
+            hours: hours.into_iter().collect(),
+            days: days.into_iter().collect(),
+        }
+    }
+}
+
+/// Deletes expired rollups once per `EXPIRY_PERIOD` rather than with every
+/// write.
+#[derive(Debug, Default)]
+pub struct Expiry {
+    last: Mutex<Option<Instant>>,
+}
+
+impl Expiry {
+    /// Whether expired rollups are to be deleted now. If so, the next
+    /// deletion is due after another `EXPIRY_PERIOD`.
+    pub fn due(&self) -> bool {
+        let mut last = self.last.lock().unwrap();
+        if last.is_some_and(|last| last.elapsed() < EXPIRY_PERIOD) {
+            return false;
+        }
+        *last = Some(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(timestamp: u64, pod: &str) -> pb::MetricRow {
+        pb::MetricRow {
+            timestamp,
+            pod: pod.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn series(pod: &str) -> Series<'_> {
+        Series {
+            environment: "",
+            namespace: "",
+            pod,
+            container: "",
+        }
+    }
+
+    #[test]
+    fn test_refresh_touches_the_hours_and_days_of_its_rows() {
+        let rollups = Rollups {
+            interval_secs: 60,
+            hourly_retention_days: Some(1),
+            daily_retention_days: None,
+        };
+        let now = DateTime::parse_from_rfc3339("1970-01-03T10:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let hour = 60 * 60 * 1000;
+        let rows = [
+            row(23 * hour + 60_000, "a"),
+            row(23 * hour, "b"),
+            row(24 * hour + 120_000, "a"),
+            row(25 * hour - 1, "a"),
+            row(48 * hour, "a"),
+        ];
+
+        // The first day is past the hourly retention.
+        assert_eq!(
+            rollups.refresh(&rows, now),
+            Refresh {
+                hours: vec![
+                    (time("1970-01-02 00:00:00"), series("a")),
+                    (time("1970-01-03 00:00:00"), series("a")),
+                ],
+                days: vec![
+                    (time("1970-01-02 00:00:00"), series("a")),
+                    (time("1970-01-03 00:00:00"), series("a")),
+                ],
+            }
+        );
+
+        let rollups = Rollups {
+            hourly_retention_days: None,
+            ..rollups
+        };
+        let refresh = rollups.refresh(&rows, now);
+        assert_eq!(
+            refresh.hours[..2],
+            [
+                (time("1970-01-01 23:00:00"), series("a")),
+                (time("1970-01-01 23:00:00"), series("b")),
+            ]
+        );
+        assert_eq!(refresh.days.len(), 4);
+    }
+
+    #[test]
+    fn test_expiry_is_due_once_per_period() {
+        let expiry = Expiry::default();
+        assert!(expiry.due());
+        assert!(!expiry.due());
+    }
+
+    #[test]
+    fn test_cutoffs() {
+        let rollups = Rollups {
+            interval_secs: 60,
+            hourly_retention_days: Some(2),
+            daily_retention_days: None,
+        };
+        let now = DateTime::parse_from_rfc3339("2024-07-08T10:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            rollups.cutoffs(now),
+            (Some(time("2024-07-06 00:00:00")), None)
+        );
+    }
+}
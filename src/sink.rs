@@ -0,0 +1,206 @@
+use crate::metrics_buffer::{Key, Metrics};
+use crate::migrations::Migration;
+use crate::owner_buffer::{AttributeKey, AttributeValue, OwnerKey, OwnerValue, Workload};
+
+/// A destination for flushed rows. Writes are fire-and-forget for the caller:
+/// a sink deals with its own failures, e.g., by retrying or spooling them.
+pub trait Sink: Send + Sync {
+    /// Prepares the destination for the rows below, applying all pending
+    /// migrations. Called once at startup.
+    fn create_tables(&self);
+
+    /// The migrations that `create_tables` would apply.
+    fn pending_migrations(&self) -> Vec<&'static Migration> {
+        Vec::new()
+    }
+
+    fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>);
+
+    fn insert_owners(&self, owners: Vec<(OwnerKey, OwnerValue)>);
+
+    fn insert_attributes(&self, attributes: Vec<(AttributeKey, AttributeValue)>);
+
+    fn insert_workloads(&self, workloads: Vec<(OwnerKey, Workload)>);
+
+    /// Writes rows that failed earlier. Called before every flush.
+    fn retry_pending(&self) {}
+
+    /// Tells whether every row handed over so far would survive a restart,
+    /// so that the write-ahead log no longer needs it. Called after every
+    /// flush; while it is false or fails, the log is kept.
+    fn sync(&self) -> Result<bool, String> {
+        Ok(true)
+    }
+}
+
+impl<S: Sink + ?Sized> Sink for Box<S> {
+    fn create_tables(&self) {
+        (**self).create_tables();
+    }
+
+    fn pending_migrations(&self) -> Vec<&'static Migration> {
+        (**self).pending_migrations()
+    }
+
+    fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>) {
+        (**self).insert_metrics(metrics);
+    }
+
+    fn insert_owners(&self, owners: Vec<(OwnerKey, OwnerValue)>) {
+        (**self).insert_owners(owners);
+    }
+
+    fn insert_attributes(&self, attributes: Vec<(AttributeKey, AttributeValue)>) {
+        (**self).insert_attributes(attributes);
+    }
+
+    fn insert_workloads(&self, workloads: Vec<(OwnerKey, Workload)>) {
+        (**self).insert_workloads(workloads);
+    }
+
+    fn retry_pending(&self) {
+        (**self).retry_pending();
+    }
+
+    fn sync(&self) -> Result<bool, String> {
+        (**self).sync()
+    }
+}
+
+/// Writes every row to all of its sinks.
+pub struct FanOut {
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl FanOut {
+    pub fn new(sinks: Vec<Box<dyn Sink>>) -> Self {
+        FanOut { sinks }
+    }
+}
+
+impl Sink for FanOut {
+    fn create_tables(&self) {
+        for sink in &self.sinks {
+            sink.create_tables();
+        }
+    }
+
+    fn pending_migrations(&self) -> Vec<&'static Migration> {
+        self.sinks
+            .iter()
+            .flat_map(|sink| sink.pending_migrations())
+            .collect()
+    }
+
+    fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>) {
+        for sink in &self.sinks {
+            sink.insert_metrics(metrics.clone());
+        }
+    }
+
+    fn insert_owners(&self, owners: Vec<(OwnerKey, OwnerValue)>) {
+        for sink in &self.sinks {
+            sink.insert_owners(owners.clone());
+        }
+    }
+
+    fn insert_attributes(&self, attributes: Vec<(AttributeKey, AttributeValue)>) {
+        for sink in &self.sinks {
+            sink.insert_attributes(attributes.clone());
+        }
+    }
+
+    fn insert_workloads(&self, workloads: Vec<(OwnerKey, Workload)>) {
+        for sink in &self.sinks {
+            sink.insert_workloads(workloads.clone());
+        }
+    }
+
+    fn retry_pending(&self) {
+        for sink in &self.sinks {
+            sink.retry_pending();
+        }
+    }
+
+    /// Syncs every sink, even after one of them failed.
+    fn sync(&self) -> Result<bool, String> {
+        let mut durable = true;
+        let mut errors = Vec::new();
+        for sink in &self.sinks {
+            match sink.sync() {
+                Ok(synced) => durable &= synced,
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(durable)
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+/// Sinks for tests, also available to integration tests and other crates
+/// through the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps everything in memory, for tests that need no database.
+    #[derive(Default, Clone)]
+    pub struct MemorySink {
+        pub metrics: Arc<Mutex<Vec<(Key, Metrics)>>>,
+        pub owners: Arc<Mutex<Vec<(OwnerKey, OwnerValue)>>>,
+        pub attributes: Arc<Mutex<Vec<(AttributeKey, AttributeValue)>>>,
+        pub workloads: Arc<Mutex<Vec<(OwnerKey, Workload)>>>,
+    }
+
+    impl Sink for MemorySink {
+        fn create_tables(&self) {}
+
+        fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>) {
+            self.metrics.lock().unwrap().extend(metrics);
+        }
+
+        fn insert_owners(&self, owners: Vec<(OwnerKey, OwnerValue)>) {
+            self.owners.lock().unwrap().extend(owners);
+        }
+
+        fn insert_attributes(&self, attributes: Vec<(AttributeKey, AttributeValue)>) {
+            self.attributes.lock().unwrap().extend(attributes);
+        }
+
+        fn insert_workloads(&self, workloads: Vec<(OwnerKey, Workload)>) {
+            self.workloads.lock().unwrap().extend(workloads);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::MemorySink;
+    use super::*;
+
+    #[test]
+    fn test_fan_out_writes_to_every_sink() {
+        let first = MemorySink::default();
+        let second = MemorySink::default();
+        let fan_out = FanOut::new(vec![Box::new(first.clone()), Box::new(second.clone())]);
+
+        fan_out.insert_owners(vec![(
+            OwnerKey {
+                environment: "env1".to_string(),
+                namespace: "ns1".to_string(),
+                pod: "pod1".to_string(),
+            },
+            OwnerValue {
+                owner: "team-a".to_string(),
+                valid_from: 60_000,
+            },
+        )]);
+
+        assert_eq!(first.owners.lock().unwrap().len(), 1);
+        assert_eq!(second.owners.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,726 @@
+use crate::database::retry_with_backoff;
+use crate::durable;
+use crate::metrics_buffer::{Key, Metrics};
+use crate::owner_buffer::{AttributeKey, AttributeValue, OwnerKey, OwnerValue, Workload};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use prometheus::IntCounter;
+use prost::Message;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub mod pb;
+
+/// Rows that were dropped because the retry queue was full and no spool
+/// directory is configured.
+pub static DROPPED_ROWS: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new(
+        "microinsight_dropped_rows_total",
+        "Rows dropped from the full retry queue for lack of a spool directory",
+    )
+    .unwrap()
+});
+
+/// Number of chunks kept in memory before further chunks go to disk.
+pub const DEFAULT_QUEUE_SIZE: usize = 100;
+const CHUNK_EXTENSION: &str = "chunk";
+const DEAD_LETTER_FILE: &str = "dead-letter.tsv";
+
+/// Result of one attempt to write a pending chunk.
+pub enum Outcome {
+    Written,
+    /// The database could not be reached; keep the chunk for later.
+    Failed,
+    /// The database will never accept the chunk, for the given reason.
+    Rejected(String),
+}
+
+/// Next id of a metric chunk. It starts at the time of the start, in
+/// nanoseconds, so that ids are not reused after a restart.
+static NEXT_CHUNK_ID: Lazy<AtomicU64> = Lazy::new(|| {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    AtomicU64::new(now.as_nanos() as u64)
+});
+
+/// Splits flushed metrics into chunks of at most `chunk_size` rows. Containers
+/// without any limits or requests are left out, unless the rows are merged
+/// into stored ones.
+pub fn metric_chunks(metrics: Vec<(Key, Metrics)>, chunk_size: usize) -> Vec<pb::Chunk> {
+    let rows: Vec<_> = metrics
+        .into_iter()
+        .filter(|(_, metrics)| {
+            metrics.cpu_limit.is_some()
+                || metrics.memory_limit.is_some()
+                || metrics.cpu_request.is_some()
+                || metrics.memory_request.is_some()
+                || metrics.late
+        })
+        .map(|(key, metrics)| pb::MetricRow {
+            timestamp: key.timestamp,
+            environment: key.environment,
+            namespace: key.namespace,
+            pod: key.pod,
+            container: key.container,
+            cpu_usage: metrics.cpu_usage,
+            cpu_restarts: metrics.cpu_restarts,
+            cpu_limit: metrics.cpu_limit,
+            memory_usage: metrics.memory_usage,
+            memory_usage_count: metrics.memory_usage_stats.count(),
+            memory_usage_min: metrics.memory_usage_stats.min(),
+            memory_usage_max: metrics.memory_usage_stats.max(),
+            memory_usage_avg: metrics.memory_usage_stats.avg(),
+            memory_usage_p95: metrics.memory_usage_stats.p95(),
+            memory_limit: metrics.memory_limit,
+            cpu_request: metrics.cpu_request,
+            memory_request: metrics.memory_request,
+            late: metrics.late,
+        })
+        .collect();
+
+    rows.chunks(chunk_size.max(1))
+        .map(|chunk| pb::Chunk {
+            metrics: chunk.to_vec(),
+            id: NEXT_CHUNK_ID.fetch_add(1, Ordering::Relaxed),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Splits flushed owners into chunks of at most `chunk_size` rows.
+pub fn owner_chunks(owners: Vec<(OwnerKey, OwnerValue)>, chunk_size: usize) -> Vec<pb::Chunk> {
+    let rows: Vec<_> = owners
+        .into_iter()
+        .map(|(key, value)| pb::OwnerRow {
+            environment: key.environment,
+            namespace: key.namespace,
+            pod: key.pod,
+            owner: value.owner,
+            valid_from: value.valid_from,
+        })
+        .collect();
+
+    rows.chunks(chunk_size.max(1))
+        .map(|chunk| pb::Chunk {
+            owners: chunk.to_vec(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Splits flushed attributes into chunks of at most `chunk_size` rows.
+pub fn attribute_chunks(
+    attributes: Vec<(AttributeKey, AttributeValue)>,
+    chunk_size: usize,
+) -> Vec<pb::Chunk> {
+    let rows: Vec<_> = attributes
+        .into_iter()
+        .map(|(key, value)| pb::AttributeRow {
+            environment: key.environment,
+            namespace: key.namespace,
+            pod: key.pod,
+            attribute: key.name,
+            value: value.value,
+            valid_from: value.valid_from,
+        })
+        .collect();
+
+    rows.chunks(chunk_size.max(1))
+        .map(|chunk| pb::Chunk {
+            attributes: chunk.to_vec(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Splits flushed workloads into chunks of at most `chunk_size` rows.
+pub fn workload_chunks(workloads: Vec<(OwnerKey, Workload)>, chunk_size: usize) -> Vec<pb::Chunk> {
+    let rows: Vec<_> = workloads
+        .into_iter()
+        .map(|(key, workload)| pb::WorkloadRow {
+            environment: key.environment,
+            namespace: key.namespace,
+            pod: key.pod,
+            kind: workload.kind,
+            workload: workload.name,
+        })
+        .collect();
+
+    rows.chunks(chunk_size.max(1))
+        .map(|chunk| pb::Chunk {
+            workloads: chunk.to_vec(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn rows(chunk: &pb::Chunk) -> usize {
+    chunk.metrics.len() + chunk.owners.len() + chunk.attributes.len() + chunk.workloads.len()
+}
+
+/// Splits a chunk into two with half of its rows each.
+fn split(mut chunk: pb::Chunk) -> (pb::Chunk, pb::Chunk) {
+    fn take<T>(rows: &mut Vec<T>, count: &mut usize) -> Vec<T> {
+        let taken = (*count).min(rows.len());
+        *count -= taken;
+        rows.drain(..taken).collect()
+    }
+
+    let mut count = rows(&chunk) / 2;
+    let head = pb::Chunk {
+        metrics: take(&mut chunk.metrics, &mut count),
+        owners: take(&mut chunk.owners, &mut count),
+        attributes: take(&mut chunk.attributes, &mut count),
+        workloads: take(&mut chunk.workloads, &mut count),
+        id: chunk.id,
+    };
+    (head, chunk)
+}
+
+struct State {
+    queue: VecDeque<pb::Chunk>,
+    /// Ids of the chunk files in the spool directory, oldest first.
+    spooled: VecDeque<u64>,
+    next_id: u64,
+}
+
+/// Holds chunks that could not be written to the database. Up to `capacity`
+/// chunks are queued in memory, further ones are spilled to the spool
+/// directory so that a longer outage does not exhaust the memory. Rows that
+/// the database rejects for good end up in a dead-letter file.
+pub struct Spool {
+    capacity: usize,
+    dir: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl Spool {
+    /// A spool without a directory. Once `capacity` chunks are queued, the
+    /// oldest ones are dropped to make room, and rejected rows can only be
+    /// logged.
+    pub fn in_memory(capacity: usize) -> Self {
+        Spool {
+            capacity,
+            dir: None,
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                spooled: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Picks up the chunks left in `dir` by a previous run.
+    pub fn open(capacity: usize, dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(CHUNK_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        if !ids.is_empty() {
+            info!("Found {} spooled chunks in {:?}", ids.len(), dir);
+        }
+
+        let next_id = ids.last().map_or(0, |id| id + 1);
+        Ok(Spool {
+            capacity,
+            dir: Some(dir),
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                spooled: ids.into(),
+                next_id,
+            }),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.queue.is_empty() && state.spooled.is_empty()
+    }
+
+    /// Whether every chunk is written or on disk, i.e., none would be lost
+    /// by a restart.
+    pub fn is_durable(&self) -> bool {
+        self.state.lock().unwrap().queue.is_empty()
+    }
+
+    fn chunk_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:020}.{}", id, CHUNK_EXTENSION))
+    }
+
+    pub fn push(&self, chunk: pb::Chunk) {
+        let mut state = self.state.lock().unwrap();
+        if state.queue.len() < self.capacity {
+            state.queue.push_back(chunk);
+            return;
+        }
+
+        let Some(dir) = &self.dir else {
+            // Rather than running out of memory during a long outage, give up
+            // on the oldest rows.
+            if let Some(oldest) = state.queue.pop_front() {
+                warn!(
+                    "Retry queue is full and no spool directory is configured, dropping {} rows",
+                    rows(&oldest)
+                );
+                DROPPED_ROWS.inc_by(rows(&oldest) as u64);
+            }
+            state.queue.push_back(chunk);
+            return;
+        };
+
+        let id = state.next_id;
+        let path = Self::chunk_path(dir, id);
+        // The write-ahead log lets go of the rows once they are spooled, so
+        // the chunk has to be on disk for good, and never partially.
+        match durable::replace(&path, &chunk.encode_to_vec()) {
+            Ok(()) => {
+                state.next_id += 1;
+                state.spooled.push_back(id);
+            }
+            Err(e) => {
+                drop(state);
+                error!("Failed to spool chunk to {:?}: {}", path, e);
+                self.dead_letter(&chunk, &format!("spooling failed: {}", e));
+            }
+        }
+    }
+
+    /// Hands the pending chunks to `write`, oldest first, until one of them
+    /// fails. Rejected chunks are moved to the dead-letter file.
+    pub fn drain<F>(&self, mut write: F)
+    where
+        F: FnMut(&pb::Chunk) -> Outcome,
+    {
+        loop {
+            let Some(chunk) = self.state.lock().unwrap().queue.pop_front() else {
+                break;
+            };
+            match write(&chunk) {
+                Outcome::Written => {}
+                Outcome::Rejected(reason) => self.dead_letter(&chunk, &reason),
+                Outcome::Failed => {
+                    self.state.lock().unwrap().queue.push_front(chunk);
+                    return;
+                }
+            }
+        }
+
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        loop {
+            let Some(id) = self.state.lock().unwrap().spooled.front().copied() else {
+                break;
+            };
+            let path = Self::chunk_path(dir, id);
+            let chunk = match fs::read(&path).map(|bytes| pb::Chunk::decode(bytes.as_slice())) {
+                Ok(Ok(chunk)) => Some(chunk),
+                Ok(Err(e)) => {
+                    error!("Spooled chunk {:?} cannot be decoded: {}", path, e);
+                    None
+                }
+                Err(e) => {
+                    error!("Spooled chunk {:?} cannot be read: {}", path, e);
+                    None
+                }
+            };
+
+            if let Some(chunk) = chunk {
+                match write(&chunk) {
+                    Outcome::Written => {}
+                    Outcome::Rejected(reason) => self.dead_letter(&chunk, &reason),
+                    Outcome::Failed => return,
+                }
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Failed to remove spooled chunk {:?}: {}", path, e);
+                }
+            } else {
+                // Keep the file for inspection, but out of the way.
+                let _ = fs::rename(&path, path.with_extension("corrupt"));
+            }
+            self.state.lock().unwrap().spooled.pop_front();
+        }
+    }
+
+    /// Sends `chunk` up to `attempts` times, backing off like the initial
+    /// connection, then keeps it for a later `retry`. Rows that can never
+    /// succeed go to the dead-letter file right away.
+    pub fn write<E, W, P>(
+        &self,
+        chunk: pb::Chunk,
+        attempts: u32,
+        base_delay: Duration,
+        write: W,
+        is_permanent: P,
+    ) where
+        E: Display,
+        W: Fn(&pb::Chunk) -> Result<(), E>,
+        P: Fn(&E) -> bool,
+    {
+        let result = retry_with_backoff(
+            // A permanent error ends the retries by counting as a success.
+            || match write(&chunk) {
+                Err(e) if is_permanent(&e) => Ok(Err(e)),
+                result => result.map(Ok),
+            },
+            attempts,
+            base_delay,
+            std::thread::sleep,
+        );
+
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => self.isolate(chunk, e, &write, &is_permanent),
+            Err(e) => {
+                warn!("Keeping a chunk for a later retry: {}", e);
+                self.push(chunk);
+            }
+        }
+    }
+
+    /// Writes the halves of a chunk that the database rejected, and their
+    /// halves in turn, so that only the rows it refuses end up in the
+    /// dead-letter file. Halves that fail for other reasons are kept for a
+    /// later retry.
+    fn isolate<E, W, P>(&self, chunk: pb::Chunk, error: E, write: &W, is_permanent: &P)
+    where
+        E: Display,
+        W: Fn(&pb::Chunk) -> Result<(), E>,
+        P: Fn(&E) -> bool,
+    {
+        if rows(&chunk) <= 1 {
+            error!("Database rejected a row: {}", error);
+            self.dead_letter(&chunk, &error.to_string());
+            return;
+        }
+
+        let (head, tail) = split(chunk);
+        for half in [head, tail] {
+            match write(&half) {
+                Ok(()) => {}
+                Err(e) if is_permanent(&e) => self.isolate(half, e, write, is_permanent),
+                Err(e) => {
+                    warn!("Keeping a chunk for a later retry: {}", e);
+                    self.push(half);
+                }
+            }
+        }
+    }
+
+    /// Writes the chunks that failed earlier. Stops at the first chunk that
+    /// still fails, as the database is most likely still unavailable.
+    pub fn retry<E, W, P>(&self, write: W, is_permanent: P)
+    where
+        E: Display,
+        W: Fn(&pb::Chunk) -> Result<(), E>,
+        P: Fn(&E) -> bool,
+    {
+        if self.is_empty() {
+            return;
+        }
+        self.drain(|chunk| match write(chunk) {
+            Ok(()) => Outcome::Written,
+            Err(e) if is_permanent(&e) => {
+                // Taken care of, row by row if need be.
+                self.isolate(chunk.clone(), e, &write, &is_permanent);
+                Outcome::Written
+            }
+            Err(e) => {
+                warn!("Database still unavailable for spooled rows: {}", e);
+                Outcome::Failed
+            }
+        });
+    }
+
+    /// Records rows that the database refused, one tab-separated line per row,
+    /// preceded by a comment with the time and the reason.
+    pub fn dead_letter(&self, chunk: &pb::Chunk, reason: &str) {
+        let mut lines = vec![format!(
+            "# {}\t{}",
+            chrono::Utc::now().to_rfc3339(),
+            reason.replace(['\n', '\t'], " ")
+        )];
+        for row in &chunk.metrics {
+            let value = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+            lines.push(format!(
+                "micrometrics\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                row.timestamp,
+                row.environment,
+                row.namespace,
+                row.pod,
+                row.container,
+                value(row.cpu_usage),
+                value(row.cpu_limit),
+                value(row.memory_usage),
+                value(row.memory_limit),
+                value(row.cpu_request),
+                value(row.memory_request),
+                row.cpu_restarts.map(|v| v.to_string()).unwrap_or_default(),
+                row.memory_usage_count
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+                value(row.memory_usage_min),
+                value(row.memory_usage_max),
+                value(row.memory_usage_avg),
+                value(row.memory_usage_p95),
+                row.late,
+            ));
+        }
+        for row in &chunk.owners {
+            lines.push(format!(
+                "microowner\t{}\t{}\t{}\t{}\t{}",
+                row.environment, row.namespace, row.pod, row.owner, row.valid_from
+            ));
+        }
+        for row in &chunk.attributes {
+            lines.push(format!(
+                "microattribute\t{}\t{}\t{}\t{}\t{}\t{}",
+                row.environment, row.namespace, row.pod, row.attribute, row.value, row.valid_from
+            ));
+        }
+        for row in &chunk.workloads {
+            lines.push(format!(
+                "microworkload\t{}\t{}\t{}\t{}\t{}",
+                row.environment, row.namespace, row.pod, row.kind, row.workload
+            ));
+        }
+
+        let Some(dir) = &self.dir else {
+            error!(
+                "Dropping rows rejected by the database:\n{}",
+                lines.join("\n")
+            );
+            return;
+        };
+
+        let path = dir.join(DEAD_LETTER_FILE);
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| {
+                writeln!(file, "{}", lines.join("\n"))?;
+                file.sync_data()
+            });
+        match result {
+            Ok(()) => error!(
+                "Wrote {} rows rejected by the database to {:?}",
+                lines.len() - 1,
+                path
+            ),
+            Err(e) => error!(
+                "Failed to write to dead-letter file {:?} ({}), dropping rows:\n{}",
+                path,
+                e,
+                lines.join("\n")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn chunk(pod: &str) -> pb::Chunk {
+        pb::Chunk {
+            owners: vec![pb::OwnerRow {
+                environment: "env1".to_string(),
+                namespace: "ns1".to_string(),
+                pod: pod.to_string(),
+                owner: "team-a".to_string(),
+                valid_from: 60_000,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn pods(chunks: &[pb::Chunk]) -> Vec<String> {
+        chunks.iter().map(|c| c.owners[0].pod.clone()).collect()
+    }
+
+    #[test]
+    fn test_metric_chunks_need_limits_or_requests() {
+        let key = |pod: &str| Key {
+            timestamp: 60_000,
+            environment: "env1".to_string(),
+            namespace: "ns1".to_string(),
+            pod: pod.to_string(),
+            container: "container1".to_string(),
+        };
+        let metrics = vec![
+            (
+                key("usage-only"),
+                Metrics {
+                    cpu_usage: Some(1.0),
+                    ..Default::default()
+                },
+            ),
+            (
+                key("request-only"),
+                Metrics {
+                    cpu_usage: Some(1.0),
+                    memory_request: Some(1024.0),
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        let chunks = metric_chunks(metrics, 10);
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].metrics.len(), 1);
+        assert_eq!(chunks[0].metrics[0].pod, "request-only");
+        assert_eq!(chunks[0].metrics[0].memory_request_bytes(), Some(1024));
+    }
+
+    #[test]
+    fn test_drain_in_order() {
+        let spool = Spool::in_memory(10);
+        spool.push(chunk("pod1"));
+        spool.push(chunk("pod2"));
+
+        let written = RefCell::new(Vec::new());
+        spool.drain(|c| {
+            written.borrow_mut().push(c.clone());
+            Outcome::Written
+        });
+
+        assert_eq!(pods(&written.borrow()), vec!["pod1", "pod2"]);
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn test_drain_stops_at_failure() {
+        let spool = Spool::in_memory(10);
+        spool.push(chunk("pod1"));
+        spool.push(chunk("pod2"));
+
+        let mut calls = 0;
+        spool.drain(|_| {
+            calls += 1;
+            Outcome::Failed
+        });
+
+        assert_eq!(calls, 1, "must not hammer a database that is down");
+        let written = RefCell::new(Vec::new());
+        spool.drain(|c| {
+            written.borrow_mut().push(c.clone());
+            Outcome::Written
+        });
+        assert_eq!(pods(&written.borrow()), vec!["pod1", "pod2"]);
+    }
+
+    #[test]
+    fn test_overflow_is_spooled_to_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(1, dir.path()).unwrap();
+        spool.push(chunk("pod1"));
+        spool.push(chunk("pod2"));
+        spool.push(chunk("pod3"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // A restart loses the queue in memory, but not the spooled chunks.
+        drop(spool);
+        let spool = Spool::open(1, dir.path()).unwrap();
+        let written = RefCell::new(Vec::new());
+        spool.drain(|c| {
+            written.borrow_mut().push(c.clone());
+            Outcome::Written
+        });
+
+        assert_eq!(pods(&written.borrow()), vec!["pod2", "pod3"]);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_rejected_chunks_go_to_dead_letter_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(10, dir.path()).unwrap();
+        spool.push(chunk("pod1"));
+
+        spool.drain(|_| Outcome::Rejected("packet too large".to_string()));
+
+        assert!(spool.is_empty());
+        let content = fs::read_to_string(dir.path().join(DEAD_LETTER_FILE)).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("\tpacket too large"));
+        assert_eq!(lines[1], "microowner\tenv1\tns1\tpod1\tteam-a\t60000");
+    }
+
+    #[test]
+    fn test_only_refused_rows_go_to_dead_letter_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(10, dir.path()).unwrap();
+        let mut rows = chunk("pod1");
+        for pod in ["pod2", "pod3", "pod4", "pod5"] {
+            rows.owners.extend(chunk(pod).owners);
+        }
+
+        let written = RefCell::new(Vec::new());
+        spool.write(
+            rows,
+            1,
+            Duration::ZERO,
+            |c| {
+                if c.owners.iter().any(|row| row.pod == "pod4") {
+                    return Err("invalid owner");
+                }
+                written
+                    .borrow_mut()
+                    .extend(c.owners.iter().map(|row| row.pod.clone()));
+                Ok(())
+            },
+            |_| true,
+        );
+
+        written.borrow_mut().sort();
+        assert_eq!(*written.borrow(), vec!["pod1", "pod2", "pod3", "pod5"]);
+        let content = fs::read_to_string(dir.path().join(DEAD_LETTER_FILE)).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], "microowner\tenv1\tns1\tpod4\tteam-a\t60000");
+    }
+
+    #[test]
+    fn test_overflow_drops_oldest_without_dir() {
+        let spool = Spool::in_memory(2);
+        let dropped = DROPPED_ROWS.get();
+        spool.push(chunk("pod1"));
+        spool.push(chunk("pod2"));
+        spool.push(chunk("pod3"));
+        assert_eq!(DROPPED_ROWS.get() - dropped, 1);
+
+        let written = RefCell::new(Vec::new());
+        spool.drain(|c| {
+            written.borrow_mut().push(c.clone());
+            Outcome::Written
+        });
+
+        assert_eq!(pods(&written.borrow()), vec!["pod2", "pod3"]);
+        assert!(spool.is_durable());
+    }
+}
@@ -0,0 +1,33 @@
+include!(concat!(env!("OUT_DIR"), "/spool.rs"));
+
+/// The sinks store memory as whole bytes. The rows keep the doubles that
+/// Prometheus reports, so that spooled chunks do not depend on the schema.
+impl MetricRow {
+    pub fn memory_usage_bytes(&self) -> Option<i64> {
+        self.memory_usage.map(|v| v.round() as i64)
+    }
+
+    pub fn memory_limit_bytes(&self) -> Option<i64> {
+        self.memory_limit.map(|v| v.round() as i64)
+    }
+
+    pub fn memory_request_bytes(&self) -> Option<i64> {
+        self.memory_request.map(|v| v.round() as i64)
+    }
+
+    pub fn memory_usage_min_bytes(&self) -> Option<i64> {
+        self.memory_usage_min.map(|v| v.round() as i64)
+    }
+
+    pub fn memory_usage_max_bytes(&self) -> Option<i64> {
+        self.memory_usage_max.map(|v| v.round() as i64)
+    }
+
+    pub fn memory_usage_avg_bytes(&self) -> Option<i64> {
+        self.memory_usage_avg.map(|v| v.round() as i64)
+    }
+
+    pub fn memory_usage_p95_bytes(&self) -> Option<i64> {
+        self.memory_usage_p95.map(|v| v.round() as i64)
+    }
+}
@@ -0,0 +1,1354 @@
+use crate::database::{DEFAULT_CONNECT_BASE_DELAY, DEFAULT_WRITE_ATTEMPTS};
+use crate::history::{ATTRIBUTES, History, OWNERS, Version};
+use crate::metrics_buffer::{Key, Metrics};
+use crate::migrations::{Migration, VERSION_TABLE, migrate, pending};
+use crate::owner_buffer::{AttributeKey, AttributeValue, OwnerKey, OwnerValue, Workload};
+use crate::rollup::{Expiry, Rollups};
+use crate::sink::Sink;
+use crate::spool::{
+    DEFAULT_QUEUE_SIZE, Spool, attribute_chunks, metric_chunks, owner_chunks, pb, workload_chunks,
+};
+use log::{debug, info, warn};
+use rusqlite::{Connection, Error, ErrorCode, TransactionBehavior, params, params_from_iter};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long a write waits for another connection, e.g., a reporting query,
+/// to release its lock.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How timestamps are stored, in UTC.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// See the MySQL backend.
+const INSERT_METRICS: &str = r"INSERT INTO micrometrics
+    (time, environment, namespace, pod, container, cpu_usage, cpu_limit, memory_usage,
+    memory_limit, cpu_request, memory_request, cpu_restarts, memory_usage_count,
+    memory_usage_min, memory_usage_max, memory_usage_avg, memory_usage_p95, merged_chunk)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+    ON CONFLICT (time, environment, namespace, pod, container) DO UPDATE SET
+    cpu_usage = IFNULL(excluded.cpu_usage, cpu_usage),
+    cpu_restarts = IFNULL(excluded.cpu_restarts, cpu_restarts),
+    memory_usage_count = IFNULL(excluded.memory_usage_count, memory_usage_count),
+    memory_usage_min = IFNULL(excluded.memory_usage_min, memory_usage_min),
+    memory_usage_max = IFNULL(excluded.memory_usage_max, memory_usage_max),
+    memory_usage_avg = IFNULL(excluded.memory_usage_avg, memory_usage_avg),
+    memory_usage_p95 = IFNULL(excluded.memory_usage_p95, memory_usage_p95),
+    cpu_limit = IFNULL(excluded.cpu_limit, cpu_limit),
+    memory_usage = IFNULL(excluded.memory_usage, memory_usage),
+    memory_limit = IFNULL(excluded.memory_limit, memory_limit),
+    cpu_request = IFNULL(excluded.cpu_request, cpu_request),
+    memory_request = IFNULL(excluded.memory_request, memory_request)";
+
+/// See the MySQL backend. SQLite's `min` and `max` return NULL if either
+/// value is NULL, like MySQL's `LEAST` and `GREATEST`.
+const MERGE_METRICS: &str = r"INSERT INTO micrometrics
+    (time, environment, namespace, pod, container, cpu_usage, cpu_limit, memory_usage,
+    memory_limit, cpu_request, memory_request, cpu_restarts, memory_usage_count,
+    memory_usage_min, memory_usage_max, memory_usage_avg, memory_usage_p95, merged_chunk)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+    ON CONFLICT (time, environment, namespace, pod, container) DO UPDATE SET
+    memory_usage_avg = COALESCE(
+        (memory_usage_avg * memory_usage_count
+            + excluded.memory_usage_avg * excluded.memory_usage_count)
+            / (memory_usage_count + excluded.memory_usage_count),
+        excluded.memory_usage_avg, memory_usage_avg),
+    memory_usage_count = COALESCE(memory_usage_count + excluded.memory_usage_count,
+        excluded.memory_usage_count, memory_usage_count),
+    memory_usage_min = COALESCE(min(memory_usage_min, excluded.memory_usage_min),
+        excluded.memory_usage_min, memory_usage_min),
+    memory_usage_max = COALESCE(max(memory_usage_max, excluded.memory_usage_max),
+        excluded.memory_usage_max, memory_usage_max),
+    cpu_limit = IFNULL(excluded.cpu_limit, cpu_limit),
+    memory_usage = IFNULL(excluded.memory_usage, memory_usage),
+    memory_limit = IFNULL(excluded.memory_limit, memory_limit),
+    cpu_request = IFNULL(excluded.cpu_request, cpu_request),
+    memory_request = IFNULL(excluded.memory_request, memory_request),
+    merged_chunk = excluded.merged_chunk
+    WHERE merged_chunk IS NOT excluded.merged_chunk";
+
+/// See the MySQL migrations. Version 1 is the schema from before migrations.
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create micrometrics and microowner",
+        statements: &[
+            r"CREATE TABLE IF NOT EXISTS micrometrics (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage REAL,
+                cpu_limit REAL,
+                memory_usage REAL,
+                memory_limit REAL,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+            r"CREATE TABLE IF NOT EXISTS microowner (
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                owner VARCHAR(255),
+                PRIMARY KEY (environment, pod)
+            )",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Store memory as integer bytes",
+        // SQLite cannot change the type of a column, so the table is rebuilt.
+        statements: &[
+            r"CREATE TABLE micrometrics_v2 (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage REAL,
+                cpu_limit REAL,
+                memory_usage INTEGER,
+                memory_limit INTEGER,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+            r"INSERT INTO micrometrics_v2
+                SELECT time, environment, pod, container, cpu_usage, cpu_limit,
+                    CAST(ROUND(memory_usage) AS INTEGER), CAST(ROUND(memory_limit) AS INTEGER)
+                FROM micrometrics",
+            "DROP TABLE micrometrics",
+            "ALTER TABLE micrometrics_v2 RENAME TO micrometrics",
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "Create hourly and daily rollups",
+        statements: &[
+            r"CREATE TABLE micrometrics_hourly (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage REAL,
+                cpu_limit_seconds REAL,
+                memory_usage_avg REAL,
+                memory_usage_max INTEGER,
+                memory_limit_max INTEGER,
+                samples INTEGER,
+                memory_samples INTEGER,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+            r"CREATE TABLE micrometrics_daily (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage REAL,
+                cpu_limit_seconds REAL,
+                memory_usage_avg REAL,
+                memory_usage_max INTEGER,
+                memory_limit_max INTEGER,
+                samples INTEGER,
+                memory_samples INTEGER,
+                PRIMARY KEY (time, environment, pod, container)
+            )",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Add resource requests",
+        statements: &[
+            "ALTER TABLE micrometrics ADD COLUMN cpu_request REAL",
+            "ALTER TABLE micrometrics ADD COLUMN memory_request INTEGER",
+            "ALTER TABLE micrometrics_hourly ADD COLUMN cpu_request_seconds REAL",
+            "ALTER TABLE micrometrics_hourly ADD COLUMN memory_request_max INTEGER",
+            "ALTER TABLE micrometrics_daily ADD COLUMN cpu_request_seconds REAL",
+            "ALTER TABLE micrometrics_daily ADD COLUMN memory_request_max INTEGER",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Add namespace to the primary keys",
+        // As in version 2, changing a primary key takes a rebuild.
+        statements: &[
+            r"CREATE TABLE micrometrics_v5 (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                namespace VARCHAR(255) NOT NULL DEFAULT '',
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage REAL,
+                cpu_limit REAL,
+                memory_usage INTEGER,
+                memory_limit INTEGER,
+                cpu_request REAL,
+                memory_request INTEGER,
+                PRIMARY KEY (time, environment, namespace, pod, container)
+            )",
+            r"INSERT INTO micrometrics_v5
+                (time, environment, pod, container, cpu_usage, cpu_limit, memory_usage, memory_limit,
+                cpu_request, memory_request)
+                SELECT time, environment, pod, container, cpu_usage, cpu_limit, memory_usage,
+                memory_limit, cpu_request, memory_request
+                FROM micrometrics",
+            "DROP TABLE micrometrics",
+            "ALTER TABLE micrometrics_v5 RENAME TO micrometrics",
+            r"CREATE TABLE micrometrics_hourly_v5 (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                namespace VARCHAR(255) NOT NULL DEFAULT '',
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage REAL,
+                cpu_limit_seconds REAL,
+                memory_usage_avg REAL,
+                memory_usage_max INTEGER,
+                memory_limit_max INTEGER,
+                samples INTEGER,
+                memory_samples INTEGER,
+                cpu_request_seconds REAL,
+                memory_request_max INTEGER,
+                PRIMARY KEY (time, environment, namespace, pod, container)
+            )",
+            r"INSERT INTO micrometrics_hourly_v5
+                (time, environment, pod, container, cpu_usage, cpu_limit_seconds, memory_usage_avg,
+                memory_usage_max, memory_limit_max, samples, memory_samples, cpu_request_seconds,
+                memory_request_max)
+                SELECT time, environment, pod, container, cpu_usage, cpu_limit_seconds, memory_usage_avg,
+                memory_usage_max, memory_limit_max, samples, memory_samples, cpu_request_seconds,
+                memory_request_max
+                FROM micrometrics_hourly",
+            "DROP TABLE micrometrics_hourly",
+            "ALTER TABLE micrometrics_hourly_v5 RENAME TO micrometrics_hourly",
+            r"CREATE TABLE micrometrics_daily_v5 (
+                time TIMESTAMP,
+                environment VARCHAR(255),
+                namespace VARCHAR(255) NOT NULL DEFAULT '',
+                pod VARCHAR(255),
+                container VARCHAR(255),
+                cpu_usage REAL,
+                cpu_limit_seconds REAL,
+                memory_usage_avg REAL,
+                memory_usage_max INTEGER,
+                memory_limit_max INTEGER,
+                samples INTEGER,
+                memory_samples INTEGER,
+                cpu_request_seconds REAL,
+                memory_request_max INTEGER,
+                PRIMARY KEY (time, environment, namespace, pod, container)
+            )",
+            r"INSERT INTO micrometrics_daily_v5
+                (time, environment, pod, container, cpu_usage, cpu_limit_seconds, memory_usage_avg,
+                memory_usage_max, memory_limit_max, samples, memory_samples, cpu_request_seconds,
+                memory_request_max)
+                SELECT time, environment, pod, container, cpu_usage, cpu_limit_seconds, memory_usage_avg,
+                memory_usage_max, memory_limit_max, samples, memory_samples, cpu_request_seconds,
+                memory_request_max
+                FROM micrometrics_daily",
+            "DROP TABLE micrometrics_daily",
+            "ALTER TABLE micrometrics_daily_v5 RENAME TO micrometrics_daily",
+            r"CREATE TABLE microowner_v5 (
+                environment VARCHAR(255),
+                namespace VARCHAR(255) NOT NULL DEFAULT '',
+                pod VARCHAR(255),
+                owner VARCHAR(255),
+                PRIMARY KEY (environment, namespace, pod)
+            )",
+            r"INSERT INTO microowner_v5 (environment, pod, owner)
+                SELECT environment, pod, owner FROM microowner",
+            "DROP TABLE microowner",
+            "ALTER TABLE microowner_v5 RENAME TO microowner",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Create microworkload",
+        statements: &[r"CREATE TABLE microworkload (
+                environment VARCHAR(255),
+                namespace VARCHAR(255),
+                pod VARCHAR(255),
+                kind VARCHAR(255),
+                workload VARCHAR(255),
+                PRIMARY KEY (environment, namespace, pod)
+            )"],
+    },
+    Migration {
+        version: 7,
+        description: "Track the validity of owners",
+        statements: &[
+            r"CREATE TABLE microowner_v7 (
+                environment VARCHAR(255),
+                namespace VARCHAR(255) NOT NULL DEFAULT '',
+                pod VARCHAR(255),
+                owner VARCHAR(255),
+                valid_from TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00',
+                valid_to TIMESTAMP,
+                PRIMARY KEY (environment, namespace, pod, valid_from)
+            )",
+            r"INSERT INTO microowner_v7 (environment, namespace, pod, owner)
+                SELECT environment, namespace, pod, owner FROM microowner",
+            "DROP TABLE microowner",
+            "ALTER TABLE microowner_v7 RENAME TO microowner",
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "Create microattribute",
+        statements: &[r"CREATE TABLE microattribute (
+                environment VARCHAR(255),
+                namespace VARCHAR(255),
+                pod VARCHAR(255),
+                attribute VARCHAR(255),
+                value VARCHAR(255),
+                valid_from TIMESTAMP NOT NULL,
+                valid_to TIMESTAMP,
+                PRIMARY KEY (environment, namespace, pod, attribute, valid_from)
+            )"],
+    },
+    Migration {
+        version: 9,
+        description: "Count CPU restarts",
+        statements: &[
+            "ALTER TABLE micrometrics ADD COLUMN cpu_restarts INTEGER",
+            "ALTER TABLE micrometrics_hourly ADD COLUMN cpu_restarts INTEGER",
+            "ALTER TABLE micrometrics_daily ADD COLUMN cpu_restarts INTEGER",
+        ],
+    },
+    Migration {
+        version: 10,
+        description: "Aggregate memory samples",
+        statements: &[
+            "ALTER TABLE micrometrics ADD COLUMN memory_usage_count INTEGER",
+            "ALTER TABLE micrometrics ADD COLUMN memory_usage_min INTEGER",
+            "ALTER TABLE micrometrics ADD COLUMN memory_usage_max INTEGER",
+            "ALTER TABLE micrometrics ADD COLUMN memory_usage_avg INTEGER",
+            "ALTER TABLE micrometrics ADD COLUMN memory_usage_p95 INTEGER",
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "Remember the chunk that late samples were merged from",
+        statements: &["ALTER TABLE micrometrics ADD COLUMN merged_chunk INTEGER"],
+    },
+];
+
+/// The same tables as the MySQL backend in an SQLite file, for deployments
+/// that do not want to run a database server.
+pub struct SqliteDatabase {
+    conn: Mutex<Connection>,
+    chunk_size: usize,
+    write_attempts: u32,
+    spool: Spool,
+    rollups: Option<Rollups>,
+    expiry: Expiry,
+}
+
+impl SqliteDatabase {
+    /// Opens or creates the database file at `path` in WAL mode, so that
+    /// reports can read while microinsight writes.
+    pub fn open(path: &str, chunk_size: usize) -> Self {
+        let conn = Connection::open(path).expect("Failed to open database file");
+        conn.busy_timeout(BUSY_TIMEOUT)
+            .expect("Failed to set busy timeout");
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .expect("Failed to enable WAL mode");
+        conn.pragma_update(None, "synchronous", "NORMAL")
+            .expect("Failed to set synchronous mode");
+        SqliteDatabase {
+            conn: Mutex::new(conn),
+            chunk_size,
+            write_attempts: DEFAULT_WRITE_ATTEMPTS,
+            spool: Spool::in_memory(DEFAULT_QUEUE_SIZE),
+            rollups: None,
+            expiry: Expiry::default(),
+        }
+    }
+
+    /// See `Database::with_spool`.
+    pub fn with_spool(mut self, spool: Spool, write_attempts: u32) -> Self {
+        self.spool = spool;
+        self.write_attempts = write_attempts;
+        self
+    }
+
+    /// See `Database::with_rollups`.
+    pub fn with_rollups(mut self, rollups: Rollups) -> Self {
+        self.rollups = Some(rollups);
+        self
+    }
+
+    fn write_with_retry(&self, chunk: pb::Chunk) {
+        self.spool.write(
+            chunk,
+            self.write_attempts,
+            DEFAULT_CONNECT_BASE_DELAY,
+            |chunk| self.write_chunk(chunk),
+            is_permanent,
+        );
+    }
+
+    fn write_chunk(&self, chunk: &pb::Chunk) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        if !chunk.metrics.is_empty() {
+            let mut insert = tx.prepare_cached(INSERT_METRICS)?;
+            let mut merge = tx.prepare_cached(MERGE_METRICS)?;
+            for row in &chunk.metrics {
+                let Some(timestamp) = chrono::DateTime::from_timestamp_millis(row.timestamp as i64)
+                else {
+                    continue;
+                };
+                let (statement, merged_chunk) = if row.late {
+                    (&mut merge, Some(chunk.id as i64))
+                } else {
+                    (&mut insert, None)
+                };
+                statement.execute(params![
+                    timestamp.format(TIME_FORMAT).to_string(),
+                    row.environment,
+                    row.namespace,
+                    row.pod,
+                    row.container,
+                    row.cpu_usage,
+                    row.cpu_limit,
+                    row.memory_usage_bytes(),
+                    row.memory_limit_bytes(),
+                    row.cpu_request,
+                    row.memory_request_bytes(),
+                    row.cpu_restarts,
+                    row.memory_usage_count,
+                    row.memory_usage_min_bytes(),
+                    row.memory_usage_max_bytes(),
+                    row.memory_usage_avg_bytes(),
+                    row.memory_usage_p95_bytes(),
+                    merged_chunk,
+                ])?;
+            }
+            drop(insert);
+            drop(merge);
+
+            if let Some(rollups) = &self.rollups {
+                refresh_rollups(&tx, rollups, &chunk.metrics)?;
+            }
+        }
+
+        write_history(&tx, &OWNERS, &chunk.owners)?;
+        write_history(&tx, &ATTRIBUTES, &chunk.attributes)?;
+
+        if !chunk.workloads.is_empty() {
+            let mut statement = tx.prepare_cached(
+                r"INSERT INTO microworkload (environment, namespace, pod, kind, workload)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT (environment, namespace, pod) DO UPDATE SET
+                kind = excluded.kind, workload = excluded.workload",
+            )?;
+            for row in &chunk.workloads {
+                statement.execute(params![
+                    row.environment,
+                    row.namespace,
+                    row.pod,
+                    row.kind,
+                    row.workload
+                ])?;
+            }
+        }
+
+        tx.commit()
+    }
+}
+
+impl Sink for SqliteDatabase {
+    fn create_tables(&self) {
+        let mut conn = self.conn.lock().unwrap();
+        conn.execute_batch(&format!(
+            r"CREATE TABLE IF NOT EXISTS {} (
+                version INTEGER PRIMARY KEY,
+                description VARCHAR(255),
+                applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            VERSION_TABLE
+        ))
+        .expect("Failed to create schema version table");
+
+        // An immediate transaction holds the write lock of the file, which
+        // keeps other processes from migrating at the same time.
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .expect("Failed to lock the database");
+        let current = schema_version(&tx).expect("Failed to read schema version");
+        migrate(MIGRATIONS, current, |migration| {
+            for statement in migration.statements {
+                tx.execute_batch(statement)?;
+            }
+            tx.execute(
+                &format!(
+                    "INSERT INTO {} (version, description) VALUES (?, ?)",
+                    VERSION_TABLE
+                ),
+                params![migration.version, migration.description],
+            )
+            .map(|_| ())
+        })
+        .expect("Failed to migrate schema");
+        tx.commit().expect("Failed to migrate schema");
+    }
+
+    fn pending_migrations(&self) -> Vec<&'static Migration> {
+        let conn = self.conn.lock().unwrap();
+        let current = schema_version(&conn).expect("Failed to read schema version");
+        pending(MIGRATIONS, current)
+    }
+
+    fn insert_metrics(&self, metrics: Vec<(Key, Metrics)>) {
+        info!("Inserting {} metrics into the database", metrics.len());
+        for chunk in metric_chunks(metrics, self.chunk_size) {
+            debug!("Inserting a chunk of {} metrics", chunk.metrics.len());
+            self.write_with_retry(chunk);
+        }
+
+        if let Some(rollups) = &self.rollups
+            && self.expiry.due()
+            && let Err(e) = expire_rollups(&self.conn.lock().unwrap(), rollups)
+        {
+            warn!("Failed to delete expired rollups: {}", e);
+        }
+    }
+
+    fn insert_owners(&self, owners: Vec<(OwnerKey, OwnerValue)>) {
+        info!("Inserting {} owners into the database", owners.len());
+        for chunk in owner_chunks(owners, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn insert_attributes(&self, attributes: Vec<(AttributeKey, AttributeValue)>) {
+        info!(
+            "Inserting {} attributes into the database",
+            attributes.len()
+        );
+        for chunk in attribute_chunks(attributes, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn insert_workloads(&self, workloads: Vec<(OwnerKey, Workload)>) {
+        info!("Inserting {} workloads into the database", workloads.len());
+        for chunk in workload_chunks(workloads, self.chunk_size) {
+            self.write_with_retry(chunk);
+        }
+    }
+
+    fn retry_pending(&self) {
+        self.spool
+            .retry(|chunk| self.write_chunk(chunk), is_permanent);
+    }
+
+    fn sync(&self) -> Result<bool, String> {
+        Ok(self.spool.is_durable())
+    }
+}
+
+/// See the MySQL backend.
+fn write_history<'a, T>(conn: &Connection, history: &History, rows: &'a [T]) -> Result<(), Error>
+where
+    Version<'a>: From<&'a T>,
+{
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut close = conn.prepare_cached(&history.close())?;
+    let mut open = conn.prepare_cached(&history.open("INSERT OR IGNORE", ""))?;
+    for version in rows.iter().map(Version::from) {
+        let Some(valid_from) = chrono::DateTime::from_timestamp_millis(version.valid_from as i64)
+        else {
+            continue;
+        };
+        let valid_from = valid_from.format(TIME_FORMAT).to_string();
+        close.execute(params_from_iter(version.close_params(&valid_from)))?;
+        open.execute(params_from_iter(version.open_params(&valid_from)))?;
+    }
+    Ok(())
+}
+
+/// See the MySQL backend. Times are stored as text, which compares in order.
+fn refresh_rollups(
+    conn: &Connection,
+    rollups: &Rollups,
+    rows: &[pb::MetricRow],
+) -> Result<(), Error> {
+    let refresh = rollups.refresh(rows, chrono::Utc::now());
+    let mut hourly = conn.prepare_cached(
+        r"INSERT INTO micrometrics_hourly
+        (time, environment, namespace, pod, container, cpu_usage, cpu_limit_seconds,
+        memory_usage_avg, memory_usage_max, memory_limit_max, samples, memory_samples,
+        cpu_request_seconds, memory_request_max, cpu_restarts)
+        SELECT ?1, environment, namespace, pod, container, SUM(cpu_usage), SUM(cpu_limit) * ?3,
+        AVG(IFNULL(memory_usage_avg, memory_usage)), MAX(IFNULL(memory_usage_max, memory_usage)),
+        MAX(memory_limit), COUNT(*), COUNT(memory_usage),
+        SUM(cpu_request) * ?3, MAX(memory_request), SUM(cpu_restarts)
+        FROM micrometrics WHERE time >= ?1 AND time < ?2
+        AND environment = ?4 AND namespace = ?5 AND pod = ?6 AND container = ?7
+        GROUP BY environment, namespace, pod, container
+        ON CONFLICT (time, environment, namespace, pod, container) DO UPDATE SET
+        cpu_usage = excluded.cpu_usage,
+        cpu_limit_seconds = excluded.cpu_limit_seconds,
+        memory_usage_avg = excluded.memory_usage_avg,
+        memory_usage_max = excluded.memory_usage_max,
+        memory_limit_max = excluded.memory_limit_max,
+        samples = excluded.samples,
+        memory_samples = excluded.memory_samples,
+        cpu_request_seconds = excluded.cpu_request_seconds,
+        memory_request_max = excluded.memory_request_max,
+        cpu_restarts = excluded.cpu_restarts",
+    )?;
+    for (hour, series) in &refresh.hours {
+        hourly.execute(params![
+            hour.format(TIME_FORMAT).to_string(),
+            (*hour + chrono::Duration::hours(1))
+                .format(TIME_FORMAT)
+                .to_string(),
+            rollups.interval_secs,
+            series.environment,
+            series.namespace,
+            series.pod,
+            series.container,
+        ])?;
+    }
+    let mut daily = conn.prepare_cached(
+        r"INSERT INTO micrometrics_daily
+        (time, environment, namespace, pod, container, cpu_usage, cpu_limit_seconds,
+        memory_usage_avg, memory_usage_max, memory_limit_max, samples, memory_samples,
+        cpu_request_seconds, memory_request_max, cpu_restarts)
+        SELECT ?1, environment, namespace, pod, container, SUM(cpu_usage), SUM(cpu_limit_seconds),
+        SUM(memory_usage_avg * memory_samples) / NULLIF(SUM(memory_samples), 0),
+        MAX(memory_usage_max), MAX(memory_limit_max), SUM(samples), SUM(memory_samples),
+        SUM(cpu_request_seconds), MAX(memory_request_max), SUM(cpu_restarts)
+        FROM micrometrics_hourly WHERE time >= ?1 AND time < ?2
+        AND environment = ?3 AND namespace = ?4 AND pod = ?5 AND container = ?6
+        GROUP BY environment, namespace, pod, container
+        ON CONFLICT (time, environment, namespace, pod, container) DO UPDATE SET
+        cpu_usage = excluded.cpu_usage,
+        cpu_limit_seconds = excluded.cpu_limit_seconds,
+        memory_usage_avg = excluded.memory_usage_avg,
+        memory_usage_max = excluded.memory_usage_max,
+        memory_limit_max = excluded.memory_limit_max,
+        samples = excluded.samples,
+        memory_samples = excluded.memory_samples,
+        cpu_request_seconds = excluded.cpu_request_seconds,
+        memory_request_max = excluded.memory_request_max,
+        cpu_restarts = excluded.cpu_restarts",
+    )?;
+    for (day, series) in &refresh.days {
+        daily.execute(params![
+            day.format(TIME_FORMAT).to_string(),
+            (*day + chrono::Duration::days(1))
+                .format(TIME_FORMAT)
+                .to_string(),
+            series.environment,
+            series.namespace,
+            series.pod,
+            series.container,
+        ])?;
+    }
+    Ok(())
+}
+
+fn expire_rollups(conn: &Connection, rollups: &Rollups) -> Result<(), Error> {
+    let (hourly, daily) = rollups.cutoffs(chrono::Utc::now());
+    for (table, cutoff) in [
+        ("micrometrics_hourly", hourly),
+        ("micrometrics_daily", daily),
+    ] {
+        if let Some(cutoff) = cutoff {
+            conn.execute(
+                &format!("DELETE FROM {} WHERE time < ?", table),
+                [cutoff.format(TIME_FORMAT).to_string()],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The latest applied migration, or 0 for a database that has never been
+/// migrated.
+fn schema_version(conn: &Connection) -> Result<u32, Error> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+        [VERSION_TABLE],
+        |r| r.get(0),
+    )?;
+    if !exists {
+        return Ok(0);
+    }
+    conn.query_row(
+        &format!("SELECT IFNULL(MAX(version), 0) FROM {}", VERSION_TABLE),
+        [],
+        |r| r.get(0),
+    )
+}
+
+/// A locked or full database can recover; rows that violate the schema or
+/// exceed SQLite's limits cannot.
+fn is_permanent(e: &Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::ConstraintViolation | ErrorCode::TooBig | ErrorCode::TypeMismatch)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gauge::Gauge;
+    use crate::metrics_buffer::{MetricsBuffer, Thresholds};
+
+    fn key(timestamp: u64) -> Key {
+        Key {
+            timestamp,
+            environment: "prod".to_string(),
+            namespace: "shop".to_string(),
+            pod: "pod-1".to_string(),
+            container: "container-1".to_string(),
+        }
+    }
+
+    fn open() -> SqliteDatabase {
+        let database = SqliteDatabase::open(":memory:", 5000);
+        database.create_tables();
+        database
+    }
+
+    #[test]
+    fn test_upsert_keeps_stored_values() {
+        let database = open();
+        database.insert_metrics(vec![(
+            key(60_000),
+            Metrics {
+                cpu_usage: Some(12.5),
+                cpu_restarts: Some(1),
+                cpu_limit: Some(60.0),
+                ..Default::default()
+            },
+        )]);
+        database.insert_metrics(vec![(
+            key(60_000),
+            Metrics {
+                memory_usage: Some(512.0),
+                memory_limit: Some(1024.0),
+                cpu_request: Some(0.25),
+                memory_request: Some(768.0),
+                ..Default::default()
+            },
+        )]);
+
+        let conn = database.conn.lock().unwrap();
+        let row: (String, f64, f64, i64, i64, f64, i64) = conn
+            .query_row(
+                "SELECT time, cpu_usage, cpu_limit, memory_usage, memory_limit, cpu_request,
+                memory_request FROM micrometrics",
+                [],
+                |r| {
+                    Ok((
+                        r.get(0)?,
+                        r.get(1)?,
+                        r.get(2)?,
+                        r.get(3)?,
+                        r.get(4)?,
+                        r.get(5)?,
+                        r.get(6)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            row,
+            (
+                "1970-01-01 00:01:00".to_string(),
+                12.5,
+                60.0,
+                512,
+                1024,
+                0.25,
+                768
+            )
+        );
+        // The restarts survive the update that does not know them.
+        let cpu_restarts: i64 = conn
+            .query_row("SELECT cpu_restarts FROM micrometrics", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(cpu_restarts, 1);
+    }
+
+    #[test]
+    fn test_metrics_without_limits_or_requests_are_skipped() {
+        let database = open();
+        database.insert_metrics(vec![(
+            key(60_000),
+            Metrics {
+                cpu_usage: Some(12.5),
+                ..Default::default()
+            },
+        )]);
+
+        let conn = database.conn.lock().unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM micrometrics", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_usage_spread_over_missed_scrapes_is_stored() {
+        let database = open();
+        let buffer = MetricsBuffer::new(60_000, 5);
+        let insert = |name, timestamp, value| {
+            buffer.insert(
+                name,
+                "prod",
+                "shop",
+                "pod-1",
+                "container-1",
+                timestamp,
+                value,
+            );
+        };
+        insert("cpu_usage_total", 60_000, 100.0);
+        insert("cpu_limit", 60_000, 1.0);
+        insert("cpu_usage_total", 240_000, 190.0);
+        insert("cpu_limit", 240_000, 1.0);
+        database.insert_metrics(buffer.flush_before(&Thresholds::all(u64::MAX)));
+
+        let conn = database.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT cpu_usage, cpu_limit FROM micrometrics
+                WHERE cpu_usage IS NOT NULL ORDER BY time",
+            )
+            .unwrap();
+        let rows: Vec<(f64, f64)> = statement
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(rows, vec![(30.0, 1.0); 3]);
+    }
+
+    #[test]
+    fn test_namespaces_keep_pods_apart() {
+        let database = open();
+        let metrics = |cpu_limit| Metrics {
+            cpu_limit: Some(cpu_limit),
+            ..Default::default()
+        };
+        let other = Key {
+            namespace: "blog".to_string(),
+            ..key(60_000)
+        };
+        database.insert_metrics(vec![(key(60_000), metrics(1.0)), (other, metrics(2.0))]);
+
+        let conn = database.conn.lock().unwrap();
+        let rows: Vec<(String, f64)> = conn
+            .prepare("SELECT namespace, cpu_limit FROM micrometrics ORDER BY namespace")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![("blog".to_string(), 2.0), ("shop".to_string(), 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_migrations_are_applied_once() {
+        let database = SqliteDatabase::open(":memory:", 5000);
+        assert_eq!(database.pending_migrations().len(), MIGRATIONS.len());
+
+        database.create_tables();
+        database.create_tables();
+
+        assert!(database.pending_migrations().is_empty());
+        let conn = database.conn.lock().unwrap();
+        let versions: i64 = conn
+            .query_row(
+                &format!("SELECT COUNT(*) FROM {}", VERSION_TABLE),
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(versions, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn test_memory_becomes_integer_bytes() {
+        let database = SqliteDatabase::open(":memory:", 5000);
+        {
+            let conn = database.conn.lock().unwrap();
+            for statement in MIGRATIONS[0].statements {
+                conn.execute_batch(statement).unwrap();
+            }
+            conn.execute_batch(&format!(
+                "CREATE TABLE {0} (version INTEGER PRIMARY KEY, description VARCHAR(255),
+                    applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+                INSERT INTO {0} (version) VALUES (1);
+                INSERT INTO micrometrics VALUES ('1970-01-01 00:01:00', 'prod', 'pod-1',
+                    'container-1', 12.5, 0.25, 2097151999.6, NULL);",
+                VERSION_TABLE
+            ))
+            .unwrap();
+        }
+
+        assert_eq!(database.pending_migrations().len(), MIGRATIONS.len() - 1);
+        database.create_tables();
+
+        let conn = database.conn.lock().unwrap();
+        let row: (f64, String, Option<i64>) = conn
+            .query_row(
+                "SELECT cpu_limit, typeof(memory_usage), memory_limit FROM micrometrics",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (0.25, "integer".to_string(), None));
+        let memory_usage: i64 = conn
+            .query_row("SELECT memory_usage FROM micrometrics", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(memory_usage, 2_097_152_000);
+    }
+
+    #[test]
+    fn test_memory_statistics_are_stored() {
+        let database = open();
+        let mut memory_usage_stats = Gauge::new("count,max,p95".parse().unwrap());
+        for value in [100.0, 300.0, 200.0] {
+            memory_usage_stats.observe(value);
+        }
+        let p95 = memory_usage_stats.p95().unwrap().round() as i64;
+        database.insert_metrics(vec![(
+            key(60_000),
+            Metrics {
+                memory_usage: Some(200.0),
+                memory_usage_stats,
+                memory_limit: Some(1024.0),
+                ..Default::default()
+            },
+        )]);
+
+        let conn = database.conn.lock().unwrap();
+        let row: (i64, Option<i64>, i64, Option<i64>, i64) = conn
+            .query_row(
+                "SELECT memory_usage_count, memory_usage_min, memory_usage_max,
+                memory_usage_avg, memory_usage_p95 FROM micrometrics",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (3, None, 300, None, p95));
+    }
+
+    #[test]
+    fn test_late_rows_are_merged() {
+        let database = open();
+        let metrics = |values: &[f64], late: bool| {
+            let mut memory_usage_stats = Gauge::default();
+            for value in values {
+                memory_usage_stats.observe(*value);
+            }
+            Metrics {
+                cpu_usage: (!late).then_some(30.0),
+                memory_usage: values.last().copied(),
+                memory_usage_stats,
+                memory_limit: (!late).then_some(1024.0),
+                late,
+                ..Default::default()
+            }
+        };
+        database.insert_metrics(vec![(key(60_000), metrics(&[100.0, 300.0], false))]);
+        database.insert_metrics(vec![(key(60_000), metrics(&[50.0], true))]);
+        database.insert_metrics(vec![(key(60_000), metrics(&[400.0], true))]);
+
+        let conn = database.conn.lock().unwrap();
+        let row: (f64, i64, i64, i64, i64, i64, i64, i64) = conn
+            .query_row(
+                "SELECT cpu_usage, memory_usage, memory_limit, memory_usage_count,
+                memory_usage_min, memory_usage_max, memory_usage_avg, memory_usage_p95
+                FROM micrometrics",
+                [],
+                |r| {
+                    Ok((
+                        r.get(0)?,
+                        r.get(1)?,
+                        r.get(2)?,
+                        r.get(3)?,
+                        r.get(4)?,
+                        r.get(5)?,
+                        r.get(6)?,
+                        r.get(7)?,
+                    ))
+                },
+            )
+            .unwrap();
+        // The averages of 2 and 1 and then of 3 and 1 samples, in whole bytes,
+        // and the 95th percentile of the samples that arrived in time.
+        let p95 = metrics(&[100.0, 300.0], false).memory_usage_stats.p95();
+        assert_eq!(
+            row,
+            (
+                30.0,
+                400,
+                1024,
+                4,
+                50,
+                400,
+                212,
+                p95.unwrap().round() as i64
+            )
+        );
+    }
+
+    #[test]
+    fn test_retried_late_rows_are_merged_once() {
+        let database = open();
+        let metrics = |value: f64, late: bool| {
+            let mut memory_usage_stats = Gauge::default();
+            memory_usage_stats.observe(value);
+            Metrics {
+                memory_usage: Some(value),
+                memory_usage_stats,
+                memory_limit: (!late).then_some(1024.0),
+                late,
+                ..Default::default()
+            }
+        };
+        database.insert_metrics(vec![(key(60_000), metrics(100.0, false))]);
+        let late = metric_chunks(vec![(key(60_000), metrics(400.0, true))], 10);
+        // The commit of the first write was lost, so the chunk comes again.
+        database.write_chunk(&late[0]).unwrap();
+        database.write_chunk(&late[0]).unwrap();
+
+        let conn = database.conn.lock().unwrap();
+        let row: (i64, i64) = conn
+            .query_row(
+                "SELECT memory_usage_count, memory_usage_avg FROM micrometrics",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (2, 250));
+    }
+
+    #[test]
+    fn test_rollups_follow_every_write() {
+        let database = SqliteDatabase::open(":memory:", 1).with_rollups(Rollups {
+            interval_secs: 60,
+            hourly_retention_days: None,
+            daily_retention_days: None,
+        });
+        database.create_tables();
+
+        let metrics = |cpu_usage, memory_usage| Metrics {
+            cpu_usage: Some(cpu_usage),
+            cpu_limit: Some(0.5),
+            memory_usage,
+            memory_limit: Some(1024.0),
+            ..Default::default()
+        };
+        // Two minutes of the first hour, in chunks of one row, and one of the
+        // second hour.
+        database.insert_metrics(vec![
+            (key(60_000), metrics(10.0, Some(256.0))),
+            (key(120_000), metrics(20.0, None)),
+        ]);
+        database.insert_metrics(vec![(key(3_660_000), metrics(30.0, Some(512.0)))]);
+        // Late data for the first hour replaces its rollup instead of adding
+        // to it.
+        database.insert_metrics(vec![(key(180_000), metrics(5.0, Some(768.0)))]);
+
+        let conn = database.conn.lock().unwrap();
+        let hourly: Vec<(String, f64, f64, f64, i64, i64, i64)> = conn
+            .prepare(
+                "SELECT time, cpu_usage, cpu_limit_seconds, memory_usage_avg, memory_usage_max,
+                samples, memory_samples FROM micrometrics_hourly ORDER BY time",
+            )
+            .unwrap()
+            .query_map([], |r| {
+                Ok((
+                    r.get(0)?,
+                    r.get(1)?,
+                    r.get(2)?,
+                    r.get(3)?,
+                    r.get(4)?,
+                    r.get(5)?,
+                    r.get(6)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            hourly,
+            vec![
+                (
+                    "1970-01-01 00:00:00".to_string(),
+                    35.0,
+                    90.0,
+                    512.0,
+                    768,
+                    3,
+                    2
+                ),
+                (
+                    "1970-01-01 01:00:00".to_string(),
+                    30.0,
+                    30.0,
+                    512.0,
+                    512,
+                    1,
+                    1
+                ),
+            ]
+        );
+
+        let daily: (String, f64, f64, f64, i64, i64) = conn
+            .query_row(
+                "SELECT time, cpu_usage, cpu_limit_seconds, memory_usage_avg, memory_limit_max,
+                samples FROM micrometrics_daily",
+                [],
+                |r| {
+                    Ok((
+                        r.get(0)?,
+                        r.get(1)?,
+                        r.get(2)?,
+                        r.get(3)?,
+                        r.get(4)?,
+                        r.get(5)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            daily,
+            (
+                "1970-01-01 00:00:00".to_string(),
+                65.0,
+                120.0,
+                512.0,
+                1024,
+                4
+            )
+        );
+    }
+
+    #[test]
+    fn test_rollups_refresh_only_what_changed() {
+        let database = SqliteDatabase::open(":memory:", 5000).with_rollups(Rollups {
+            interval_secs: 60,
+            hourly_retention_days: None,
+            daily_retention_days: None,
+        });
+        database.create_tables();
+        let metrics = Metrics {
+            cpu_usage: Some(10.0),
+            cpu_limit: Some(0.5),
+            ..Default::default()
+        };
+        let other = Key {
+            pod: "pod-2".to_string(),
+            ..key(60_000)
+        };
+        database.insert_metrics(vec![
+            (key(60_000), metrics.clone()),
+            (other, metrics.clone()),
+        ]);
+        // Stands for a rollup that a refresh of the other pod would change.
+        database
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE micrometrics_hourly SET cpu_usage = 99 WHERE pod = 'pod-2'",
+                [],
+            )
+            .unwrap();
+
+        database.insert_metrics(vec![(key(120_000), metrics)]);
+
+        let conn = database.conn.lock().unwrap();
+        let hourly: Vec<(String, f64)> = conn
+            .prepare("SELECT pod, cpu_usage FROM micrometrics_hourly ORDER BY pod")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            hourly,
+            vec![("pod-1".to_string(), 20.0), ("pod-2".to_string(), 99.0)]
+        );
+    }
+
+    #[test]
+    fn test_days_past_hourly_retention_are_kept() {
+        let database = SqliteDatabase::open(":memory:", 5000).with_rollups(Rollups {
+            interval_secs: 60,
+            hourly_retention_days: Some(90),
+            daily_retention_days: None,
+        });
+        database.create_tables();
+        database
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                r"INSERT INTO micrometrics_daily (time, environment, namespace, pod, container,
+            cpu_usage, samples, memory_samples)
+            VALUES ('1970-01-01 00:00:00', 'prod', 'shop', 'pod-1', 'container-1', 600, 60, 0)",
+                [],
+            )
+            .unwrap();
+
+        // Late data for a day whose hourly rollups have expired.
+        database.insert_metrics(vec![(
+            key(60_000),
+            Metrics {
+                cpu_usage: Some(10.0),
+                cpu_limit: Some(0.5),
+                ..Default::default()
+            },
+        )]);
+
+        let conn = database.conn.lock().unwrap();
+        let count = |table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |r| r.get(0))
+                .unwrap()
+        };
+        assert_eq!(count("micrometrics"), 1);
+        assert_eq!(count("micrometrics_hourly"), 0);
+        let daily: f64 = conn
+            .query_row("SELECT cpu_usage FROM micrometrics_daily", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(daily, 600.0);
+    }
+
+    #[test]
+    fn test_owner_changes_are_tracked() {
+        let database = open();
+        // The last one is a retry of the first, which must not reopen it.
+        for (owner, valid_from) in [
+            ("team-a", 60_000),
+            ("team-a", 120_000),
+            ("team-b", 180_000),
+            ("team-a", 60_000),
+        ] {
+            database.insert_owners(vec![(
+                OwnerKey {
+                    environment: "prod".to_string(),
+                    namespace: "shop".to_string(),
+                    pod: "pod-1".to_string(),
+                },
+                OwnerValue {
+                    owner: owner.to_string(),
+                    valid_from,
+                },
+            )]);
+        }
+
+        let conn = database.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare("SELECT owner, valid_from, valid_to FROM microowner ORDER BY valid_from")
+            .unwrap();
+        let owners: Vec<(String, String, Option<String>)> = statement
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            owners,
+            vec![
+                (
+                    "team-a".to_string(),
+                    "1970-01-01 00:01:00".to_string(),
+                    Some("1970-01-01 00:03:00".to_string())
+                ),
+                (
+                    "team-b".to_string(),
+                    "1970-01-01 00:03:00".to_string(),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_attribute_changes_are_tracked() {
+        let database = open();
+        // The label of the product disappears and comes back, and an older
+        // cost center is retried after the newer one.
+        for (attribute, value, valid_from) in [
+            ("cost_center", "cc-1", 60_000),
+            ("product", "shop", 60_000),
+            ("cost_center", "cc-2", 120_000),
+            ("product", "", 180_000),
+            ("cost_center", "cc-0", 30_000),
+            ("product", "web", 240_000),
+        ] {
+            database.insert_attributes(vec![(
+                AttributeKey {
+                    environment: "prod".to_string(),
+                    namespace: "shop".to_string(),
+                    pod: "pod-1".to_string(),
+                    name: attribute.to_string(),
+                },
+                AttributeValue {
+                    value: value.to_string(),
+                    valid_from,
+                },
+            )]);
+        }
+
+        let conn = database.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT attribute, value, valid_to IS NULL FROM microattribute
+                ORDER BY attribute, valid_from",
+            )
+            .unwrap();
+        let attributes: Vec<(String, String, bool)> = statement
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            attributes,
+            vec![
+                ("cost_center".to_string(), "cc-1".to_string(), false),
+                ("cost_center".to_string(), "cc-2".to_string(), true),
+                ("product".to_string(), "shop".to_string(), false),
+                ("product".to_string(), "web".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_later_workload_wins() {
+        let database = open();
+        for (kind, name) in [("ReplicaSet", "web-5d4f"), ("Deployment", "web")] {
+            database.insert_workloads(vec![(
+                OwnerKey {
+                    environment: "prod".to_string(),
+                    namespace: "shop".to_string(),
+                    pod: "web-5d4f-abcde".to_string(),
+                },
+                Workload {
+                    kind: kind.to_string(),
+                    name: name.to_string(),
+                },
+            )]);
+        }
+
+        let conn = database.conn.lock().unwrap();
+        let workload: (String, String) = conn
+            .query_row("SELECT kind, workload FROM microworkload", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(workload, ("Deployment".to_string(), "web".to_string()));
+    }
+}
@@ -0,0 +1,608 @@
+use crate::durable;
+use log::{debug, info, warn};
+use prost::Message;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub mod pb {
+    include!(concat!(env!("OUT_DIR"), "/wal.rs"));
+}
+
+/// Size after which the active segment is sealed and a new one is started.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// Every record starts with the payload length and the CRC32 of the payload,
+/// both little endian.
+const HEADER_SIZE: usize = 8;
+const SEGMENT_EXTENSION: &str = "wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+/// What the records of a segment contain, as far as truncation cares.
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    /// Newest sample timestamp in the segment, `None` if it has no samples.
+    max_timestamp: Option<u64>,
+    /// Whether the segment holds owners, attributes or owner references that
+    /// have not been committed yet.
+    has_owners: bool,
+}
+
+impl Segment {
+    fn new(id: u64) -> Self {
+        Segment {
+            id,
+            max_timestamp: None,
+            has_owners: false,
+        }
+    }
+
+    fn record(&mut self, batch: &pb::Batch) {
+        if let Some(max) = batch.samples.iter().map(|s| s.timestamp).max() {
+            self.max_timestamp = Some(self.max_timestamp.map_or(max, |m| m.max(max)));
+        }
+        self.has_owners |= !batch.owners.is_empty()
+            || !batch.attributes.is_empty()
+            || !batch.owner_references.is_empty();
+    }
+}
+
+struct ActiveSegment {
+    segment: Segment,
+    file: File,
+    len: u64,
+}
+
+struct State {
+    sealed: Vec<Segment>,
+    active: Option<ActiveSegment>,
+    next_id: u64,
+    /// The last checkpoint, also of a previous run.
+    committed: Option<Checkpoint>,
+}
+
+/// Marks how far the buffers have been written to the database. Every record
+/// in a segment up to `segment` is committed if it is a sample older than
+/// `committed_before`, or an owner, attribute or owner reference and
+/// `owners_committed` is set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checkpoint {
+    pub segment: u64,
+    pub committed_before: u64,
+    pub owners_committed: bool,
+    /// Per environment, every bucket before it has been written, which tells
+    /// the next run which samples are late. Samples before it are committed
+    /// as well.
+    pub flushed_before: HashMap<String, u64>,
+}
+
+impl Checkpoint {
+    fn is_committed(&self, sample: &pb::Sample) -> bool {
+        let before = self
+            .flushed_before
+            .get(&sample.environment)
+            .map_or(self.committed_before, |&before| {
+                before.max(self.committed_before)
+            });
+        sample.timestamp < before
+    }
+}
+
+/// Append-only log of the batches accepted by the buffers, split into
+/// segments of roughly `segment_size` bytes. Segments are only ever appended
+/// to while active; sealed segments are replayed on startup and deleted once
+/// everything in them has been committed to the database.
+pub struct Wal {
+    dir: PathBuf,
+    segment_size: u64,
+    state: Mutex<State>,
+}
+
+impl Wal {
+    pub fn open(dir: impl AsRef<Path>, segment_size: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+
+        // Until a segment has been replayed, nothing is known about its
+        // content, so it must not be truncated.
+        let sealed: Vec<Segment> = ids
+            .iter()
+            .map(|&id| Segment {
+                id,
+                max_timestamp: Some(u64::MAX),
+                has_owners: true,
+            })
+            .collect();
+        let next_id = ids.last().map_or(0, |id| id + 1);
+        let committed = read_checkpoint(&dir.join(CHECKPOINT_FILE));
+        info!(
+            "Opened write-ahead log in {:?} with {} segments",
+            dir,
+            sealed.len()
+        );
+
+        Ok(Wal {
+            dir,
+            segment_size,
+            state: Mutex::new(State {
+                sealed,
+                active: None,
+                next_id,
+                committed,
+            }),
+        })
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+    }
+
+    /// Writes `batch` and syncs it to disk before returning, so that an
+    /// acknowledged remote write request survives a crash.
+    pub fn append(&self, batch: &pb::Batch) -> io::Result<()> {
+        let payload = batch.encode_to_vec();
+        let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        let mut state = self.state.lock().unwrap();
+        if state.active.is_none() {
+            let id = state.next_id;
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.segment_path(id))?;
+            state.next_id += 1;
+            state.active = Some(ActiveSegment {
+                segment: Segment::new(id),
+                file,
+                len: 0,
+            });
+        }
+
+        let active = state.active.as_mut().unwrap();
+        active.file.write_all(&record)?;
+        active.file.sync_data()?;
+        active.len += record.len() as u64;
+        active.segment.record(batch);
+
+        if active.len >= self.segment_size {
+            Self::seal_active(&mut state);
+        }
+        Ok(())
+    }
+
+    fn seal_active(state: &mut State) {
+        if let Some(active) = state.active.take() {
+            debug!("Sealing write-ahead log segment {}", active.segment.id);
+            state.sealed.push(active.segment);
+        }
+    }
+
+    /// Seals the active segment and returns the newest sealed segment, i.e.,
+    /// the segment up to which everything appended so far is contained.
+    pub fn seal(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        Self::seal_active(&mut state);
+        state.sealed.last().map(|segment| segment.id)
+    }
+
+    /// Per environment, every bucket before it has been written by this or a
+    /// previous run.
+    pub fn flushed_before(&self) -> HashMap<String, u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .committed
+            .as_ref()
+            .map(|checkpoint| checkpoint.flushed_before.clone())
+            .unwrap_or_default()
+    }
+
+    /// Reads the sealed segments in order and hands every intact batch to
+    /// `apply`, without the samples that the last checkpoint committed. A
+    /// record that is cut short or fails its checksum ends the segment, as it
+    /// can only be the result of a crash during the write.
+    pub fn replay<F>(&self, mut apply: F) -> io::Result<usize>
+    where
+        F: FnMut(pb::Batch),
+    {
+        let mut state = self.state.lock().unwrap();
+        let committed = state.committed.clone();
+        let mut replayed = 0;
+        for segment in state.sealed.iter_mut() {
+            let path = self.segment_path(segment.id);
+            let mut reader = BufReader::new(File::open(&path)?);
+            *segment = Segment::new(segment.id);
+            let mut offset = 0u64;
+
+            while let Some(mut batch) = read_record(&mut reader, &path, offset)? {
+                offset += (HEADER_SIZE + batch.encoded_len()) as u64;
+                if let Some(committed) = &committed
+                    && segment.id <= committed.segment
+                {
+                    batch
+                        .samples
+                        .retain(|sample| !committed.is_committed(sample));
+                }
+                segment.record(&batch);
+                apply(batch);
+                replayed += 1;
+            }
+        }
+        info!("Replayed {} batches from the write-ahead log", replayed);
+        Ok(replayed)
+    }
+
+    /// Deletes the sealed segments up to the checkpoint that contain nothing
+    /// but committed records, and remembers the checkpoint for the next run.
+    pub fn truncate(&self, checkpoint: &Checkpoint) {
+        let mut state = self.state.lock().unwrap();
+        let path = self.dir.join(CHECKPOINT_FILE);
+        let content = pb::Checkpoint {
+            segment: checkpoint.segment,
+            committed_before: checkpoint.committed_before,
+            flushed_before: checkpoint.flushed_before.clone(),
+        };
+        if let Err(e) = durable::replace(&path, &content.encode_to_vec()) {
+            warn!(
+                "Failed to write write-ahead log checkpoint {:?}: {}",
+                path, e
+            );
+        }
+        state.committed = Some(checkpoint.clone());
+
+        state.sealed.retain_mut(|segment| {
+            if segment.id > checkpoint.segment {
+                return true;
+            }
+            if checkpoint.owners_committed {
+                segment.has_owners = false;
+            }
+            let samples_committed = segment
+                .max_timestamp
+                .is_none_or(|max| max < checkpoint.committed_before);
+            if !samples_committed || segment.has_owners {
+                return true;
+            }
+
+            let path = self.segment_path(segment.id);
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    debug!("Removed committed write-ahead log segment {}", segment.id);
+                    false
+                }
+                Err(e) => {
+                    warn!("Failed to remove write-ahead log segment {:?}: {}", path, e);
+                    true
+                }
+            }
+        });
+    }
+}
+
+/// The checkpoint of a previous run, if it left a readable one.
+fn read_checkpoint(path: &Path) -> Option<Checkpoint> {
+    let bytes = fs::read(path).ok()?;
+    match pb::Checkpoint::decode(bytes.as_slice()) {
+        Ok(checkpoint) => Some(Checkpoint {
+            segment: checkpoint.segment,
+            committed_before: checkpoint.committed_before,
+            owners_committed: false,
+            flushed_before: checkpoint.flushed_before,
+        }),
+        Err(e) => {
+            warn!("Ignoring write-ahead log checkpoint {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn read_record(reader: &mut impl Read, path: &Path, offset: u64) -> io::Result<Option<pb::Batch>> {
+    let mut header = [0u8; HEADER_SIZE];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < HEADER_SIZE => {
+            warn!(
+                "Ignoring truncated record header in {:?} at {}",
+                path, offset
+            );
+            return Ok(None);
+        }
+        _ => {}
+    }
+
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let mut payload = vec![0u8; len];
+    if read_full(reader, &mut payload)? < len {
+        warn!("Ignoring truncated record in {:?} at {}", path, offset);
+        return Ok(None);
+    }
+    if crc32fast::hash(&payload) != checksum {
+        warn!(
+            "Ignoring record with bad checksum in {:?} at {}",
+            path, offset
+        );
+        return Ok(None);
+    }
+
+    match pb::Batch::decode(payload.as_slice()) {
+        Ok(batch) => Ok(Some(batch)),
+        Err(e) => {
+            warn!(
+                "Ignoring undecodable record in {:?} at {}: {}",
+                path, offset, e
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Like `read_exact`, but reports how much was read instead of failing at the
+/// end of the file.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(timestamp: u64, owner: bool) -> pb::Batch {
+        pb::Batch {
+            samples: vec![pb::Sample {
+                name: "cpu_limit".to_string(),
+                environment: "env1".to_string(),
+                namespace: "ns1".to_string(),
+                pod: "pod1".to_string(),
+                container: "container1".to_string(),
+                timestamp,
+                value: 1.0,
+            }],
+            owners: if owner {
+                vec![pb::Owner {
+                    environment: "env1".to_string(),
+                    namespace: "ns1".to_string(),
+                    pod: "pod1".to_string(),
+                    owner: "team-a".to_string(),
+                    timestamp,
+                }]
+            } else {
+                vec![]
+            },
+            attributes: vec![],
+            owner_references: vec![],
+        }
+    }
+
+    fn replay_all(dir: &Path) -> (Wal, Vec<pb::Batch>) {
+        let wal = Wal::open(dir, DEFAULT_SEGMENT_SIZE).unwrap();
+        let mut batches = Vec::new();
+        wal.replay(|b| batches.push(b)).unwrap();
+        (wal, batches)
+    }
+
+    fn segment_count(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    == Some(SEGMENT_EXTENSION)
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_replay_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        wal.append(&batch(1000, false)).unwrap();
+        wal.append(&batch(2000, true)).unwrap();
+        drop(wal);
+
+        let (_, batches) = replay_all(dir.path());
+
+        assert_eq!(batches, vec![batch(1000, false), batch(2000, true)]);
+    }
+
+    #[test]
+    fn test_segments_roll_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), 1).unwrap();
+        wal.append(&batch(1000, false)).unwrap();
+        wal.append(&batch(2000, false)).unwrap();
+        drop(wal);
+
+        assert_eq!(segment_count(dir.path()), 2);
+        let (_, batches) = replay_all(dir.path());
+        assert_eq!(batches.len(), 2);
+    }
+
+    #[test]
+    fn test_replay_stops_at_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        wal.append(&batch(1000, false)).unwrap();
+        wal.append(&batch(2000, false)).unwrap();
+        drop(wal);
+
+        let path = dir.path().join(format!("{:020}.wal", 0));
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let (_, batches) = replay_all(dir.path());
+        assert_eq!(batches, vec![batch(1000, false)]);
+    }
+
+    #[test]
+    fn test_replay_stops_at_bad_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        wal.append(&batch(1000, false)).unwrap();
+        drop(wal);
+
+        let path = dir.path().join(format!("{:020}.wal", 0));
+        let mut content = fs::read(&path).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        fs::write(&path, content).unwrap();
+
+        let (_, batches) = replay_all(dir.path());
+        assert!(batches.is_empty());
+    }
+
+    #[test]
+    fn test_truncate_removes_committed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), 1).unwrap();
+        wal.append(&batch(1000, false)).unwrap();
+        wal.append(&batch(5000, false)).unwrap();
+        let segment = wal.seal().unwrap();
+
+        wal.truncate(&Checkpoint {
+            segment,
+            committed_before: 2000,
+            owners_committed: false,
+            ..Default::default()
+        });
+
+        assert_eq!(segment_count(dir.path()), 1);
+        let (_, batches) = replay_all(dir.path());
+        assert_eq!(batches, vec![batch(5000, false)]);
+    }
+
+    #[test]
+    fn test_truncate_keeps_uncommitted_owners() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        wal.append(&batch(1000, true)).unwrap();
+        let segment = wal.seal().unwrap();
+
+        wal.truncate(&Checkpoint {
+            segment,
+            committed_before: 2000,
+            owners_committed: false,
+            ..Default::default()
+        });
+        assert_eq!(segment_count(dir.path()), 1);
+
+        wal.truncate(&Checkpoint {
+            segment,
+            committed_before: 2000,
+            owners_committed: true,
+            ..Default::default()
+        });
+        assert_eq!(segment_count(dir.path()), 0);
+    }
+
+    #[test]
+    fn test_replay_skips_committed_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        wal.append(&batch(1000, true)).unwrap();
+        wal.append(&batch(2500, false)).unwrap();
+        wal.append(&batch(5000, false)).unwrap();
+        let segment = wal.seal().unwrap();
+        wal.truncate(&Checkpoint {
+            segment,
+            committed_before: 2000,
+            owners_committed: false,
+            flushed_before: HashMap::from([("env1".to_string(), 3000)]),
+        });
+        // A late sample after the checkpoint has not been written yet.
+        wal.append(&batch(1000, false)).unwrap();
+        drop(wal);
+
+        let (wal, batches) = replay_all(dir.path());
+        let committed = pb::Batch {
+            samples: vec![],
+            ..batch(1000, true)
+        };
+        assert_eq!(
+            batches,
+            vec![
+                committed,
+                pb::Batch::default(),
+                batch(5000, false),
+                batch(1000, false)
+            ]
+        );
+        assert_eq!(wal.flushed_before()["env1"], 3000);
+    }
+
+    #[test]
+    fn test_truncate_ignores_segments_after_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        wal.append(&batch(1000, false)).unwrap();
+        let segment = wal.seal().unwrap();
+        wal.append(&batch(1000, false)).unwrap();
+        wal.seal();
+
+        wal.truncate(&Checkpoint {
+            segment,
+            committed_before: 2000,
+            owners_committed: true,
+            ..Default::default()
+        });
+
+        assert_eq!(segment_count(dir.path()), 1);
+    }
+
+    #[test]
+    fn test_unreplayed_segments_are_not_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        wal.append(&batch(1000, false)).unwrap();
+        drop(wal);
+
+        let wal = Wal::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        let segment = wal.seal().unwrap();
+        wal.truncate(&Checkpoint {
+            segment,
+            committed_before: 2000,
+            owners_committed: true,
+            ..Default::default()
+        });
+
+        assert_eq!(segment_count(dir.path()), 1);
+    }
+}
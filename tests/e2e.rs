@@ -1,7 +1,12 @@
 use microinsight::metrics_buffer::MetricsBuffer;
 use microinsight::owner_buffer::OwnerBuffer;
 use microinsight::prometheus::{Label, Sample, TimeSeries, WriteRequest};
-use microinsight::{Server, buffer_manager::BufferManager, database::Database};
+use microinsight::sink::Sink;
+use microinsight::{
+    Server,
+    buffer_manager::BufferManager,
+    database::{Database, PartitionPeriod},
+};
 use mysql::prelude::*;
 use prost::Message;
 use reqwest::Client;
@@ -10,6 +15,17 @@ use std::time::{Duration, SystemTime};
 use testcontainers::ImageExt;
 use testcontainers_modules::{mariadb, testcontainers::runners::AsyncRunner};
 
+type MetricsRow = (
+    u64,
+    String,
+    String,
+    String,
+    Option<f64>,
+    Option<f64>,
+    Option<i64>,
+    Option<i64>,
+);
+
 #[tokio::test]
 async fn test_receive_data_e2e() {
     let mysql_instance = mariadb::Mariadb::default()
@@ -23,16 +39,18 @@ async fn test_receive_data_e2e() {
     let database = Database::new(&db_url, 5000);
     database.create_tables();
     let interval_millis = 60000;
-    let metrics_buffer = MetricsBuffer::new(interval_millis, 5);
+    // The only environment goes idle after its single sample, which flushes
+    // the bucket on the next tick of the flusher.
+    let metrics_buffer =
+        MetricsBuffer::new(interval_millis, 5).with_idle_timeout(Duration::from_secs(1));
     let owner_buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
     let buffer_manager = BufferManager::new(metrics_buffer, owner_buffer);
 
-    let server = Server::new(buffer_manager, database);
+    let server = Server::new(buffer_manager, database, Duration::from_secs(1));
     let server_handle = tokio::spawn(server.run().await.expect("Failed to start server"));
     tokio::time::sleep(Duration::from_secs(5)).await;
 
-    // Prometheus reports sample timestamps in milliseconds. Use a bucket that is
-    // older than max_delay so that it is flushed by this write request.
+    // Prometheus reports sample timestamps in milliseconds.
     let now_millis = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
@@ -65,7 +83,7 @@ async fn test_receive_data_e2e() {
                 },
             ],
             samples: vec![Sample {
-                value: 0.5,
+                value: 536870912.0,
                 timestamp: sample_millis as i64,
             }],
             exemplars: vec![],
@@ -93,20 +111,14 @@ async fn test_receive_data_e2e() {
 
     assert_eq!(response.status(), 204);
 
+    // Writing happens in the background, give the flusher a few ticks.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
     let opts = mysql::Opts::from_url(&db_url).expect("Invalid database URL");
     let pool = mysql::Pool::new(opts).expect("Failed to create database pool");
     let mut conn = pool.get_conn().unwrap();
-    let result: Option<(
-        u64,
-        String,
-        String,
-        String,
-        Option<f32>,
-        Option<f32>,
-        Option<f32>,
-        Option<f32>,
-    )> = conn
-        .query_first("SELECT UNIX_TIMESTAMP(time), environment, pod, container, cpu_usage, cpu_limit, memory_usage, memory_limit FROM micrometrics LIMIT 1")
+    let result: Option<MetricsRow> = conn
+        .query_first("SELECT UNIX_TIMESTAMP(time), environment, pod, container, cpu_usage, CAST(cpu_limit AS DOUBLE), memory_usage, memory_limit FROM micrometrics LIMIT 1")
         .unwrap();
 
     assert!(result.is_some(), "no row was written to micrometrics");
@@ -116,7 +128,41 @@ async fn test_receive_data_e2e() {
     assert_eq!(environment, "prod");
     assert_eq!(pod, "pod-1");
     assert_eq!(container, "container-1");
-    assert_eq!(memory_limit, Some(0.5));
+    assert_eq!(memory_limit, Some(536870912));
 
     server_handle.abort();
 }
+
+#[tokio::test]
+async fn test_partition_maintenance() {
+    let mysql_instance = mariadb::Mariadb::default()
+        .with_env_var("MARIADB_ROOT_PASSWORD", "test")
+        .start()
+        .await
+        .unwrap();
+    let host = mysql_instance.get_host().await.unwrap();
+    let port = mysql_instance.get_host_port_ipv4(3306).await.unwrap();
+    let db_url = format!("mysql://root:test@{}:{}/test", host, port);
+    let database = Database::new(&db_url, 5000);
+    database.create_tables();
+
+    let maintenance = database.maintenance(PartitionPeriod::Day, Some(7), Duration::from_secs(60));
+    maintenance.run_once().expect("Failed to partition table");
+    // A second round finds everything in place.
+    maintenance
+        .run_once()
+        .expect("Failed to maintain partitions");
+
+    let pool = mysql::Pool::new(mysql::Opts::from_url(&db_url).unwrap()).unwrap();
+    let mut conn = pool.get_conn().unwrap();
+    let partitions: Vec<String> = conn
+        .query(
+            "SELECT PARTITION_NAME FROM information_schema.PARTITIONS
+            WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'micrometrics'
+            ORDER BY PARTITION_ORDINAL_POSITION",
+        )
+        .unwrap();
+    assert_eq!(partitions.len(), 6, "p_past, today, 3 ahead and p_future");
+    assert_eq!(partitions.first().unwrap(), "p_past");
+    assert_eq!(partitions.last().unwrap(), "p_future");
+}
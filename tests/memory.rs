@@ -0,0 +1,90 @@
+use microinsight::metrics_buffer::MetricsBuffer;
+use microinsight::owner_buffer::OwnerBuffer;
+use microinsight::prometheus::{Label, Sample, TimeSeries, WriteRequest};
+use microinsight::sink::testing::MemorySink;
+use microinsight::{Server, buffer_manager::BufferManager};
+use prost::Message;
+use reqwest::Client;
+use snap::raw::Encoder;
+use std::time::{Duration, SystemTime};
+
+fn label(name: &str, value: &str) -> Label {
+    Label {
+        name: name.to_string(),
+        value: value.to_string(),
+    }
+}
+
+/// The round trip of the SQLite test, collecting the flushed rows in memory
+/// instead of a database.
+#[tokio::test]
+async fn test_receive_data_memory() {
+    let sink = MemorySink::default();
+    let interval_millis = 60000;
+    let metrics_buffer =
+        MetricsBuffer::new(interval_millis, 5).with_idle_timeout(Duration::from_secs(1));
+    let owner_buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
+    let buffer_manager = BufferManager::new(metrics_buffer, owner_buffer);
+
+    let server = Server::new(buffer_manager, sink.clone(), Duration::from_secs(1));
+    let server_handle = tokio::spawn(server.run().await.expect("Failed to start server"));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let now_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let sample_millis = now_millis - 6 * interval_millis;
+    let expected_bucket_millis = (sample_millis / interval_millis) * interval_millis;
+
+    let write_request = WriteRequest {
+        timeseries: vec![TimeSeries {
+            labels: vec![
+                label("cluster", "prod"),
+                label("namespace", "shop"),
+                label("pod", "pod-1"),
+                label("container", "container-1"),
+                label("resource", "memory"),
+                label("__name__", "kube_pod_container_resource_limits"),
+            ],
+            samples: vec![Sample {
+                value: 536870912.0,
+                timestamp: sample_millis as i64,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        }],
+        metadata: vec![],
+    };
+
+    let mut buf = Vec::new();
+    write_request
+        .encode(&mut buf)
+        .expect("Failed to encode WriteRequest");
+    let compressed_payload = Encoder::new()
+        .compress_vec(&buf)
+        .expect("Failed to compress payload");
+
+    let response = Client::new()
+        .post("http://127.0.0.1:80/receive")
+        .body(compressed_payload)
+        .header("Content-Encoding", "snappy")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 204);
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let metrics = sink.metrics.lock().unwrap().clone();
+    assert_eq!(metrics.len(), 1);
+    let (key, row) = &metrics[0];
+    assert_eq!(key.timestamp, expected_bucket_millis);
+    assert_eq!(key.environment, "prod");
+    assert_eq!(key.namespace, "shop");
+    assert_eq!(key.pod, "pod-1");
+    assert_eq!(key.container, "container-1");
+    assert_eq!(row.memory_limit, Some(536870912.0));
+
+    server_handle.abort();
+}
@@ -0,0 +1,107 @@
+use microinsight::metrics_buffer::{Key, Metrics};
+use microinsight::owner_buffer::{OwnerKey, OwnerValue};
+use microinsight::postgresql::PostgresDatabase;
+use microinsight::rollup::Rollups;
+use microinsight::sink::Sink;
+use postgres::{Client, NoTls};
+use std::time::Duration;
+use testcontainers_modules::{postgres::Postgres, testcontainers::runners::SyncRunner};
+
+fn key(timestamp: u64) -> Key {
+    Key {
+        timestamp,
+        environment: "prod".to_string(),
+        namespace: "shop".to_string(),
+        pod: "pod-1".to_string(),
+        container: "container-1".to_string(),
+    }
+}
+
+// The PostgreSQL client runs its own runtime, so this test must not run
+// inside of one.
+#[test]
+fn test_postgres_upsert() {
+    let instance = Postgres::default().start().unwrap();
+    let host = instance.get_host().unwrap();
+    let port = instance.get_host_port_ipv4(5432).unwrap();
+    let db_url = format!("postgres://postgres:postgres@{}:{}/postgres", host, port);
+
+    let database = PostgresDatabase::connect(&db_url, 5000, 10, Duration::from_secs(1))
+        .with_rollups(Rollups::new(60));
+    database.create_tables();
+
+    database.insert_metrics(vec![(
+        key(60_000),
+        Metrics {
+            cpu_usage: Some(12.5),
+            cpu_limit: Some(60.0),
+            memory_limit: Some(1024.0),
+            ..Default::default()
+        },
+    )]);
+    // A later flush of the same bucket only fills in what is missing, like
+    // the IFNULL upsert of the MySQL backend.
+    database.insert_metrics(vec![(
+        key(60_000),
+        Metrics {
+            memory_usage: Some(512.0),
+            memory_limit: Some(1024.0),
+            ..Default::default()
+        },
+    )]);
+    for (owner, valid_from) in [("team-a", 60_000), ("team-a", 120_000), ("team-b", 180_000)] {
+        database.insert_owners(vec![(
+            OwnerKey {
+                environment: "prod".to_string(),
+                namespace: "shop".to_string(),
+                pod: "pod-1".to_string(),
+            },
+            OwnerValue {
+                owner: owner.to_string(),
+                valid_from,
+            },
+        )]);
+    }
+
+    let mut client = Client::connect(&db_url, NoTls).unwrap();
+    let row = client
+        .query_one(
+            "SELECT EXTRACT(EPOCH FROM time)::bigint, cpu_usage, cpu_limit::float8, memory_usage, memory_limit FROM micrometrics",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 60);
+    assert_eq!(row.get::<_, Option<f64>>(1), Some(12.5));
+    assert_eq!(row.get::<_, Option<f64>>(2), Some(60.0));
+    assert_eq!(row.get::<_, Option<i64>>(3), Some(512));
+    assert_eq!(row.get::<_, Option<i64>>(4), Some(1024));
+
+    let hourly = client
+        .query_one(
+            "SELECT cpu_usage, cpu_limit_seconds, memory_usage_max, samples FROM micrometrics_hourly",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(hourly.get::<_, Option<f64>>(0), Some(12.5));
+    assert_eq!(hourly.get::<_, Option<f64>>(1), Some(3600.0));
+    assert_eq!(hourly.get::<_, Option<i64>>(2), Some(512));
+    assert_eq!(hourly.get::<_, i32>(3), 1);
+
+    let owners: Vec<(String, i64, Option<i64>)> = client
+        .query(
+            "SELECT owner, EXTRACT(EPOCH FROM valid_from)::bigint, EXTRACT(EPOCH FROM valid_to)::bigint
+            FROM microowner WHERE namespace = 'shop' ORDER BY valid_from",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect();
+    assert_eq!(
+        owners,
+        vec![
+            ("team-a".to_string(), 60, Some(180)),
+            ("team-b".to_string(), 180, None)
+        ]
+    );
+}
@@ -0,0 +1,128 @@
+use microinsight::metrics_buffer::MetricsBuffer;
+use microinsight::owner_buffer::OwnerBuffer;
+use microinsight::prometheus::{Label, Sample, TimeSeries, WriteRequest};
+use microinsight::sink::Sink;
+use microinsight::sqlite::SqliteDatabase;
+use microinsight::{Server, buffer_manager::BufferManager};
+use prost::Message;
+use reqwest::Client;
+use snap::raw::Encoder;
+use std::time::{Duration, SystemTime};
+
+/// The same round trip as the MariaDB end-to-end test, but against a local
+/// SQLite file, so it needs neither Docker nor a database server.
+#[tokio::test]
+async fn test_receive_data_sqlite() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("microinsight.db");
+    let database = SqliteDatabase::open(db_path.to_str().unwrap(), 5000);
+    database.create_tables();
+    let interval_millis = 60000;
+    // The only environment goes idle after its single sample, which flushes
+    // the bucket on the next tick of the flusher.
+    let metrics_buffer =
+        MetricsBuffer::new(interval_millis, 5).with_idle_timeout(Duration::from_secs(1));
+    let owner_buffer = OwnerBuffer::new(300, SystemTime::UNIX_EPOCH);
+    let buffer_manager = BufferManager::new(metrics_buffer, owner_buffer);
+
+    let server = Server::new(buffer_manager, database, Duration::from_secs(1));
+    let server_handle = tokio::spawn(server.run().await.expect("Failed to start server"));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let now_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let sample_millis = now_millis - 6 * interval_millis;
+    let expected_bucket_millis = (sample_millis / interval_millis) * interval_millis;
+
+    let write_request = WriteRequest {
+        timeseries: vec![TimeSeries {
+            labels: vec![
+                Label {
+                    name: "cluster".to_string(),
+                    value: "prod".to_string(),
+                },
+                Label {
+                    name: "namespace".to_string(),
+                    value: "shop".to_string(),
+                },
+                Label {
+                    name: "pod".to_string(),
+                    value: "pod-1".to_string(),
+                },
+                Label {
+                    name: "container".to_string(),
+                    value: "container-1".to_string(),
+                },
+                Label {
+                    name: "resource".to_string(),
+                    value: "memory".to_string(),
+                },
+                Label {
+                    name: "__name__".to_string(),
+                    value: "kube_pod_container_resource_limits".to_string(),
+                },
+            ],
+            samples: vec![Sample {
+                value: 536870912.0,
+                timestamp: sample_millis as i64,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        }],
+        metadata: vec![],
+    };
+
+    let mut buf = Vec::new();
+    write_request
+        .encode(&mut buf)
+        .expect("Failed to encode WriteRequest");
+    let compressed_payload = Encoder::new()
+        .compress_vec(&buf)
+        .expect("Failed to compress payload");
+
+    let response = Client::new()
+        .post("http://127.0.0.1:80/receive")
+        .body(compressed_payload)
+        .header("Content-Encoding", "snappy")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 204);
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let conn = rusqlite::Connection::open(&db_path).unwrap();
+    let (time, environment, namespace, pod, container, memory_limit): (
+        i64,
+        String,
+        String,
+        String,
+        String,
+        i64,
+    ) = conn
+        .query_row(
+            "SELECT CAST(strftime('%s', time) AS INTEGER), environment, namespace, pod, container, memory_limit FROM micrometrics",
+            [],
+            |r| {
+                Ok((
+                    r.get(0)?,
+                    r.get(1)?,
+                    r.get(2)?,
+                    r.get(3)?,
+                    r.get(4)?,
+                    r.get(5)?,
+                ))
+            },
+        )
+        .expect("no row was written to micrometrics");
+    assert_eq!(time as u64, expected_bucket_millis / 1000);
+    assert_eq!(environment, "prod");
+    assert_eq!(namespace, "shop");
+    assert_eq!(pod, "pod-1");
+    assert_eq!(container, "container-1");
+    assert_eq!(memory_limit, 536870912);
+
+    server_handle.abort();
+}